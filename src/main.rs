@@ -19,23 +19,54 @@
 // CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-#![feature(bufreader_buffer)]
-extern crate chrono;
-extern crate core;
 extern crate env_logger;
 #[macro_use]
 extern crate log;
+extern crate simplewebserver;
 
-use std::net::SocketAddr;
+use std::env;
+use std::path::PathBuf;
+use std::process;
 
 use simplewebserver::App;
-
-mod simplewebserver;
+use simplewebserver::config::Config;
 
 fn main() {
     env_logger::init();
 
-    let addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
-    App::new(addr)
-        .run();
-}
\ No newline at end of file
+    let args = env::args().collect::<Vec<_>>();
+    let path = match args.get(1).map(String::as_str) {
+        Some("-c") | Some("--config") => match args.get(2) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => {
+                eprintln!("usage: {} [-c <config.toml>]", args[0]);
+                process::exit(2);
+            }
+        },
+        Some(_) => {
+            eprintln!("usage: {} [-c <config.toml>]", args[0]);
+            process::exit(2);
+        }
+        None => None,
+    };
+    let config = match &path {
+        Some(path) => Config::load(path),
+        None => Ok(Config::default()),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut app = App::new(config);
+    if let Some(path) = path {
+        app = app.reload_from(path);
+    }
+    if let Err(e) = app.run() {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Access logs
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Local;
+
+use super::request::Request;
+use super::status::StatusCode;
+
+/// An access log in the Common Log Format:
+///
+/// ```notrust
+/// 127.0.0.1 - alice [10/Oct/2018:13:55:36 +0800] "GET /index.html HTTP/1.0" 200 2326
+/// ```
+#[derive(Debug)]
+pub struct AccessLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    /// Open `path` for appending, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+        Ok(AccessLog {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn log(&self, peer: Option<SocketAddr>, req: &Request, status_code: StatusCode, sent: u64) {
+        let line = format!("{} - - [{}] \"{} {} {}\" {} {}\n",
+                           peer.map_or("-".to_string(), |p| p.ip().to_string()),
+                           Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                           req.method(),
+                           req.uri(),
+                           req.version(),
+                           status_code.to_u16(),
+                           sent);
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("{}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,863 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Proxy cache
+//!
+//! Responses relayed by the proxy are stored on disk, each as a `.meta`
+//! file holding the key, freshness and header fields and a `.body` file,
+//! both named by the SHA-1 of the key, and served from there while fresh
+//! [[RFC7234](https://tools.ietf.org/html/rfc7234)]. Stale entries are
+//! revalidated with a conditional request; `stale-while-revalidate` and
+//! `stale-if-error` [[RFC5861](https://tools.ietf.org/html/rfc5861)] allow
+//! serving them meanwhile or when the upstream fails. The least recently
+//! used entries are evicted once the cache grows over `max_size`.
+//! Requests with `Authorization` are always forwarded, and the responses
+//! to them only stored when marked `public`, `s-maxage` or
+//! `must-revalidate`.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha1::Sha1;
+
+use super::header::{CacheControl, parse_date};
+use super::proxy;
+use super::request::{Method, Request, RequestLimits};
+use super::response::Response;
+use super::status::StatusCode;
+use super::upstream::Pool;
+
+/// Status codes cacheable by default
+/// [[RFC7231, Section 6.1](https://tools.ietf.org/html/rfc7231#section-6.1)].
+static CACHEABLE: &[u16] = &[200, 203, 204, 206, 300, 301, 404, 405, 410, 414, 501];
+
+/// Separates the request-header values selected by `Vary` in a key.
+const VARY_SEPARATOR: char = '\u{1f}';
+
+#[derive(Debug)]
+pub struct Cache {
+    pub name: String,
+    dir: PathBuf,
+    max_size: AtomicU64,
+    index: Mutex<Index>,
+    counter: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    /// Entries by key, the primary key followed by the `Vary` values.
+    entries: HashMap<String, Meta>,
+    /// Header fields named by `Vary`, by primary key.
+    vary: HashMap<String, Vec<String>>,
+    size: u64,
+    clock: u64,
+    revalidating: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Meta {
+    key: String,
+    primary: String,
+    status: u16,
+    /// Seconds since the epoch.
+    stored: u64,
+    fresh_until: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+    /// Never serve stale (`must-revalidate`, `proxy-revalidate`, `no-cache`).
+    must_revalidate: bool,
+    vary: Vec<(String, String)>,
+    header: Vec<(String, String)>,
+    size: u64,
+    /// Last use, for LRU eviction.
+    used: u64,
+}
+
+impl Meta {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.header.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Conditional header fields revalidating this entry.
+    fn validators(&self) -> Vec<(&'static str, String)> {
+        let mut validators = Vec::new();
+        if let Some(etag) = self.header("ETag") {
+            validators.push(("If-None-Match", etag.to_string()));
+        }
+        if let Some(last_modified) = self.header("Last-Modified") {
+            validators.push(("If-Modified-Since", last_modified.to_string()));
+        }
+        validators
+    }
+
+    fn to_text(&self) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("key\t{}\n", self.key));
+        s.push_str(&format!("primary\t{}\n", self.primary));
+        s.push_str(&format!("status\t{}\n", self.status));
+        s.push_str(&format!("stored\t{}\n", self.stored));
+        s.push_str(&format!("fresh_until\t{}\n", self.fresh_until));
+        s.push_str(&format!("stale_while_revalidate\t{}\n", self.stale_while_revalidate));
+        s.push_str(&format!("stale_if_error\t{}\n", self.stale_if_error));
+        s.push_str(&format!("must_revalidate\t{}\n", self.must_revalidate));
+        for (k, v) in &self.vary {
+            s.push_str(&format!("vary\t{}\t{}\n", k, v));
+        }
+        for (k, v) in &self.header {
+            s.push_str(&format!("header\t{}\t{}\n", k, v));
+        }
+        s
+    }
+
+    fn from_text<R: BufRead>(reader: R) -> Option<Meta> {
+        let mut meta = Meta {
+            key: String::new(),
+            primary: String::new(),
+            status: 0,
+            stored: 0,
+            fresh_until: 0,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            must_revalidate: false,
+            vary: Vec::new(),
+            header: Vec::new(),
+            size: 0,
+            used: 0,
+        };
+        for line in reader.lines() {
+            let line = line.ok()?;
+            let mut cursor = line.splitn(3, '\t');
+            match (cursor.next()?, cursor.next()?) {
+                ("key", v) => meta.key = v.to_string(),
+                ("primary", v) => meta.primary = v.to_string(),
+                ("status", v) => meta.status = v.parse().ok()?,
+                ("stored", v) => meta.stored = v.parse().ok()?,
+                ("fresh_until", v) => meta.fresh_until = v.parse().ok()?,
+                ("stale_while_revalidate", v) => meta.stale_while_revalidate = v.parse().ok()?,
+                ("stale_if_error", v) => meta.stale_if_error = v.parse().ok()?,
+                ("must_revalidate", v) => meta.must_revalidate = v.parse().ok()?,
+                ("vary", k) => meta.vary.push((k.to_string(), cursor.next()?.to_string())),
+                ("header", k) => meta.header.push((k.to_string(), cursor.next()?.to_string())),
+                _ => return None,
+            }
+        }
+        if meta.key.is_empty() || meta.status == 0 {
+            return None;
+        }
+        Some(meta)
+    }
+}
+
+impl Cache {
+    /// Open the cache stored in `dir`, creating the directory if needed
+    /// and indexing the entries found there.
+    pub fn open<P: AsRef<Path>>(name: String, dir: P, max_size: u64) -> io::Result<Arc<Cache>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let cache = Arc::new(Cache {
+            name,
+            dir,
+            max_size: AtomicU64::new(max_size),
+            index: Mutex::new(Index::default()),
+            counter: AtomicUsize::new(0),
+        });
+
+        let mut loaded = Vec::new();
+        for entry in fs::read_dir(&cache.dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("meta") => {}
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            let meta = File::open(&path).ok()
+                .and_then(|f| Meta::from_text(BufReader::new(f)))
+                // a file holding another key than its name says is not
+                // served for either
+                .filter(|meta| cache.meta_path(&meta.key) == path)
+                .and_then(|mut meta| {
+                    meta.size = fs::metadata(cache.body_path(&meta.key)).ok()?.len();
+                    Some(meta)
+                });
+            match meta {
+                Some(meta) => loaded.push(meta),
+                None => {
+                    warn!("{}: discarding invalid cache entry", path.display());
+                    let _ = fs::remove_file(&path);
+                    let _ = fs::remove_file(path.with_extension("body"));
+                }
+            }
+        }
+        // oldest first, so the most recently stored are used last
+        loaded.sort_by_key(|m| m.stored);
+        for meta in loaded {
+            cache.insert(meta);
+        }
+        Ok(cache)
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.meta", Sha1::from(key).digest()))
+    }
+
+    fn body_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.body", Sha1::from(key).digest()))
+    }
+
+    fn lookup(&self, primary: &str, req: &Request) -> Option<Meta> {
+        let mut index = self.index.lock().unwrap();
+        let names = index.vary.get(primary).cloned().unwrap_or_default();
+        let key = variant_key(primary, &vary_values(&names, req));
+        index.clock += 1;
+        let clock = index.clock;
+        let meta = index.entries.get_mut(&key)?;
+        meta.used = clock;
+        Some(meta.clone())
+    }
+
+    fn insert(&self, mut meta: Meta) {
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        meta.used = index.clock;
+        index.vary.insert(meta.primary.clone(), meta.vary.iter().map(|(k, _)| k.clone()).collect());
+        index.size += meta.size;
+        if let Some(old) = index.entries.insert(meta.key.clone(), meta) {
+            index.size -= old.size;
+        }
+        self.evict(&mut index);
+    }
+
+    /// The directory the entries are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Change the size the cache may grow to, evicting entries as needed.
+    pub fn resize(&self, max_size: u64) {
+        self.max_size.store(max_size, Ordering::SeqCst);
+        self.evict(&mut self.index.lock().unwrap());
+    }
+
+    /// Evict the least recently used entries until the cache fits.
+    fn evict(&self, index: &mut Index) {
+        while index.size > self.max_size.load(Ordering::SeqCst) {
+            let victim = match index.entries.values().min_by_key(|m| m.used) {
+                Some(m) => m.key.clone(),
+                None => break,
+            };
+            debug!("cache `{}`: evicting {}", self.name, victim);
+            self.remove(index, &victim);
+        }
+    }
+
+    fn remove(&self, index: &mut Index, key: &str) {
+        if let Some(meta) = index.entries.remove(key) {
+            index.size -= meta.size;
+            let _ = fs::remove_file(self.meta_path(key));
+            let _ = fs::remove_file(self.body_path(key));
+        }
+    }
+
+    /// Remove every entry for `primary`, or all entries; returns how many
+    /// were removed.
+    pub fn purge(&self, primary: Option<&str>) -> usize {
+        let mut index = self.index.lock().unwrap();
+        let keys = index.entries.values()
+            .filter(|m| primary.is_none_or(|p| m.primary == p))
+            .map(|m| m.key.clone())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.remove(&mut index, key);
+        }
+        if let Some(primary) = primary {
+            index.vary.remove(primary);
+        }
+        keys.len()
+    }
+
+    /// Answer `req` from the cache or by forwarding it to `pool`.
+    pub fn handle(cache: &Arc<Cache>, pool: &Arc<Pool>, timeout: Duration, limits: &RequestLimits, req: &Request) -> Response {
+        let primary = primary_key(req);
+        match req.method() {
+            method if method.is_cacheable() => {}
+            method => {
+                // unsafe methods invalidate [RFC7234, Section 4.4]
+                if !method.is_safe() {
+                    cache.purge(Some(&primary));
+                }
+                return label(proxy::forward(pool, timeout, limits, req), "BYPASS");
+            }
+        }
+        let request_cc = req.header("Cache-Control")
+            .and_then(|v| CacheControl::from_str(v).ok())
+            .unwrap_or_default();
+        if request_cc.has("no-store") {
+            return label(proxy::forward(pool, timeout, limits, req), "BYPASS");
+        }
+        // what the origin answers an authorized request may be meant for
+        // that client alone, so it is not answered from the cache
+        if req.header("Authorization").is_some() {
+            if *req.method() == Method::HEAD {
+                return label(proxy::forward(pool, timeout, limits, req), "BYPASS");
+            }
+            let res = proxy::forward_for_cache(pool, timeout, limits, req, &[]);
+            return label(Cache::store(cache, primary, req, res), "BYPASS");
+        }
+
+        let now = now();
+        let meta = match cache.lookup(&primary, req) {
+            Some(meta) => meta,
+            None if *req.method() == Method::HEAD => return label(proxy::forward(pool, timeout, limits, req), "MISS"),
+            None => {
+                let res = proxy::forward_for_cache(pool, timeout, limits, req, &[]);
+                return label(Cache::store(cache, primary, req, res), "MISS");
+            }
+        };
+
+        if now < meta.fresh_until && !request_cc.has("no-cache") {
+            if let Some(res) = cache.serve(&meta, req, "HIT") {
+                return res;
+            }
+        }
+        // whole seconds past freshness, so a window of 0 allows none
+        let staleness = now.saturating_sub(meta.fresh_until);
+        if !meta.must_revalidate && !request_cc.has("no-cache") && staleness < meta.stale_while_revalidate {
+            if let Some(res) = cache.serve(&meta, req, "STALE") {
+                Cache::revalidate_in_background(cache, pool, timeout, limits, req, &meta);
+                return res;
+            }
+        }
+
+        let res = proxy::forward_for_cache(pool, timeout, limits, req, &meta.validators());
+        match res.status_code().to_u16() {
+            304 => {
+                let meta = cache.refresh(meta, &res);
+                if let Some(res) = cache.serve(&meta, req, "REVALIDATED") {
+                    return res;
+                }
+                let res = proxy::forward_for_cache(pool, timeout, limits, req, &[]);
+                label(Cache::store(cache, primary, req, res), "MISS")
+            }
+            500..=504 if !meta.must_revalidate && staleness < meta.stale_if_error => {
+                match cache.serve(&meta, req, "STALE") {
+                    Some(stale) => stale,
+                    None => res,
+                }
+            }
+            _ => label(Cache::store(cache, primary, req, res), "EXPIRED"),
+        }
+    }
+
+    fn revalidate_in_background(cache: &Arc<Cache>, pool: &Arc<Pool>, timeout: Duration, limits: &RequestLimits,
+                                req: &Request, meta: &Meta) {
+        if !cache.index.lock().unwrap().revalidating.insert(meta.key.clone()) {
+            return;
+        }
+        let (cache, pool, limits, req, meta) = (cache.clone(), pool.clone(), limits.clone(), req.clone(), meta.clone());
+        thread::spawn(move || {
+            let res = proxy::forward_for_cache(&pool, timeout, &limits, &req, &meta.validators());
+            if res.status_code() == StatusCode::NotModified {
+                cache.refresh(meta.clone(), &res);
+            } else if !res.status_code().is_server_error() {
+                let mut res = Cache::store(&cache, meta.primary.clone(), &req, res);
+                if let Err(e) = io::copy(&mut res.take_stream(), &mut io::sink()) {
+                    warn!("cache `{}`: revalidating {}: {}", cache.name, meta.primary, e);
+                }
+            }
+            cache.index.lock().unwrap().revalidating.remove(&meta.key);
+        });
+    }
+
+    /// Build the response for `meta` from the stored entry, or `None` if
+    /// the body has gone missing.
+    fn serve(&self, meta: &Meta, req: &Request, status: &str) -> Option<Response> {
+        let mut res = Response::new(StatusCode::from_u16(meta.status)?);
+        for (k, v) in &meta.header {
+            res.append_header(k.as_str(), v.as_str());
+        }
+        res.set_header("Age", now().saturating_sub(meta.stored).to_string());
+        res.set_header("X-Cache", status);
+
+        let etag = meta.header("ETag");
+        if etag.is_some() && req.header("If-None-Match").is_some_and(|v| v.split(',').any(|t| Some(t.trim()) == etag)) {
+            let mut not_modified = Response::new(StatusCode::NotModified);
+            for name in &["ETag", "Cache-Control", "Expires", "Last-Modified", "Age", "X-Cache"] {
+                if let Some(v) = res.header(name).map(|v| v.to_string()) {
+                    not_modified.set_header(*name, v);
+                }
+            }
+            return Some(not_modified);
+        }
+
+        if *req.method() == Method::HEAD {
+            // with no body to measure, the length is only the stored one
+            res.set_stream(io::empty());
+            return Some(res);
+        }
+        match File::open(self.body_path(&meta.key)) {
+            Ok(file) => res.set_stream(file),
+            Err(e) => {
+                warn!("cache `{}`: {}: {}", self.name, meta.primary, e);
+                let mut index = self.index.lock().unwrap();
+                self.remove(&mut index, &meta.key);
+                return None;
+            }
+        }
+        Some(res)
+    }
+
+    /// Update a stored entry with the header fields of a 304 response.
+    fn refresh(&self, mut meta: Meta, res: &Response) -> Meta {
+        let updated: Vec<_> = res.headers()
+            .filter(|(k, _)| !k.eq_ignore_ascii_case("Content-Length"))
+            .collect();
+        meta.header.retain(|(name, _)| !updated.iter().any(|(k, _)| name.eq_ignore_ascii_case(k)));
+        for (k, v) in updated {
+            meta.header.push((k.to_string(), v.to_string()));
+        }
+        let now = now();
+        if let Some(freshness) = freshness(&meta.header, now) {
+            meta.stored = now;
+            meta.fresh_until = now + freshness.lifetime;
+            meta.stale_while_revalidate = freshness.stale_while_revalidate;
+            meta.stale_if_error = freshness.stale_if_error;
+            meta.must_revalidate = freshness.must_revalidate;
+        }
+        if let Err(e) = fs::write(self.meta_path(&meta.key), meta.to_text()) {
+            warn!("cache `{}`: {}: {}", self.name, meta.primary, e);
+        }
+        self.insert(meta.clone());
+        meta
+    }
+
+    /// Store `res` if it is cacheable; its body is written to the cache
+    /// while it is relayed to the client.
+    fn store(cache: &Arc<Cache>, primary: String, req: &Request, mut res: Response) -> Response {
+        let code = res.status_code().to_u16();
+        if !CACHEABLE.contains(&code) || res.header("Set-Cookie").is_some() {
+            return res;
+        }
+        // [RFC7234, Section 3.2]
+        if req.header("Authorization").is_some() {
+            let response_cc = res.header("Cache-Control")
+                .and_then(|v| CacheControl::from_str(v).ok())
+                .unwrap_or_default();
+            if !["public", "s-maxage", "must-revalidate"].iter().any(|d| response_cc.has(d)) {
+                return res;
+            }
+        }
+        let header = res.headers()
+            .filter(|(k, _)| !k.eq_ignore_ascii_case("Age") && !k.eq_ignore_ascii_case("X-Cache"))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        let now = now();
+        let freshness = match freshness(&header, now) {
+            Some(freshness) => freshness,
+            None => return res,
+        };
+        let names = match res.header("Vary") {
+            Some(v) if v.trim() == "*" => return res,
+            Some(v) => v.split(',').map(|n| n.trim().to_lowercase()).filter(|n| !n.is_empty()).collect(),
+            None => Vec::new(),
+        };
+        let vary = vary_values(&names, req);
+        let meta = Meta {
+            key: variant_key(&primary, &vary),
+            primary,
+            status: code,
+            stored: now,
+            fresh_until: now + freshness.lifetime,
+            stale_while_revalidate: freshness.stale_while_revalidate,
+            stale_if_error: freshness.stale_if_error,
+            must_revalidate: freshness.must_revalidate,
+            vary,
+            header,
+            size: 0,
+            used: 0,
+        };
+
+        let tmp = cache.dir.join(format!("{}-{}.tmp", process::id(), cache.counter.fetch_add(1, Ordering::SeqCst)));
+        let file = match File::create(&tmp) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("cache `{}`: {}: {}", cache.name, tmp.display(), e);
+                return res;
+            }
+        };
+        let body = res.take_stream();
+        res.set_stream(Fill {
+            body,
+            file: Some(BufWriter::new(file)),
+            tmp,
+            meta: Some(meta),
+            cache: cache.clone(),
+            written: 0,
+        });
+        res
+    }
+}
+
+/// A response body copied into the cache as it is read; the entry is
+/// only added once the body is complete.
+struct Fill {
+    body: Box<dyn Read + Send>,
+    file: Option<BufWriter<File>>,
+    tmp: PathBuf,
+    meta: Option<Meta>,
+    cache: Arc<Cache>,
+    written: u64,
+}
+
+impl Fill {
+    fn abandon(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let (mut file, mut meta) = match (self.file.take(), self.meta.take()) {
+            (Some(file), Some(meta)) => (file, meta),
+            _ => return Ok(()),
+        };
+        file.flush()?;
+        meta.size = self.written;
+        fs::rename(&self.tmp, self.cache.body_path(&meta.key))?;
+        fs::write(self.cache.meta_path(&meta.key), meta.to_text())?;
+        self.cache.insert(meta);
+        Ok(())
+    }
+}
+
+impl Read for Fill {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.body.read(buf)?;
+        if n == 0 {
+            if let Err(e) = self.finish() {
+                warn!("cache `{}`: {}", self.cache.name, e);
+                self.abandon();
+            }
+        } else if self.file.is_some() {
+            let too_large = self.written + n as u64 > self.cache.max_size.load(Ordering::SeqCst);
+            if too_large || self.file.as_mut().unwrap().write_all(&buf[..n]).is_err() {
+                self.abandon();
+            }
+            self.written += n as u64;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+struct Freshness {
+    lifetime: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+    must_revalidate: bool,
+}
+
+/// How long a response with header fields `header` stays fresh
+/// [[RFC7234, Section 4.2.1](https://tools.ietf.org/html/rfc7234#section-4.2.1)],
+/// or `None` if it must not be stored.
+fn freshness(header: &[(String, String)], now: u64) -> Option<Freshness> {
+    let get = |name: &str| header.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str());
+    let cc = get("Cache-Control")
+        .and_then(|v| CacheControl::from_str(v).ok())
+        .unwrap_or_default();
+    if cc.has("no-store") || cc.has("private") {
+        return None;
+    }
+    let validated = get("ETag").is_some() || get("Last-Modified").is_some();
+    let lifetime = if cc.has("no-cache") {
+        Some(0)
+    } else if let Some(s) = cc.seconds("s-maxage").or_else(|| cc.seconds("max-age")) {
+        Some(s)
+    } else if let Some(expires) = get("Expires") {
+        // an invalid date means already expired
+        let date = get("Date").and_then(parse_date).map_or(now as i64, |d| d.timestamp());
+        Some(parse_date(expires).map_or(0, |e| (e.timestamp() - date).max(0) as u64))
+    } else {
+        None
+    };
+    match lifetime {
+        Some(0) if !validated => None,
+        Some(lifetime) => Some(Freshness {
+            lifetime,
+            stale_while_revalidate: cc.seconds("stale-while-revalidate").unwrap_or(0),
+            stale_if_error: cc.seconds("stale-if-error").unwrap_or(0),
+            must_revalidate: cc.has("must-revalidate") || cc.has("proxy-revalidate") || cc.has("no-cache"),
+        }),
+        None if validated => Some(Freshness {
+            lifetime: 0,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            must_revalidate: true,
+        }),
+        None => None,
+    }
+}
+
+/// The host and the request-target as forwarded, still percent-encoded,
+/// so that targets the upstream may tell apart are stored apart.
+fn primary_key(req: &Request) -> String {
+    let host = req.host().unwrap_or_default();
+    let target = req.target();
+    match target.query() {
+        Some(query) => format!("{}{}?{}", host, target.path(), query),
+        None => format!("{}{}", host, target.path()),
+    }
+}
+
+fn vary_values(names: &[String], req: &Request) -> Vec<(String, String)> {
+    names.iter()
+        .map(|n| (n.clone(), req.header(n).unwrap_or("").to_string()))
+        .collect()
+}
+
+fn variant_key(primary: &str, vary: &[(String, String)]) -> String {
+    let mut key = primary.to_string();
+    for (k, v) in vary {
+        key.push(VARY_SEPARATOR);
+        key.push_str(&format!("{}={}", k, v));
+    }
+    key
+}
+
+fn label(mut res: Response, status: &str) -> Response {
+    res.set_header("X-Cache", status);
+    res
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::TcpListener;
+
+    use super::*;
+    use super::super::common::HTTPVersion;
+    use super::super::header::Header;
+    use super::super::proxy::Upstream;
+    use super::super::upstream::{KeepAlive, Strategy};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A stand-in origin answering each request head with `answer(head)`,
+    /// counting the requests.
+    fn origin<F>(answer: F) -> (Arc<Pool>, Arc<AtomicUsize>)
+        where F: Fn(&str) -> String + Send + Sync + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (answer, counted) = (Arc::new(answer), requests.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, answer, counted) = (stream.unwrap(), answer.clone(), counted.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(&stream);
+                    let mut head = String::new();
+                    while let Ok(n) = reader.read_line(&mut head) {
+                        if n == 0 {
+                            return;
+                        }
+                        if head.ends_with("\r\n\r\n") {
+                            counted.fetch_add(1, Ordering::SeqCst);
+                            if (&stream).write_all(answer(&head).as_bytes()).is_err() {
+                                return;
+                            }
+                            head.clear();
+                        }
+                    }
+                });
+            }
+        });
+        let upstream = Upstream {
+            host: "127.0.0.1".to_string(),
+            port,
+            path: String::new(),
+        };
+        let pool = Pool::new("origin".to_string(), vec![upstream], Strategy::RoundRobin, 0, Duration::from_secs(0),
+                             KeepAlive::default(), None);
+        (pool, requests)
+    }
+
+    fn response(header: &str, body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n{}", header, body.len(), body)
+    }
+
+    fn cache(max_size: u64) -> Arc<Cache> {
+        let dir = env::temp_dir().join(format!("cache-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        Cache::open("test".to_string(), dir, max_size).unwrap()
+    }
+
+    /// Send a GET for `uri` through the cache; the `X-Cache` label, the
+    /// status and the body.
+    fn get(cache: &Arc<Cache>, pool: &Arc<Pool>, uri: &str, header: &[(&str, &str)]) -> (String, u16, String) {
+        let mut fields = Header::new();
+        fields.append("Host", "example.com");
+        for (k, v) in header {
+            fields.append(*k, *v);
+        }
+        let req = Request::from_parts(Method::GET, uri, HTTPVersion::new(1, 1), fields, None, None, None).unwrap();
+        let mut res = Cache::handle(cache, pool, Duration::from_secs(5), &RequestLimits::default(), &req);
+        let mut body = String::new();
+        res.take_stream().read_to_string(&mut body).unwrap();
+        (res.header("X-Cache").unwrap_or("").to_string(), res.status_code().to_u16(), body)
+    }
+
+    fn header(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn freshness_lifetime() {
+        let lifetime = |fields: &[(&str, &str)]| freshness(&header(fields), 1_000_000).map(|f| (f.lifetime, f.must_revalidate));
+        assert_eq!(lifetime(&[("Cache-Control", "max-age=60")]), Some((60, false)));
+        assert_eq!(lifetime(&[("Cache-Control", "max-age=60, s-maxage=10")]), Some((10, false)));
+        assert_eq!(lifetime(&[("Date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("Expires", "Sun, 06 Nov 1994 08:50:37 GMT")]),
+                   Some((60, false)));
+        // an invalid date is in the past, and a lifetime of 0 needs a validator
+        assert_eq!(lifetime(&[("Expires", "0")]), None);
+        assert_eq!(lifetime(&[("Expires", "0"), ("ETag", "\"a\"")]), Some((0, false)));
+        assert_eq!(lifetime(&[("Cache-Control", "no-cache"), ("ETag", "\"a\"")]), Some((0, true)));
+        assert_eq!(lifetime(&[("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")]), Some((0, true)));
+        assert_eq!(lifetime(&[("Cache-Control", "max-age=60, must-revalidate")]), Some((60, true)));
+        assert_eq!(lifetime(&[("Cache-Control", "max-age=60, no-store")]), None);
+        assert_eq!(lifetime(&[("Cache-Control", "max-age=60, private")]), None);
+        assert_eq!(lifetime(&[]), None);
+
+        let stale = freshness(&header(&[("Cache-Control", "max-age=1, stale-while-revalidate=5, stale-if-error=9")]), 0).unwrap();
+        assert_eq!((stale.stale_while_revalidate, stale.stale_if_error), (5, 9));
+    }
+
+    #[test]
+    fn fresh_responses_are_served_from_the_cache() {
+        let (pool, requests) = origin(|head| {
+            let target = head.split(' ').nth(1).unwrap().to_string();
+            response("Cache-Control: max-age=60\r\n", &target)
+        });
+        let cache = cache(1 << 20);
+        assert_eq!(get(&cache, &pool, "/a/b", &[]), ("MISS".to_string(), 200, "/a/b".to_string()));
+        assert_eq!(get(&cache, &pool, "/a/b", &[]), ("HIT".to_string(), 200, "/a/b".to_string()));
+        // the same path once decoded, but not to the origin
+        assert_eq!(get(&cache, &pool, "/a%2Fb", &[]), ("MISS".to_string(), 200, "/a%2Fb".to_string()));
+        assert_eq!(get(&cache, &pool, "/a%2fb", &[]), ("HIT".to_string(), 200, "/a%2Fb".to_string()));
+        assert_eq!(get(&cache, &pool, "/a/b", &[("Cache-Control", "no-cache")]).0, "EXPIRED");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // entries outlive the index, under the names their keys hash to
+        let reopened = Cache::open("test".to_string(), cache.dir(), 1 << 20).unwrap();
+        assert_eq!(get(&reopened, &pool, "/a%2Fb", &[]), ("HIT".to_string(), 200, "/a%2Fb".to_string()));
+        let a = reopened.meta_path("example.com/a/b");
+        let b = reopened.meta_path("example.com/a%2Fb");
+        fs::copy(&a, &b).unwrap();
+        let reopened = Cache::open("test".to_string(), cache.dir(), 1 << 20).unwrap();
+        assert_eq!(get(&reopened, &pool, "/a%2Fb", &[]), ("MISS".to_string(), 200, "/a%2Fb".to_string()));
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn variants_are_selected_by_vary() {
+        let (pool, requests) = origin(|head| {
+            let language = head.lines()
+                .find(|l| l.to_lowercase().starts_with("accept-language:"))
+                .map_or("none", |l| l[16..].trim());
+            response("Cache-Control: max-age=60\r\nVary: Accept-Language\r\n", language)
+        });
+        let cache = cache(1 << 20);
+        assert_eq!(get(&cache, &pool, "/", &[("Accept-Language", "en")]), ("MISS".to_string(), 200, "en".to_string()));
+        assert_eq!(get(&cache, &pool, "/", &[("Accept-Language", "fr")]), ("MISS".to_string(), 200, "fr".to_string()));
+        assert_eq!(get(&cache, &pool, "/", &[("Accept-Language", "en")]), ("HIT".to_string(), 200, "en".to_string()));
+        assert_eq!(get(&cache, &pool, "/", &[("Accept-Language", "fr")]), ("HIT".to_string(), 200, "fr".to_string()));
+        assert_eq!(get(&cache, &pool, "/", &[]), ("MISS".to_string(), 200, "none".to_string()));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn stale_responses_are_revalidated() {
+        let (pool, requests) = origin(|head| {
+            if head.contains("If-None-Match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nCache-Control: max-age=60\r\n\r\n".to_string()
+            } else {
+                response("Cache-Control: max-age=0\r\nETag: \"v1\"\r\n", "body")
+            }
+        });
+        let cache = cache(1 << 20);
+        assert_eq!(get(&cache, &pool, "/", &[]), ("MISS".to_string(), 200, "body".to_string()));
+        assert_eq!(get(&cache, &pool, "/", &[]), ("REVALIDATED".to_string(), 200, "body".to_string()));
+        // fresh for a minute now
+        assert_eq!(get(&cache, &pool, "/", &[]), ("HIT".to_string(), 200, "body".to_string()));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // and answered to a client holding the same validator
+        assert_eq!(get(&cache, &pool, "/", &[("If-None-Match", "\"v1\"")]), ("HIT".to_string(), 304, String::new()));
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let (pool, requests) = origin(|_| response("Cache-Control: max-age=60\r\n", "0123456789"));
+        let cache = cache(25);
+        get(&cache, &pool, "/a", &[]);
+        get(&cache, &pool, "/b", &[]);
+        assert_eq!(get(&cache, &pool, "/a", &[]).0, "HIT");
+        // /b was used last longest ago
+        get(&cache, &pool, "/c", &[]);
+        assert_eq!(get(&cache, &pool, "/a", &[]).0, "HIT");
+        assert_eq!(get(&cache, &pool, "/c", &[]).0, "HIT");
+        assert!(!cache.body_path("example.com/b").exists());
+        assert_eq!(get(&cache, &pool, "/b", &[]).0, "MISS");
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // bodies larger than the whole cache are relayed, not stored
+        cache.resize(5);
+        assert_eq!(cache.index.lock().unwrap().size, 0);
+        assert_eq!(get(&cache, &pool, "/d", &[]), ("MISS".to_string(), 200, "0123456789".to_string()));
+        assert_eq!(get(&cache, &pool, "/d", &[]).0, "MISS");
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
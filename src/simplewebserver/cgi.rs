@@ -0,0 +1,441 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! CGI/1.1 [[RFC3875](https://tools.ietf.org/html/rfc3875)]
+//!
+//! The Request-URI path is resolved below the handler's root one segment
+//! at a time; the first regular file found is the script, the rest of the
+//! path is passed on as `PATH_INFO`:
+//!
+//! ```notrust
+//! /cgi-bin/admin.cgi/users/42?page=2
+//! |----SCRIPT_NAME--||-PATH_INFO-| |QUERY_STRING
+//! ```
+use std::env;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::header::Authorization;
+use super::request::Request;
+use super::response::Response;
+use super::root::Root;
+use super::status::StatusCode;
+
+static SERVER_SOFTWARE: &str = concat!("simplewebserver/", env!("CARGO_PKG_VERSION"));
+
+/// Largest header block accepted from a script.
+pub const MAX_HEAD: usize = 64 * 1024;
+
+/// Run the script addressed by `req` below `root` and turn its output into
+/// a response: 404 if there is no script, 500 if it cannot be started,
+/// 502 if its output is not a valid CGI response and 504 if it does not
+/// write its header block within `timeout`. The body is streamed, and cut
+/// off if the script goes `timeout` without writing any of it.
+pub fn run(root: &Root, timeout: Duration, req: &Request) -> Response {
+    let path = req.path();
+    if let Err(status_code) = root.resolve(path) {
+        return Response::error(status_code);
+    }
+    let root = root.path();
+    let (script, script_name, path_info) = match find_script(root, path) {
+        Some(found) => found,
+        None => return Response::error(StatusCode::NotFound),
+    };
+
+    let mut command = Command::new(&script);
+    command.env_clear()
+        .envs(environment(root, &script, &script_name, &path_info, req))
+        .current_dir(script.parent().unwrap_or(root))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("{}: {}", script.display(), e);
+            return Response::error(StatusCode::InternalServerError);
+        }
+    };
+
+    // feed stdin and read the header block on their own threads, so a
+    // script that does not read its input cannot block us
+    if let Some(mut stdin) = child.stdin.take() {
+        let body = req.body().map(|b| b.to_vec()).unwrap_or_default();
+        thread::spawn(move || stdin.write_all(&body));
+    }
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let head = read_head(&mut stdout);
+        let _ = tx.send((head, stdout));
+    });
+
+    // the timeout covers the header block, then each wait for the body
+    match rx.recv_timeout(timeout) {
+        Ok((Ok(mut res), stdout)) => {
+            res.set_stream(Output::new(script, stdout, child, timeout));
+            res
+        }
+        Ok((Err(message), _)) => {
+            warn!("{}: {}", script.display(), message);
+            kill(&mut child);
+            Response::error(StatusCode::BadGateway)
+        }
+        Err(_) => {
+            warn!("{}: timed out after {}s", script.display(), timeout.as_secs());
+            kill(&mut child);
+            Response::error(StatusCode::GatewayTimeout)
+        }
+    }
+}
+
+/// The body of a script's output, read as it is written. The script is
+/// waited for at the end of its output, and killed if the response is
+/// dropped before then or it stays silent for longer than `idle`.
+struct Output {
+    script: PathBuf,
+    /// Chunks read from stdout on a thread of their own, as a pipe cannot
+    /// be read with a timeout; empty at the end.
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+    child: Option<Child>,
+    idle: Duration,
+}
+
+impl Output {
+    fn new(script: PathBuf, mut stdout: BufReader<ChildStdout>, child: Child, idle: Duration) -> Self {
+        // one chunk ahead at most, so a slow client holds the script up
+        let (tx, chunks) = mpsc::sync_channel(1);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; 8 * 1024];
+            let read = stdout.read(&mut chunk).map(|n| {
+                chunk.truncate(n);
+                chunk
+            });
+            let end = !matches!(read, Ok(ref chunk) if !chunk.is_empty());
+            if tx.send(read).is_err() || end {
+                return;
+            }
+        });
+        Output {
+            script,
+            chunks,
+            chunk: io::Cursor::new(Vec::new()),
+            child: Some(child),
+            idle,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            match child.wait() {
+                Ok(status) if !status.success() => warn!("{}: {}", self.script.display(), status),
+                Err(e) => warn!("{}: {}", self.script.display(), e),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.child.is_none() {
+            return Ok(0);
+        }
+        if self.chunk.position() as usize == self.chunk.get_ref().len() {
+            match self.chunks.recv_timeout(self.idle) {
+                Ok(Ok(ref chunk)) if chunk.is_empty() => {
+                    self.finish();
+                    return Ok(0);
+                }
+                Ok(Ok(chunk)) => self.chunk = io::Cursor::new(chunk),
+                Ok(Err(e)) => return Err(e),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.finish();
+                    return Ok(0);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    warn!("{}: no output for {}s", self.script.display(), self.idle.as_secs());
+                    if let Some(mut child) = self.child.take() {
+                        kill(&mut child);
+                    }
+                    // an error rather than the end, so the client does not
+                    // take what it got for the whole body
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "script stopped writing"));
+                }
+            }
+        }
+        self.chunk.read(buf)
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            kill(&mut child);
+        }
+    }
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Walk `path` below `root` until a regular file is found, returning it
+/// with the `SCRIPT_NAME` and `PATH_INFO` it splits the path into.
+pub fn find_script(root: &Path, path: &str) -> Option<(PathBuf, String, String)> {
+    let mut file = root.to_path_buf();
+    let mut end = 0;
+    for segment in path.split('/').skip(1) {
+        if segment.is_empty() {
+            return None;
+        }
+        file.push(segment);
+        end += 1 + segment.len();
+        if file.is_file() {
+            return Some((file, path[..end].to_string(), path[end..].to_string()));
+        }
+        if !file.is_dir() {
+            return None;
+        }
+    }
+    None
+}
+
+/// The meta-variables of [[RFC3875, Section 4.1](https://tools.ietf.org/html/rfc3875#section-4.1)].
+pub fn environment(root: &Path, script: &Path, script_name: &str, path_info: &str, req: &Request) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    let mut set = |k: &str, v: String| env.push((k.to_string(), v));
+
+    if let Ok(path) = env::var("PATH") {
+        set("PATH", path);
+    }
+    set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    set("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string());
+    set("SERVER_PROTOCOL", req.version().to_string());
+    let local = req.local_addr();
+    set("SERVER_NAME", req.host()
+        .or_else(|| local.map(|a| a.ip().to_string()))
+        .unwrap_or_default());
+    if let Some(local) = local {
+        set("SERVER_PORT", local.port().to_string());
+    }
+    set("REQUEST_METHOD", req.method().to_string());
+    set("REQUEST_URI", req.uri().to_string());
+    set("DOCUMENT_ROOT", root.display().to_string());
+    set("SCRIPT_NAME", script_name.to_string());
+    set("SCRIPT_FILENAME", script.display().to_string());
+    set("PATH_INFO", path_info.to_string());
+    if !path_info.is_empty() {
+        set("PATH_TRANSLATED", root.join(path_info.trim_start_matches('/')).display().to_string());
+    }
+    set("QUERY_STRING", req.query().to_string());
+    if let Some(peer) = req.peer_addr() {
+        set("REMOTE_ADDR", peer.ip().to_string());
+        set("REMOTE_HOST", peer.ip().to_string());
+        set("REMOTE_PORT", peer.port().to_string());
+    }
+    if let Ok(Some(Authorization::BaseAuthorization { username, .. })) = req.header_map().authorization() {
+        set("AUTH_TYPE", "Basic".to_string());
+        set("REMOTE_USER", username);
+    }
+    if let Some(body) = req.body() {
+        set("CONTENT_LENGTH", body.len().to_string());
+    }
+    if let Some(content_type) = req.header("Content-Type") {
+        set("CONTENT_TYPE", content_type.to_string());
+    }
+
+    for (k, v) in req.headers() {
+        // passed above, or credentials the script must not see
+        if k.eq_ignore_ascii_case("Content-Length") || k.eq_ignore_ascii_case("Content-Type")
+            || k.eq_ignore_ascii_case("Authorization") || k.eq_ignore_ascii_case("Proxy-Authorization")
+            // "httpoxy": HTTP_PROXY would be taken for a proxy setting
+            || k.eq_ignore_ascii_case("Proxy") {
+            continue;
+        }
+        let name: String = k.chars()
+            .map(|c| if c == '-' { '_' } else { c.to_ascii_uppercase() })
+            .collect();
+        if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            set(&format!("HTTP_{}", name), v.to_string());
+        }
+    }
+    env
+}
+
+/// Read the header block of a CGI response
+/// [[RFC3875, Section 6](https://tools.ietf.org/html/rfc3875#section-6)]
+/// from `reader`, leaving it at the start of the body. Blocks larger than
+/// `MAX_HEAD` are refused.
+///
+/// A `Location` without `Status` is a redirect. A local redirect, to a path
+/// on this server, is sent to the client as well rather than re-processed.
+pub fn read_head<R: BufRead>(reader: &mut R) -> Result<Response, String> {
+    let mut headers = Vec::new();
+    let mut remaining = MAX_HEAD;
+    loop {
+        let mut line = Vec::new();
+        let n = reader.by_ref().take(remaining as u64).read_until(b'\n', &mut line).map_err(|e| e.to_string())?;
+        if n == remaining && !line.ends_with(b"\n") {
+            return Err(format!("header block larger than {} bytes", MAX_HEAD));
+        }
+        if n == 0 {
+            return Err("output ended before the end of the header block".to_string());
+        }
+        remaining -= n;
+        let line = String::from_utf8(line).map_err(|_| "header block is not UTF-8".to_string())?;
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().ok_or_else(|| format!("malformed header line `{}`", line))?.trim();
+        if name.is_empty() || name.contains(' ') {
+            return Err(format!("malformed header line `{}`", line));
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    if headers.is_empty() {
+        return Err("no header fields".to_string());
+    }
+
+    let field = |name: &str| headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str());
+    let mut reason_phrase = "";
+    let status = match field("Status") {
+        Some(status) => {
+            let mut cursor = status.splitn(2, ' ');
+            let code = cursor.next()
+                .and_then(|c| c.parse::<u16>().ok())
+                .ok_or_else(|| format!("malformed Status `{}`", status))?;
+            reason_phrase = cursor.next().unwrap_or("").trim();
+            StatusCode::from_u16(code).ok_or_else(|| format!("unsupported Status {}", code))?
+        }
+        None if field("Location").is_some() => StatusCode::MovedTemporarily,
+        None if field("Content-Type").is_some() => StatusCode::OK,
+        None => return Err("neither Content-Type, Location nor Status given".to_string()),
+    };
+
+    let mut res = Response::new(status);
+    res.set_reason_phrase(reason_phrase);
+    for (k, v) in &headers {
+        if k.eq_ignore_ascii_case("Status") || k.eq_ignore_ascii_case("Connection")
+            || k.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+        res.append_header(k.as_str(), v.as_str());
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use std::time::Instant;
+
+    use super::*;
+    use super::super::common::HTTPVersion;
+    use super::super::header::Header;
+    use super::super::request::Method;
+    use super::super::root::Symlinks;
+
+    fn head(output: &[u8]) -> (Result<Response, String>, Vec<u8>) {
+        let mut reader = io::Cursor::new(output.to_vec());
+        let res = read_head(&mut reader);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        (res, rest)
+    }
+
+    #[test]
+    fn header_block_is_read_up_to_the_body() {
+        let (res, rest) = head(b"Status: 404 Gone Fishing\r\nContent-Type: text/plain\r\n\r\nbody\r\n\r\nmore");
+        let res = res.unwrap();
+        assert_eq!(res.status_code(), StatusCode::NotFound);
+        assert_eq!(rest, b"body\r\n\r\nmore");
+
+        let (res, rest) = head(b"Content-Type: text/plain\n\nbody");
+        assert_eq!(res.unwrap().status_code(), StatusCode::OK);
+        assert_eq!(rest, b"body");
+
+        let (res, _) = head(b"Location: /elsewhere\r\n\r\n");
+        assert_eq!(res.unwrap().status_code(), StatusCode::MovedTemporarily);
+    }
+
+    #[test]
+    fn malformed_output_is_refused() {
+        assert!(head(b"Content-Type: text/plain\r\nbody").0.is_err());
+        assert!(head(b"no colon\r\n\r\n").0.is_err());
+        assert!(head(b"X-Only: this\r\n\r\n").0.is_err());
+        assert!(head(b"Status: abc\r\n\r\n").0.is_err());
+        assert!(head(b"\r\n").0.is_err());
+    }
+
+    #[test]
+    fn header_block_is_limited() {
+        let mut output = b"Content-Type: text/plain\r\n".to_vec();
+        let field = format!("X-Padding: {}\r\n", "x".repeat(1000));
+        while output.len() <= MAX_HEAD {
+            output.extend_from_slice(field.as_bytes());
+        }
+        output.extend_from_slice(b"\r\nbody");
+        let (res, _) = head(&output);
+        assert_eq!(res.unwrap_err(), format!("header block larger than {} bytes", MAX_HEAD));
+
+        // a single endless line is cut off as well
+        let mut output = b"Content-Type: ".to_vec();
+        output.extend(vec![b'x'; MAX_HEAD * 2]);
+        assert!(head(&output).0.is_err());
+    }
+
+    #[test]
+    fn silent_scripts_are_cut_off() {
+        let root = env::temp_dir().join(format!("cgi-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let script = root.join("stall.cgi");
+        fs::write(&script, "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\nstart'\nexec sleep 30\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let req = Request::from_parts(Method::GET, "/stall.cgi", HTTPVersion::new(1, 1), Header::new(), None, None, None).unwrap();
+        let started = Instant::now();
+        let mut res = run(&Root::new(root.as_path(), Symlinks::default(), false), Duration::from_secs(1), &req);
+        assert_eq!(res.status_code(), StatusCode::OK);
+        let mut body = res.take_stream();
+        let mut start = [0; 5];
+        body.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"start");
+        assert_eq!(body.read(&mut [0; 16]).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
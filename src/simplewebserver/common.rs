@@ -22,7 +22,9 @@
 use core::fmt;
 use std::str::FromStr;
 
-#[derive(Debug)]
+use super::error::ParseError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct HTTPVersion {
     major: u8,
     minor: u8,
@@ -35,14 +37,9 @@ impl HTTPVersion {
             minor,
         }
     }
-}
 
-impl Default for HTTPVersion {
-    fn default() -> Self {
-        HTTPVersion {
-            major: 0,
-            minor: 0,
-        }
+    pub fn major(&self) -> u8 {
+        self.major
     }
 }
 
@@ -53,16 +50,41 @@ impl fmt::Display for HTTPVersion {
 }
 
 impl FromStr for HTTPVersion {
-    type Err = ();
+    type Err = ParseError;
 
+    /// `HTTP/<major>.<minor>` [[RFC1945, Section 3.1](https://tools.ietf.org/html/rfc1945#section-3.1)]
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        let mut result = s.split(|c|
-            c == '/' ||
-                c == '.').filter(|k| !k.is_empty());
-        result.next(); // HTTP
-        Ok(HTTPVersion {
-            major: u8::from_str(result.next().unwrap()).unwrap(),
-            minor: u8::from_str(result.next().unwrap()).unwrap(),
-        })
+        let invalid = || ParseError::invalid("HTTP-Version", format!("`{}` is not of the form HTTP/<major>.<minor>", s));
+        let digits = match s.strip_prefix("HTTP/") {
+            Some(digits) => digits,
+            None => return Err(invalid()),
+        };
+        let mut result = digits.splitn(2, '.');
+        let mut number = || result.next()
+            .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|n| u8::from_str(n).ok());
+        match (number(), number()) {
+            (Some(major), Some(minor)) => Ok(HTTPVersion {
+                major,
+                minor,
+            }),
+            _ => Err(invalid()),
+        }
     }
-}
\ No newline at end of file
+}
+
+/// 64-bit FNV-1a, stable across runs unlike `DefaultHasher`, followed by
+/// the MurmurHash3 finalizer so that keys differing only in their last
+/// bytes still spread over the whole range.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
@@ -0,0 +1,1441 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Server configuration
+//!
+//! The configuration file is written in TOML:
+//!
+//! ```toml
+//! [[listen]]
+//! addr = "127.0.0.1:8080"
+//!
+//! [[listen]]
+//! addr = "127.0.0.1:8443"
+//! tls = { cert = "/etc/ssl/example.com.pem", key = "/etc/ssl/example.com.key" }
+//!
+//! [[listen]]
+//! addr = "127.0.0.1:8444"    # HTTP/1.1 only, for WebSocket and SSE
+//! tls = { cert = "/etc/ssl/example.com.pem", key = "/etc/ssl/example.com.key", http2 = false }
+//!
+//! [[listen]]
+//! addr = "127.0.0.1:8010"
+//! strict_http10 = true
+//!
+//! [[listen]]
+//! addr = "127.0.0.1:8020"    # behind a load balancer
+//! strict_parsing = true
+//!
+//! [limits]
+//! max_request_line = 8192     # 414 beyond
+//! max_headers = 100           # 431 beyond
+//! max_header_size = 16384     # 431 beyond
+//! max_body = 10485760         # 413 beyond, request bodies are held in memory
+//! header_timeout = 10         # 408 beyond
+//! body_timeout = 30           # 408 beyond
+//! send_timeout = 30
+//!
+//! [upstream.backend]
+//! servers = ["http://10.0.0.1:8080", "http://10.0.0.2:8080"]
+//! strategy = "least_conn"     # or "round_robin", "hash"
+//! # hash_key = "header:X-User" # or "ip", with strategy = "hash"
+//! max_fails = 3
+//! fail_timeout = 10
+//! health_check = { path = "/health", interval = 5, timeout = 2, fails = 2, passes = 2 }
+//! keepalive = { max_idle = 8, idle_timeout = 60, max_lifetime = 300 }
+//!
+//! [cache.main]
+//! path = "/var/cache/simplewebserver"
+//! max_size = 104857600
+//!
+//! [forward_proxy]
+//! listen = ["127.0.0.1:3128"]
+//! allow = ["crates.io:443", "*.github.com", "static.rust-lang.org"]
+//! timeout = 30
+//! access_log = "/var/log/egress.log"
+//!
+//! [[server]]
+//! server_name = ["example.com", "*.corp.example"]
+//! default = true
+//! root = "/var/www"
+//! access_log = "/var/log/example.com.log"
+//!
+//!   [[server.location]]
+//!   prefix = "/"
+//!   handler = "static"
+//!   root = "/var/www"
+//!   index = ["index.html"]
+//!   symlinks = "within_root"  # or "deny", "follow"
+//!   dotfiles = false
+//!
+//!   [[server.location]]
+//!   exact = "/old"
+//!   handler = "redirect"
+//!   to = "/new"
+//!   status = 301            # or 302 (the default), 303, 307, 308
+//!
+//!   [[server.location]]
+//!   prefix = "/api/"
+//!   handler = "proxy"
+//!   upstream = "backend"   # or a single "http://127.0.0.1:9000"
+//!   timeout = 30
+//!   cache = "main"
+//!
+//!   [[server.location]]
+//!   exact = "/purge"        # PURGE ?key=example.com/path, or everything
+//!   handler = "purge"
+//!   cache = "main"
+//!
+//!   [[server.location]]
+//!   prefix = "/cgi-bin/"
+//!   handler = "cgi"
+//!   root = "/usr/lib"       # runs /usr/lib/cgi-bin/<script>
+//!   timeout = 30
+//!
+//!   [[server.location]]
+//!   regex = "\\.php(/|$)"
+//!   handler = "fastcgi"
+//!   pass = "127.0.0.1:9000"  # or "unix:/run/php/php-fpm.sock"
+//!   root = "/var/www"
+//!   index = ["index.php"]
+//!
+//!   [[server.location]]
+//!   exact = "/live"
+//!   handler = "websocket"
+//!   service = "broadcast"   # or "echo"
+//!   max_message = 65536
+//!
+//!   [[server.location]]
+//!   exact = "/events"       # GET to subscribe, POST to publish
+//!   handler = "sse"
+//!   heartbeat = 15
+//!   history = 100
+//!
+//!   [[server.location]]
+//!   exact = "/status"
+//!   handler = "status"
+//!
+//!   [[server.location]]
+//!   regex = "^/admin/"
+//!   handler = "autoindex"
+//!   root = "/var/www"
+//!   headers = { "X-Frame-Options" = "DENY" }
+//!   limits = { max_body = 1024 }
+//!   auth = { realm = "admin", users = { alice = "secret" } }
+//! ```
+//!
+//! Every location selects exactly one way of matching (`exact`, `prefix`
+//! or `regex`) and one handler (`static` if omitted). A server may set a
+//! `root` inherited by its locations and an `access_log` file. Locations are matched the way nginx does:
+//! an exact match wins, otherwise the longest matching prefix is
+//! remembered, then regular expressions are tried in the order they
+//! appear and the first one that matches is used; if none does, the
+//! remembered prefix is used.
+//!
+//! Handlers serving files from a `root` never leave it: symbolic links are
+//! followed only while they point below the root unless `symlinks` says
+//! otherwise, and files whose name starts with a `.` (other than
+//! `.well-known`) are not served or listed unless `dotfiles` is set.
+//!
+//! Connections accepted on a `forward_proxy` address never reach a server.
+//!
+//! `limits` bound every request received on any listener: the sizes are in
+//! bytes and the timeouts, in seconds, cover receiving the whole header
+//! and the whole body, so that a slow client cannot hold a connection.
+//!
+//! A listener with `tls` offers HTTP/2 and HTTP/1.1 through ALPN; plain
+//! listeners accept HTTP/2 with prior knowledge or an `h2c` upgrade. A
+//! `strict_http10` listener speaks HTTP/1.0 and 0.9 only, as RFC 1945
+//! defines them, and answers anything newer with `505 HTTP Version Not
+//! Supported`. A
+//! `strict_parsing` listener refuses HTTP/1.x requests that are only
+//! tolerated elsewhere (see `request::Parsing`), which leaves nothing for
+//! a proxy in front of it to read differently.
+use core::fmt;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use regex::Regex;
+use toml;
+
+use super::access_log::AccessLog;
+use super::cache::Cache;
+use super::fastcgi::{Address, Backend};
+use super::proxy::Upstream;
+use super::request::{is_token, Parsing, RequestLimits};
+use super::root::{Root, Symlinks};
+use super::upstream::{HashKey, HealthCheck, KeepAlive, Pool, Strategy};
+use super::websocket::{Broadcast, Echo, Service};
+use super::sse::{Hub, Source};
+use super::status::StatusCode;
+use super::tls::Tls;
+
+/// Errors raised while loading the configuration.
+///
+/// Validation errors carry the dotted path of the offending key, e.g.
+/// `server[0].location[2].root`.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    fn invalid<K: Into<String>, M: Into<String>>(key: K, message: M) -> Self {
+        ConfigError::Invalid {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+/// # Raw configuration
+/// The file as written by the user, before validation.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    listen: Vec<RawListen>,
+    limits: Option<RawRequestLimits>,
+    #[serde(default)]
+    upstream: BTreeMap<String, RawUpstream>,
+    #[serde(default)]
+    cache: BTreeMap<String, RawCache>,
+    forward_proxy: Option<RawForwardProxy>,
+    #[serde(default)]
+    server: Vec<RawServer>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawListen {
+    addr: String,
+    tls: Option<RawTls>,
+    #[serde(default)]
+    strict_http10: bool,
+    #[serde(default)]
+    strict_parsing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRequestLimits {
+    max_request_line: Option<usize>,
+    max_headers: Option<usize>,
+    max_header_size: Option<usize>,
+    max_body: Option<usize>,
+    header_timeout: Option<u64>,
+    body_timeout: Option<u64>,
+    send_timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTls {
+    cert: String,
+    key: String,
+    http2: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawForwardProxy {
+    listen: Vec<String>,
+    allow: Vec<String>,
+    timeout: Option<u64>,
+    access_log: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawUpstream {
+    servers: Vec<String>,
+    strategy: Option<String>,
+    hash_key: Option<String>,
+    max_fails: Option<usize>,
+    fail_timeout: Option<u64>,
+    keepalive: Option<RawKeepAlive>,
+    health_check: Option<RawHealthCheck>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawKeepAlive {
+    max_idle: Option<usize>,
+    idle_timeout: Option<u64>,
+    max_lifetime: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHealthCheck {
+    path: String,
+    interval: Option<u64>,
+    timeout: Option<u64>,
+    fails: Option<usize>,
+    passes: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCache {
+    path: String,
+    max_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServer {
+    #[serde(default)]
+    server_name: Vec<String>,
+    #[serde(default)]
+    default: bool,
+    root: Option<String>,
+    access_log: Option<String>,
+    #[serde(default)]
+    location: Vec<RawLocation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLocation {
+    exact: Option<String>,
+    prefix: Option<String>,
+    regex: Option<String>,
+    #[serde(default = "default_handler")]
+    handler: String,
+
+    root: Option<String>,
+    symlinks: Option<String>,
+    dotfiles: Option<bool>,
+    index: Option<Vec<String>>,
+    upstream: Option<String>,
+    pass: Option<String>,
+    timeout: Option<u64>,
+    cache: Option<String>,
+    service: Option<String>,
+    max_message: Option<usize>,
+    heartbeat: Option<u64>,
+    history: Option<usize>,
+    to: Option<String>,
+    status: Option<u16>,
+
+    auth: Option<RawAuth>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    limits: Option<RawLimits>,
+}
+
+fn default_handler() -> String {
+    "static".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAuth {
+    realm: Option<String>,
+    users: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLimits {
+    max_body: Option<usize>,
+}
+
+/// # Validated configuration
+#[derive(Debug)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// The certificates of the listeners that speak TLS.
+    pub tls: BTreeMap<SocketAddr, Tls>,
+    /// The listeners that only accept requests RFC 1945 allows.
+    pub strict_http10: Vec<SocketAddr>,
+    /// The listeners whose HTTP/1.x requests are parsed strictly.
+    pub strict_parsing: Vec<SocketAddr>,
+    /// What clients may send on any listener.
+    pub limits: RequestLimits,
+    pub upstreams: BTreeMap<String, Arc<Pool>>,
+    pub caches: BTreeMap<String, Arc<Cache>>,
+    pub forward_proxy: Option<ForwardProxy>,
+    /// What locations hold on to between requests, kept on reload.
+    pub services: Services,
+    pub servers: Vec<Server>,
+}
+
+/// The state of locations that outlives a reload: WebSocket broadcasts and
+/// SSE hubs with their clients, by server and matcher, and the pools of
+/// the upstreams locations name by URL.
+#[derive(Debug, Default)]
+pub struct Services {
+    pub broadcasts: BTreeMap<String, Arc<Broadcast>>,
+    pub hubs: BTreeMap<String, Arc<Hub>>,
+    pub pools: BTreeMap<String, Arc<Pool>>,
+}
+
+/// A forward proxy for clients configured to use it on one of its `listen`
+/// addresses: absolute-form `http://` requests are forwarded and `CONNECT`
+/// opens a TCP tunnel, in both cases only to a destination in `allow`.
+#[derive(Debug)]
+pub struct ForwardProxy {
+    pub listen: Vec<SocketAddr>,
+    pub allow: Vec<Allow>,
+    pub timeout: Duration,
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+impl ForwardProxy {
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.allow.iter().any(|a| a.is_match(host, port))
+    }
+}
+
+/// An allowed destination, written `host` or `host:port`. The host may be
+/// `*.example.com`, matching any subdomain, or `*`, matching any host;
+/// without a port only 80 and 443 are allowed.
+#[derive(Debug)]
+pub struct Allow {
+    host: String,
+    port: Option<u16>,
+}
+
+impl Allow {
+    pub fn is_match(&self, host: &str, port: u16) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let host_match = if self.host == "*" {
+            true
+        } else if self.host.starts_with("*.") {
+            host.ends_with(&self.host[1..])
+        } else {
+            host == self.host
+        };
+        host_match && match self.port {
+            Some(p) => p == port,
+            None => port == 80 || port == 443,
+        }
+    }
+}
+
+impl ::std::str::FromStr for Allow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as ::std::str::FromStr>::Err> {
+        let (host, port) = match s.rfind(':') {
+            Some(i) if !s.ends_with(']') => (&s[..i], Some(s[i + 1..].parse::<u16>()
+                .map_err(|_| format!("`{}` has an invalid port", s))?)),
+            _ => (s, None),
+        };
+        if host != "*" && !(host.starts_with('[') && host.ends_with(']')) {
+            validate_server_name(host)?;
+        }
+        Ok(Allow {
+            // a fully qualified name is the same host
+            host: host.trim_end_matches('.').to_lowercase(),
+            port,
+        })
+    }
+}
+
+/// A virtual host, selected by the `Host` header of the request.
+///
+/// `server_name` holds exact names (`example.com`) and wildcard names
+/// (`*.example.com`, matching any subdomain), all in lower case.
+#[derive(Debug)]
+pub struct Server {
+    pub server_name: Vec<String>,
+    pub default: bool,
+    pub locations: Vec<Location>,
+    pub access_log: Option<AccessLog>,
+}
+
+#[derive(Debug)]
+pub enum Matcher {
+    /// `exact = "/path"`
+    Exact(String),
+    /// `prefix = "/path"`
+    Prefix(String),
+    /// `regex = "^/path"`
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn is_match(&self, path: &str) -> bool {
+        match self {
+            Matcher::Exact(p) => path == p,
+            Matcher::Prefix(p) => path.starts_with(p.as_str()),
+            Matcher::Regex(r) => r.is_match(path),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Matcher::Exact(p) => write!(f, "= {}", p),
+            Matcher::Prefix(p) => f.write_str(p),
+            Matcher::Regex(r) => write!(f, "~ {}", r),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Handler {
+    /// Serve files below `root`, trying `index` for directories.
+    Static {
+        root: Root,
+        index: Vec<String>,
+    },
+    /// Like `Static`, but list directories without an index file.
+    Autoindex {
+        root: Root,
+        index: Vec<String>,
+    },
+    /// Forward the request to a peer of `pool`, waiting at most `timeout`
+    /// for it to connect or respond, and keep responses in `cache`.
+    Proxy {
+        pool: Arc<Pool>,
+        timeout: Duration,
+        cache: Option<Arc<Cache>>,
+    },
+    /// Answer with a redirect to `to`.
+    Redirect {
+        to: String,
+        status: StatusCode,
+    },
+    /// Execute CGI scripts below `root`, killing them if they take longer
+    /// than `timeout` to start answering or go silent for as long.
+    Cgi {
+        root: Root,
+        timeout: Duration,
+    },
+    /// Pass requests for scripts below `root` to the FastCGI application
+    /// behind `backend`, trying `index` for directories.
+    FastCgi {
+        backend: Arc<Backend>,
+        root: Root,
+        index: Vec<String>,
+        timeout: Duration,
+    },
+    /// Accept WebSocket connections for `service`, closing them on
+    /// messages larger than `max_message` bytes.
+    WebSocket {
+        service: Arc<dyn Service>,
+        max_message: usize,
+    },
+    /// Stream Server-Sent Events from `source`, with a heartbeat comment
+    /// every `heartbeat`.
+    Sse {
+        source: Arc<dyn Source>,
+        heartbeat: Duration,
+    },
+    /// Report the state of the upstream pools.
+    Status {
+        pools: Vec<Arc<Pool>>,
+    },
+    /// Remove entries from `cache`.
+    Purge {
+        cache: Arc<Cache>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Auth {
+    pub realm: String,
+    pub users: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub max_body: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Location {
+    pub matcher: Matcher,
+    pub handler: Handler,
+    pub auth: Option<Auth>,
+    pub headers: Vec<(String, String)>,
+    pub limits: Limits,
+}
+
+impl Config {
+    /// Read, parse and validate the configuration file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        read(path.as_ref())?.parse()
+    }
+
+    /// Like `load`, but keep the upstream pools and caches of `current`
+    /// whose settings did not change, with their connections, peer state
+    /// and entries. A cache whose directory did not change is kept as
+    /// well and resized, and the broadcasts and hubs of locations still
+    /// there keep their clients.
+    pub fn reload<P: AsRef<Path>>(path: P, current: &Config) -> Result<Self, ConfigError> {
+        let raw: RawConfig = toml::from_str(&read(path.as_ref())?).map_err(ConfigError::Parse)?;
+        validate(raw, Some(current))
+    }
+
+    /// How HTTP/1.x requests received on `local` are parsed.
+    pub fn parsing(&self, local: SocketAddr) -> Parsing {
+        if self.strict_parsing.contains(&local) {
+            Parsing::Strict
+        } else {
+            Parsing::Lenient
+        }
+    }
+
+    /// Every address to listen on, for servers and the forward proxy.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.listen.clone();
+        if let Some(proxy) = &self.forward_proxy {
+            addrs.extend(&proxy.listen);
+        }
+        addrs
+    }
+}
+
+impl Default for Config {
+    /// Serve the working directory on `127.0.0.1:80`.
+    fn default() -> Self {
+        Config {
+            listen: vec!["127.0.0.1:80".parse().unwrap()],
+            tls: BTreeMap::new(),
+            strict_http10: Vec::new(),
+            strict_parsing: Vec::new(),
+            limits: RequestLimits::default(),
+            upstreams: BTreeMap::new(),
+            caches: BTreeMap::new(),
+            forward_proxy: None,
+            services: Services::default(),
+            servers: vec![Server {
+                server_name: Vec::new(),
+                default: true,
+                access_log: None,
+                locations: vec![Location {
+                    matcher: Matcher::Prefix("/".to_string()),
+                    handler: Handler::Autoindex {
+                        root: Root::new(".", Symlinks::default(), false),
+                        index: vec!["index.html".to_string()],
+                    },
+                    auth: None,
+                    headers: Vec::new(),
+                    limits: Limits::default(),
+                }],
+            }],
+        }
+    }
+}
+
+impl ::std::str::FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, <Self as ::std::str::FromStr>::Err> {
+        let raw: RawConfig = toml::from_str(s).map_err(ConfigError::Parse)?;
+        validate(raw, None)
+    }
+}
+
+fn read(path: &Path) -> Result<String, ConfigError> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    Ok(content)
+}
+
+/// Validate `raw`, reusing what it shares with the `current` configuration.
+fn validate(raw: RawConfig, current: Option<&Config>) -> Result<Config, ConfigError> {
+    if raw.listen.is_empty() && raw.forward_proxy.is_none() {
+        return Err(ConfigError::invalid("listen", "at least one listener is required"));
+    }
+    let mut listen = Vec::new();
+    let mut tls = BTreeMap::new();
+    let mut strict_http10 = Vec::new();
+    let mut strict_parsing = Vec::new();
+    for (i, l) in raw.listen.iter().enumerate() {
+        let key = format!("listen[{}].addr", i);
+        let addr = l.addr.parse::<SocketAddr>()
+            .map_err(|e| ConfigError::invalid(key.clone(), format!("`{}` is not a socket address: {}", l.addr, e)))?;
+        if listen.contains(&addr) {
+            return Err(ConfigError::invalid(key, format!("`{}` is already declared", addr)));
+        }
+        if l.strict_http10 {
+            if l.tls.is_some() {
+                return Err(ConfigError::invalid(format!("listen[{}].strict_http10", i), "cannot be combined with tls"));
+            }
+            strict_http10.push(addr);
+        }
+        if l.strict_parsing {
+            strict_parsing.push(addr);
+        }
+        if let Some(t) = &l.tls {
+            let certificate = Tls::load(Path::new(&t.cert), Path::new(&t.key), t.http2.unwrap_or(true))
+                .map_err(|m| ConfigError::invalid(format!("listen[{}].tls", i), m))?;
+            tls.insert(addr, certificate);
+        }
+        listen.push(addr);
+    }
+    let limits = validate_limits(raw.limits)?;
+
+    let mut upstreams = BTreeMap::new();
+    for (name, u) in raw.upstream {
+        let previous = current.and_then(|c| c.upstreams.get(&name));
+        let pool = validate_upstream(&format!("upstream.{}", name), name.clone(), u, previous)?;
+        upstreams.insert(name, pool);
+    }
+
+    let mut caches = BTreeMap::new();
+    for (name, c) in raw.cache {
+        let key = format!("cache.{}", name);
+        let max_size = match c.max_size {
+            Some(0) => return Err(ConfigError::invalid(format!("{}.max_size", key), "must be at least 1 byte")),
+            Some(max_size) => max_size,
+            None => 100 * 1024 * 1024,
+        };
+        let cache = match current.and_then(|current| current.caches.get(&name)) {
+            // opening the directory again would index it twice
+            Some(cache) if cache.dir() == Path::new(&c.path) => {
+                cache.resize(max_size);
+                cache.clone()
+            }
+            _ => Cache::open(name.clone(), &c.path, max_size)
+                .map_err(|e| ConfigError::invalid(format!("{}.path", key), format!("`{}`: {}", c.path, e)))?,
+        };
+        caches.insert(name, cache);
+    }
+
+    let forward_proxy = match raw.forward_proxy {
+        Some(p) => Some(validate_forward_proxy(p, &listen)?),
+        None => None,
+    };
+
+    if raw.server.is_empty() && !listen.is_empty() {
+        return Err(ConfigError::invalid("server", "at least one server is required"));
+    }
+    let mut servers = Vec::new();
+    let mut services = Services::default();
+    let mut has_default = false;
+    let mut names: Vec<(String, usize)> = Vec::new();
+    for (i, s) in raw.server.into_iter().enumerate() {
+        let key = format!("server[{}]", i);
+        if s.default {
+            if has_default {
+                return Err(ConfigError::invalid(format!("{}.default", key), "only one server may be the default"));
+            }
+            has_default = true;
+        }
+        let mut server_name = Vec::new();
+        for (j, name) in s.server_name.iter().enumerate() {
+            let name_key = format!("{}.server_name[{}]", key, j);
+            validate_server_name(name)
+                .map_err(|m| ConfigError::invalid(name_key.clone(), m))?;
+            let name = name.to_lowercase();
+            if let Some(&(_, other)) = names.iter().find(|(n, _)| *n == name) {
+                return Err(ConfigError::invalid(name_key, format!("`{}` is already used by server[{}]", name, other)));
+            }
+            names.push((name.clone(), i));
+            server_name.push(name);
+        }
+
+        if let Some(root) = &s.root {
+            validate_dir(&format!("{}.root", key), root.clone())?;
+        }
+        let mut locations = Vec::new();
+        for (j, l) in s.location.into_iter().enumerate() {
+            let shared = Shared {
+                server: server_id(i, &server_name),
+                current: current.map(|c| &c.services),
+                services: &mut services,
+            };
+            locations.push(validate_location(&format!("{}.location[{}]", key, j), l, s.root.as_ref(), &upstreams, &caches, shared)?);
+        }
+        // a server with a root but no locations serves its root
+        if locations.is_empty() {
+            if let Some(root) = &s.root {
+                locations.push(Location {
+                    matcher: Matcher::Prefix("/".to_string()),
+                    handler: Handler::Static {
+                        root: Root::new(root.as_str(), Symlinks::default(), false),
+                        index: vec!["index.html".to_string()],
+                    },
+                    auth: None,
+                    headers: Vec::new(),
+                    limits: Limits::default(),
+                });
+            }
+        }
+
+        let access_log = match s.access_log {
+            Some(path) => Some(AccessLog::open(&path)
+                .map_err(|e| ConfigError::invalid(format!("{}.access_log", key), format!("`{}`: {}", path, e)))?),
+            None => None,
+        };
+
+        servers.push(Server {
+            server_name,
+            default: s.default,
+            locations,
+            access_log,
+        });
+    }
+    if !has_default && !servers.is_empty() {
+        servers[0].default = true;
+    }
+
+    Ok(Config {
+        listen,
+        tls,
+        strict_http10,
+        strict_parsing,
+        limits,
+        upstreams,
+        caches,
+        forward_proxy,
+        services,
+        servers,
+    })
+}
+
+/// What identifies a server across reloads: its names, or its place for
+/// one without.
+fn server_id(server: usize, server_name: &[String]) -> String {
+    match server_name {
+        [] => format!("server[{}]", server),
+        names => names.join(" "),
+    }
+}
+
+/// Fill in the `[limits]` the file leaves out with the defaults; none of
+/// them may be 0.
+fn validate_limits(raw: Option<RawRequestLimits>) -> Result<RequestLimits, ConfigError> {
+    let mut limits = RequestLimits::default();
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return Ok(limits),
+    };
+    let sizes = [
+        ("max_request_line", raw.max_request_line, &mut limits.max_request_line),
+        ("max_headers", raw.max_headers, &mut limits.max_headers),
+        ("max_header_size", raw.max_header_size, &mut limits.max_header_size),
+        ("max_body", raw.max_body, &mut limits.max_body),
+    ];
+    for (name, value, limit) in sizes {
+        match value {
+            Some(0) => return Err(ConfigError::invalid(format!("limits.{}", name), "must be at least 1")),
+            Some(value) => *limit = value,
+            None => {}
+        }
+    }
+    let timeouts = [
+        ("header_timeout", raw.header_timeout, &mut limits.header_timeout),
+        ("body_timeout", raw.body_timeout, &mut limits.body_timeout),
+        ("send_timeout", raw.send_timeout, &mut limits.send_timeout),
+    ];
+    for (name, value, limit) in timeouts {
+        match value {
+            Some(0) => return Err(ConfigError::invalid(format!("limits.{}", name), "must be at least 1 second")),
+            Some(value) => *limit = Duration::from_secs(value),
+            None => {}
+        }
+    }
+    Ok(limits)
+}
+
+fn validate_forward_proxy(p: RawForwardProxy, server_listen: &[SocketAddr]) -> Result<ForwardProxy, ConfigError> {
+    if p.listen.is_empty() {
+        return Err(ConfigError::invalid("forward_proxy.listen", "at least one listener is required"));
+    }
+    let mut listen = Vec::new();
+    for (i, addr) in p.listen.iter().enumerate() {
+        let key = format!("forward_proxy.listen[{}]", i);
+        let parsed = addr.parse::<SocketAddr>()
+            .map_err(|e| ConfigError::invalid(key.clone(), format!("`{}` is not a socket address: {}", addr, e)))?;
+        if listen.contains(&parsed) || server_listen.contains(&parsed) {
+            return Err(ConfigError::invalid(key, format!("`{}` is already declared", parsed)));
+        }
+        listen.push(parsed);
+    }
+    let mut allow = Vec::new();
+    for (i, a) in p.allow.iter().enumerate() {
+        allow.push(a.parse::<Allow>()
+            .map_err(|m| ConfigError::invalid(format!("forward_proxy.allow[{}]", i), m))?);
+    }
+    let access_log = match p.access_log {
+        Some(path) => Some(Arc::new(AccessLog::open(&path)
+            .map_err(|e| ConfigError::invalid("forward_proxy.access_log", format!("`{}`: {}", path, e)))?)),
+        None => None,
+    };
+    Ok(ForwardProxy {
+        listen,
+        allow,
+        timeout: Duration::from_secs(p.timeout.unwrap_or(60)),
+        access_log,
+    })
+}
+
+fn validate_server_name(name: &str) -> Result<(), String> {
+    let host = name.strip_prefix("*.").unwrap_or(name);
+    if host.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if host.contains('*') {
+        return Err(format!("`{}`: a wildcard is only allowed as the leading label, e.g. `*.example.com`", name));
+    }
+    if !host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+        return Err(format!("`{}` is not a valid host name", name));
+    }
+    // only the root label, after a trailing dot, may be empty
+    if host.strip_suffix('.').unwrap_or(host).split('.').any(|label| label.is_empty()) {
+        return Err(format!("`{}` has an empty label", name));
+    }
+    Ok(())
+}
+
+/// Validate the upstream at `key`, keeping the `previous` pool of that
+/// name if its settings did not change.
+fn validate_upstream(key: &str, name: String, u: RawUpstream, previous: Option<&Arc<Pool>>) -> Result<Arc<Pool>, ConfigError> {
+    if u.servers.is_empty() {
+        return Err(ConfigError::invalid(format!("{}.servers", key), "at least one server is required"));
+    }
+    let mut servers = Vec::new();
+    for (i, server) in u.servers.iter().enumerate() {
+        servers.push(server.parse::<Upstream>()
+            .map_err(|e| ConfigError::invalid(format!("{}.servers[{}]", key, i), e))?);
+    }
+
+    let strategy = match u.strategy.as_deref() {
+        None | Some("round_robin") => Strategy::RoundRobin,
+        Some("least_conn") => Strategy::LeastConnections,
+        Some("hash") => Strategy::Hash(match u.hash_key.as_deref() {
+            None | Some("ip") => HashKey::ClientIp,
+            Some(k) if k.starts_with("header:") && k.len() > "header:".len() =>
+                HashKey::Header(k["header:".len()..].to_string()),
+            Some(k) => return Err(ConfigError::invalid(
+                format!("{}.hash_key", key),
+                format!("unknown hash key `{}`, expected `ip` or `header:<name>`", k))),
+        }),
+        Some(other) => return Err(ConfigError::invalid(
+            format!("{}.strategy", key),
+            format!("unknown strategy `{}`, expected one of `round_robin`, `least_conn`, `hash`", other))),
+    };
+    if u.hash_key.is_some() {
+        if let Strategy::Hash(_) = strategy {} else {
+            return Err(ConfigError::invalid(format!("{}.hash_key", key), "only valid with strategy `hash`"));
+        }
+    }
+
+    let health_check = match u.health_check {
+        Some(h) => {
+            let path = validate_path(&format!("{}.health_check.path", key), h.path)?;
+            let positive = |name: &str, value: Option<u64>, default: u64| match value {
+                Some(0) => Err(ConfigError::invalid(format!("{}.health_check.{}", key, name), "must be at least 1")),
+                Some(v) => Ok(v),
+                None => Ok(default),
+            };
+            Some(HealthCheck {
+                path,
+                interval: Duration::from_secs(positive("interval", h.interval, 5)?),
+                timeout: Duration::from_secs(positive("timeout", h.timeout, 2)?),
+                fails: positive("fails", h.fails.map(|v| v as u64), 2)? as usize,
+                passes: positive("passes", h.passes.map(|v| v as u64), 2)? as usize,
+            })
+        }
+        None => None,
+    };
+
+    let keep_alive = match u.keepalive {
+        Some(k) => {
+            let default = KeepAlive::default();
+            KeepAlive {
+                max_idle: k.max_idle.unwrap_or(default.max_idle),
+                idle_timeout: k.idle_timeout.map_or(default.idle_timeout, Duration::from_secs),
+                max_lifetime: k.max_lifetime.map_or(default.max_lifetime, Duration::from_secs),
+            }
+        }
+        None => KeepAlive::default(),
+    };
+
+    let max_fails = u.max_fails.unwrap_or(1);
+    let fail_timeout = Duration::from_secs(u.fail_timeout.unwrap_or(10));
+    if let Some(pool) = previous {
+        if pool.peers.iter().map(|p| &p.upstream).eq(servers.iter()) && pool.strategy == strategy
+            && pool.max_fails == max_fails && pool.fail_timeout == fail_timeout
+            && pool.keep_alive == keep_alive && pool.health_check == health_check {
+            return Ok(pool.clone());
+        }
+    }
+    Ok(Pool::new(name, servers, strategy, max_fails, fail_timeout, keep_alive, health_check))
+}
+
+/// Where a location finds the services it may share: with the same
+/// location of the `current` configuration, and with the other locations.
+struct Shared<'a> {
+    server: String,
+    current: Option<&'a Services>,
+    services: &'a mut Services,
+}
+
+impl<'a> Shared<'a> {
+    fn id(&self, matcher: &Matcher) -> String {
+        format!("{} {}", self.server, matcher)
+    }
+
+    fn broadcast(&mut self, matcher: &Matcher) -> Arc<Broadcast> {
+        let id = self.id(matcher);
+        let broadcast = self.services.broadcasts.get(&id)
+            .or_else(|| self.current.and_then(|c| c.broadcasts.get(&id)))
+            .cloned()
+            .unwrap_or_default();
+        self.services.broadcasts.insert(id, broadcast.clone());
+        broadcast
+    }
+
+    fn hub(&mut self, matcher: &Matcher, history: usize) -> Arc<Hub> {
+        let id = self.id(matcher);
+        let hub = match self.services.hubs.get(&id).or_else(|| self.current.and_then(|c| c.hubs.get(&id))) {
+            Some(hub) => {
+                hub.set_history(history);
+                hub.clone()
+            }
+            None => Arc::new(Hub::new(history)),
+        };
+        self.services.hubs.insert(id, hub.clone());
+        hub
+    }
+
+    /// The pool of the lone server at `url`, which has no settings of its
+    /// own, so any location naming it can share it.
+    fn pool(&mut self, url: String, upstream: Upstream) -> Arc<Pool> {
+        let pool = match self.services.pools.get(&url).or_else(|| self.current.and_then(|c| c.pools.get(&url))) {
+            Some(pool) => pool.clone(),
+            // a lone server is never ejected
+            None => Pool::new(url.clone(), vec![upstream], Strategy::RoundRobin, 0, Duration::from_secs(0),
+                              KeepAlive::default(), None),
+        };
+        self.services.pools.insert(url, pool.clone());
+        pool
+    }
+}
+
+/// Validate the location at `key`; `root` is the server's document root,
+/// used by handlers that need one when the location does not set it.
+fn validate_location(key: &str, l: RawLocation, root: Option<&String>,
+                     upstreams: &BTreeMap<String, Arc<Pool>>,
+                     caches: &BTreeMap<String, Arc<Cache>>,
+                     mut shared: Shared) -> Result<Location, ConfigError> {
+    let matcher = match (l.exact, l.prefix, l.regex) {
+        (Some(p), None, None) => Matcher::Exact(validate_path(&format!("{}.exact", key), p)?),
+        (None, Some(p), None) => Matcher::Prefix(validate_path(&format!("{}.prefix", key), p)?),
+        (None, None, Some(r)) => Matcher::Regex(Regex::new(&r)
+            .map_err(|e| ConfigError::invalid(format!("{}.regex", key), e.to_string()))?),
+        _ => return Err(ConfigError::invalid(key, "exactly one of `exact`, `prefix` or `regex` is required")),
+    };
+
+    let allowed: &[&str] = match l.handler.as_str() {
+        "static" | "autoindex" => &["root", "symlinks", "dotfiles", "index"],
+        "proxy" => &["upstream", "timeout", "cache"],
+        "redirect" => &["to", "status"],
+        "cgi" => &["root", "symlinks", "dotfiles", "timeout"],
+        "fastcgi" => &["pass", "root", "symlinks", "dotfiles", "index", "timeout"],
+        "websocket" => &["service", "max_message"],
+        "sse" => &["heartbeat", "history"],
+        "status" => &[],
+        "purge" => &["cache"],
+        other => return Err(ConfigError::invalid(
+            format!("{}.handler", key),
+            format!("unknown handler `{}`, expected one of `static`, `autoindex`, `proxy`, `redirect`, `cgi`, `fastcgi`, `websocket`, `sse`, `status`, `purge`", other))),
+    };
+    let given = [
+        ("root", l.root.is_some()),
+        ("symlinks", l.symlinks.is_some()),
+        ("dotfiles", l.dotfiles.is_some()),
+        ("index", l.index.is_some()),
+        ("upstream", l.upstream.is_some()),
+        ("pass", l.pass.is_some()),
+        ("timeout", l.timeout.is_some()),
+        ("cache", l.cache.is_some()),
+        ("service", l.service.is_some()),
+        ("max_message", l.max_message.is_some()),
+        ("heartbeat", l.heartbeat.is_some()),
+        ("history", l.history.is_some()),
+        ("to", l.to.is_some()),
+        ("status", l.status.is_some()),
+    ];
+    for &(name, present) in given.iter() {
+        if present && !allowed.contains(&name) {
+            return Err(ConfigError::invalid(
+                format!("{}.{}", key, name),
+                format!("not valid for handler `{}`", l.handler)));
+        }
+    }
+
+    let cache = match &l.cache {
+        Some(name) => Some(caches.get(name).cloned()
+            .ok_or_else(|| ConfigError::invalid(format!("{}.cache", key), format!("no cache named `{}`", name)))?),
+        None => None,
+    };
+    let handler_name = l.handler;
+    let root = match handler_name.as_str() {
+        "static" | "autoindex" | "cgi" | "fastcgi" => l.root.or_else(|| root.cloned()),
+        _ => l.root,
+    };
+    if l.timeout == Some(0) {
+        return Err(ConfigError::invalid(format!("{}.timeout", key), "must be at least 1 second"));
+    }
+    let required = |name: &str, value: Option<String>| {
+        value.ok_or_else(|| ConfigError::invalid(
+            format!("{}.{}", key, name),
+            format!("required for handler `{}`", handler_name)))
+    };
+    let symlinks = match l.symlinks.as_deref() {
+        None | Some("within_root") => Symlinks::WithinRoot,
+        Some("deny") => Symlinks::Deny,
+        Some("follow") => Symlinks::Follow,
+        Some(other) => return Err(ConfigError::invalid(
+            format!("{}.symlinks", key),
+            format!("unknown policy `{}`, expected one of `deny`, `within_root`, `follow`", other))),
+    };
+    let dotfiles = l.dotfiles.unwrap_or(false);
+    let document_root = |root: Option<String>| -> Result<Root, ConfigError> {
+        let dir = validate_dir(&format!("{}.root", key), required("root", root)?)?;
+        Ok(Root::new(dir, symlinks, dotfiles))
+    };
+    let handler = match handler_name.as_str() {
+        "static" => Handler::Static {
+            root: document_root(root)?,
+            index: l.index.unwrap_or_else(|| vec!["index.html".to_string()]),
+        },
+        "autoindex" => Handler::Autoindex {
+            root: document_root(root)?,
+            index: l.index.unwrap_or_else(|| vec!["index.html".to_string()]),
+        },
+        "proxy" => Handler::Proxy {
+            pool: {
+                let upstream = required("upstream", l.upstream)?;
+                if upstream.starts_with("http://") {
+                    let u = upstream.parse::<Upstream>()
+                        .map_err(|e| ConfigError::invalid(format!("{}.upstream", key), e))?;
+                    shared.pool(upstream, u)
+                } else {
+                    upstreams.get(&upstream).cloned()
+                        .ok_or_else(|| ConfigError::invalid(
+                            format!("{}.upstream", key),
+                            format!("no upstream named `{}`", upstream)))?
+                }
+            },
+            timeout: Duration::from_secs(l.timeout.unwrap_or(60)),
+            cache,
+        },
+        "redirect" => Handler::Redirect {
+            to: required("to", l.to)?,
+            status: match l.status.unwrap_or(302) {
+                301 => StatusCode::MovedPermanently,
+                302 => StatusCode::MovedTemporarily,
+                303 => StatusCode::SeeOther,
+                307 => StatusCode::TemporaryRedirect,
+                308 => StatusCode::PermanentRedirect,
+                s => return Err(ConfigError::invalid(
+                    format!("{}.status", key),
+                    format!("{} is not a redirect status, expected one of 301, 302, 303, 307, 308", s))),
+            },
+        },
+        "cgi" => Handler::Cgi {
+            root: document_root(root)?,
+            timeout: Duration::from_secs(l.timeout.unwrap_or(30)),
+        },
+        "fastcgi" => Handler::FastCgi {
+            backend: Backend::new(required("pass", l.pass)?.parse::<Address>()
+                .map_err(|e| ConfigError::invalid(format!("{}.pass", key), e))?),
+            root: document_root(root)?,
+            index: l.index.unwrap_or_else(|| vec!["index.php".to_string()]),
+            timeout: Duration::from_secs(l.timeout.unwrap_or(60)),
+        },
+        "websocket" => Handler::WebSocket {
+            service: match required("service", l.service)?.as_str() {
+                "echo" => Arc::new(Echo),
+                "broadcast" => shared.broadcast(&matcher),
+                other => return Err(ConfigError::invalid(
+                    format!("{}.service", key),
+                    format!("unknown service `{}`, expected `echo` or `broadcast`", other))),
+            },
+            max_message: match l.max_message {
+                Some(0) => return Err(ConfigError::invalid(format!("{}.max_message", key), "must be at least 1 byte")),
+                Some(max) => max,
+                None => 1024 * 1024,
+            },
+        },
+        "sse" => Handler::Sse {
+            source: shared.hub(&matcher, l.history.unwrap_or(100)),
+            heartbeat: match l.heartbeat {
+                Some(0) => return Err(ConfigError::invalid(format!("{}.heartbeat", key), "must be at least 1 second")),
+                Some(h) => Duration::from_secs(h),
+                None => Duration::from_secs(15),
+            },
+        },
+        "status" => Handler::Status {
+            pools: upstreams.values().cloned().collect(),
+        },
+        "purge" => Handler::Purge {
+            cache: cache.ok_or_else(|| ConfigError::invalid(format!("{}.cache", key), "required for handler `purge`"))?,
+        },
+        _ => unreachable!(),
+    };
+
+    let auth = match l.auth {
+        Some(a) => {
+            if a.users.is_empty() {
+                return Err(ConfigError::invalid(format!("{}.auth.users", key), "at least one user is required"));
+            }
+            if let Some(user) = a.users.keys().find(|u| u.contains(':')) {
+                return Err(ConfigError::invalid(
+                    format!("{}.auth.users.{}", key, user),
+                    "user names must not contain `:`"));
+            }
+            Some(Auth {
+                realm: a.realm.unwrap_or_else(|| "Restricted".to_string()),
+                users: a.users,
+            })
+        }
+        None => None,
+    };
+
+    let mut headers = Vec::new();
+    for (name, value) in l.headers {
+        if !is_token(&name) {
+            return Err(ConfigError::invalid(
+                format!("{}.headers.{}", key, name),
+                "not a valid header name"));
+        }
+        if value.contains('\r') || value.contains('\n') {
+            return Err(ConfigError::invalid(
+                format!("{}.headers.{}", key, name),
+                "header values must not contain line breaks"));
+        }
+        headers.push((name, value));
+    }
+
+    let limits = match l.limits {
+        Some(limits) => Limits {
+            max_body: limits.max_body,
+        },
+        None => Limits::default(),
+    };
+
+    Ok(Location {
+        matcher,
+        handler,
+        auth,
+        headers,
+        limits,
+    })
+}
+
+fn validate_path(key: &str, path: String) -> Result<String, ConfigError> {
+    if !path.starts_with('/') {
+        return Err(ConfigError::invalid(key, format!("`{}` must start with `/`", path)));
+    }
+    Ok(path)
+}
+
+fn validate_dir(key: &str, dir: String) -> Result<PathBuf, ConfigError> {
+    let path = PathBuf::from(&dir);
+    if !path.is_dir() {
+        return Err(ConfigError::invalid(key, format!("`{}` is not a directory", dir)));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn config(cache: &Path, max_size: u64, servers: &str) -> RawConfig {
+        toml::from_str(&format!(r#"
+            [[listen]]
+            addr = "127.0.0.1:8080"
+
+            [upstream.backend]
+            servers = [{}]
+
+            [cache.main]
+            path = "{}"
+            max_size = {}
+
+            [[server]]
+              [[server.location]]
+              prefix = "/"
+              handler = "proxy"
+              upstream = "backend"
+              cache = "main"
+        "#, servers, cache.display(), max_size)).unwrap()
+    }
+
+    #[test]
+    fn reload_keeps_unchanged_pools_and_caches() {
+        let dir = env::temp_dir().join(format!("config-{}", process::id()));
+        let other = dir.join("other");
+        let current = validate(config(&dir, 1024, r#""http://127.0.0.1:9000""#), None).unwrap();
+
+        let same = validate(config(&dir, 2048, r#""http://127.0.0.1:9000""#), Some(&current)).unwrap();
+        assert!(Arc::ptr_eq(&same.upstreams["backend"], &current.upstreams["backend"]));
+        assert!(Arc::ptr_eq(&same.caches["main"], &current.caches["main"]));
+
+        let changed = validate(config(&other, 1024, r#""http://127.0.0.1:9000", "http://127.0.0.1:9001""#), Some(&current)).unwrap();
+        assert!(!Arc::ptr_eq(&changed.upstreams["backend"], &current.upstreams["backend"]));
+        assert_eq!(changed.upstreams["backend"].peers.len(), 2);
+        assert!(!Arc::ptr_eq(&changed.caches["main"], &current.caches["main"]));
+        assert_eq!(changed.caches["main"].dir(), other.as_path());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn services(ws: &str, history: usize) -> RawConfig {
+        toml::from_str(&format!(r#"
+            [[listen]]
+            addr = "127.0.0.1:8080"
+
+            [[server]]
+            server_name = ["example.com"]
+              [[server.location]]
+              exact = "{}"
+              handler = "websocket"
+              service = "broadcast"
+
+              [[server.location]]
+              exact = "/events"
+              handler = "sse"
+              history = {}
+
+              [[server.location]]
+              prefix = "/api/"
+              handler = "proxy"
+              upstream = "http://127.0.0.1:9000"
+
+            [[server]]
+              [[server.location]]
+              prefix = "/"
+              handler = "proxy"
+              upstream = "http://127.0.0.1:9000"
+        "#, ws, history)).unwrap()
+    }
+
+    #[test]
+    fn reload_keeps_the_services_of_locations() {
+        let current = validate(services("/ws", 10), None).unwrap();
+        // both locations naming the server share its pool
+        assert!(Arc::ptr_eq(&current.services.pools["http://127.0.0.1:9000"],
+                            match &current.servers[1].locations[0].handler {
+                                Handler::Proxy { pool, .. } => pool,
+                                _ => panic!("not a proxy"),
+                            }));
+
+        let same = validate(services("/ws", 20), Some(&current)).unwrap();
+        assert!(Arc::ptr_eq(&same.services.broadcasts["example.com = /ws"], &current.services.broadcasts["example.com = /ws"]));
+        assert!(Arc::ptr_eq(&same.services.hubs["example.com = /events"], &current.services.hubs["example.com = /events"]));
+        assert!(Arc::ptr_eq(&same.services.pools["http://127.0.0.1:9000"], &current.services.pools["http://127.0.0.1:9000"]));
+
+        // another location, another broadcast
+        let moved = validate(services("/socket", 10), Some(&current)).unwrap();
+        assert!(!Arc::ptr_eq(&moved.services.broadcasts["example.com = /socket"], &current.services.broadcasts["example.com = /ws"]));
+        assert!(!moved.services.broadcasts.contains_key("example.com = /ws"));
+    }
+
+    #[test]
+    fn allowed_destinations() {
+        let allows = |allow: &str, host: &str, port: u16| allow.parse::<Allow>().unwrap().is_match(host, port);
+        // without a port, the defaults of http and https only
+        assert!(allows("example.com", "example.com", 80));
+        assert!(allows("example.com", "example.com", 443));
+        assert!(!allows("example.com", "example.com", 8080));
+        assert!(allows("example.com:8080", "example.com", 8080));
+        assert!(!allows("example.com:8080", "example.com", 80));
+
+        assert!(allows("Example.COM", "example.com.", 443));
+        assert!(allows("example.com.", "EXAMPLE.com", 443));
+        assert!(!allows("example.com", "www.example.com", 443));
+
+        assert!(allows("*.example.com", "www.example.com", 443));
+        assert!(allows("*.example.com", "a.b.example.com.", 80));
+        assert!(!allows("*.example.com", "example.com", 443));
+        assert!(!allows("*.example.com", "badexample.com", 443));
+        assert!(!allows("*.example.com", "www.example.com", 22));
+
+        assert!(allows("*", "anything.test", 80));
+        assert!(!allows("*", "anything.test", 25));
+        assert!(allows("*:25", "anything.test", 25));
+        assert!(allows("[::1]:8080", "[::1]", 8080));
+        assert!(allows("127.0.0.1", "127.0.0.1", 443));
+
+        for invalid in &["", "*.", ".", "a..com", "a.*.com", "example.com:http", "example.com:70000", "exa mple.com"] {
+            assert!(invalid.parse::<Allow>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    fn redirect(status: u16) -> Result<Config, ConfigError> {
+        format!(r#"
+            [[listen]]
+            addr = "127.0.0.1:8080"
+
+            [[server]]
+              [[server.location]]
+              exact = "/old"
+              handler = "redirect"
+              to = "/new"
+              status = {}
+        "#, status).parse()
+    }
+
+    #[test]
+    fn redirect_statuses() {
+        for status in &[301, 302, 303, 307, 308] {
+            match &redirect(*status).unwrap().servers[0].locations[0].handler {
+                Handler::Redirect { status: s, .. } => assert_eq!(s.to_u16(), *status),
+                _ => panic!("not a redirect"),
+            }
+        }
+        for status in &[200, 300, 304, 305, 306] {
+            let e = redirect(*status).unwrap_err().to_string();
+            assert!(e.contains("expected one of 301, 302, 303, 307, 308"), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Errors raised while parsing HTTP messages
+use core::fmt;
+use std::io;
+
+use super::status::StatusCode;
+
+/// What is wrong with a message, and in which element of it.
+///
+/// Each kind maps to the status code the client is answered with, except
+/// for I/O errors: the connection is then closed without a response.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection failed or closed before the message was complete.
+    Io(io::Error),
+    /// `element` (e.g. `Request-Line`) does not follow the grammar.
+    Invalid {
+        element: &'static str,
+        message: String,
+    },
+    /// An HTTP-Version we do not speak.
+    UnsupportedVersion(String),
+    /// A Request-Line longer than the limit, in bytes.
+    RequestLineTooLong(usize),
+    /// Too many header fields, or too many bytes of them.
+    HeaderTooLarge(String),
+    /// A Content-Length over the limit, in bytes.
+    BodyTooLarge(usize),
+    /// The client took too long to send the message.
+    Timeout,
+    /// A `Transfer-Encoding` we cannot decode.
+    UnsupportedTransferCoding(String),
+}
+
+impl ParseError {
+    pub fn invalid<M: Into<String>>(element: &'static str, message: M) -> Self {
+        ParseError::Invalid {
+            element,
+            message: message.into(),
+        }
+    }
+
+    /// The status code to answer the client with, if any.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            ParseError::Io(_) => None,
+            ParseError::Invalid { .. } => Some(StatusCode::BadRequest),
+            ParseError::UnsupportedVersion(_) => Some(StatusCode::HTTPVersionNotSupported),
+            ParseError::RequestLineTooLong(_) => Some(StatusCode::URITooLong),
+            // [RFC6585, Section 5]
+            ParseError::HeaderTooLarge(_) => Some(StatusCode::RequestHeaderFieldsTooLarge),
+            ParseError::BodyTooLarge(_) => Some(StatusCode::RequestEntityTooLarge),
+            ParseError::Timeout => Some(StatusCode::RequestTimeout),
+            // [RFC7230, Section 3.3.1]
+            ParseError::UnsupportedTransferCoding(_) => Some(StatusCode::NotImplemented),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    /// A socket read timeout is the client's fault, and answered.
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::Timeout,
+            _ => ParseError::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "{}", e),
+            ParseError::Invalid { element, message } => write!(f, "{}: {}", element, message),
+            ParseError::UnsupportedVersion(version) => write!(f, "HTTP-Version: `{}` is not supported", version),
+            ParseError::RequestLineTooLong(max) => write!(f, "Request-Line: longer than {} bytes", max),
+            ParseError::HeaderTooLarge(message) => write!(f, "HTTP-header: {}", message),
+            ParseError::BodyTooLarge(max) => write!(f, "Entity-Body: larger than {} bytes", max),
+            ParseError::Timeout => f.write_str("timed out"),
+            ParseError::UnsupportedTransferCoding(coding) => write!(f, "Transfer-Encoding: `{}` is not supported", coding),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::header::Header;
+    use super::super::uri::URI;
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        for (e, code) in [
+            (ParseError::invalid("Request-Line", "empty line"), StatusCode::BadRequest),
+            (ParseError::UnsupportedVersion("HTTP/2.0".to_string()), StatusCode::HTTPVersionNotSupported),
+            (ParseError::UnsupportedTransferCoding("gzip".to_string()), StatusCode::NotImplemented),
+            (ParseError::RequestLineTooLong(8192), StatusCode::URITooLong),
+            (ParseError::HeaderTooLarge("more than 100 fields".to_string()), StatusCode::RequestHeaderFieldsTooLarge),
+            (ParseError::BodyTooLarge(1024), StatusCode::RequestEntityTooLarge),
+            (ParseError::Timeout, StatusCode::RequestTimeout),
+        ] {
+            assert_eq!(e.status_code(), Some(code), "{}", e);
+        }
+        assert_eq!(ParseError::Io(io::ErrorKind::ConnectionReset.into()).status_code(), None);
+
+        // as the parsers raise them
+        assert_eq!("/a%zz".parse::<URI>().unwrap_err().status_code(), Some(StatusCode::BadRequest));
+        let mut header = Header::new();
+        header.append("Content-Length", "five");
+        assert_eq!(header.content_length().unwrap_err().status_code(), Some(StatusCode::BadRequest));
+    }
+
+    #[test]
+    fn io_errors() {
+        for kind in &[io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut] {
+            assert!(matches!(ParseError::from(io::Error::from(*kind)), ParseError::Timeout));
+        }
+        assert!(matches!(ParseError::from(io::Error::from(io::ErrorKind::UnexpectedEof)), ParseError::Io(_)));
+    }
+
+    #[test]
+    fn messages_name_the_element() {
+        assert_eq!(ParseError::invalid("Request-Line", "empty line").to_string(), "Request-Line: empty line");
+        assert_eq!(ParseError::UnsupportedVersion("HTTP/3.0".to_string()).to_string(), "HTTP-Version: `HTTP/3.0` is not supported");
+        assert_eq!(ParseError::UnsupportedTransferCoding("gzip".to_string()).to_string(),
+                   "Transfer-Encoding: `gzip` is not supported");
+    }
+}
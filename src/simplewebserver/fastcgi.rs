@@ -0,0 +1,806 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! FastCGI responder client [[FastCGI Specification](https://fastcgi-archives.github.io/FastCGI_Specification.html)]
+//!
+//! Requests are sent to the application as records over TCP or a Unix
+//! socket. Connections are kept open with `FCGI_KEEP_CONN`; when the
+//! application reports `FCGI_MPXS_CONNS=1` all requests share a single
+//! connection, otherwise each connection carries one request at a time
+//! and goes back to an idle pool when it is done.
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use super::cgi;
+use super::proxy::gateway_error;
+use super::request::Request;
+use super::response::Response;
+use super::root::Root;
+use super::status::StatusCode;
+
+const VERSION_1: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+const UNKNOWN_TYPE: u8 = 11;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+
+const REQUEST_COMPLETE: u8 = 0;
+const CANT_MPX_CONN: u8 = 1;
+const OVERLOADED: u8 = 2;
+
+/// Largest content of a single record.
+const MAX_CONTENT: usize = 65535;
+
+/// Idle connections kept per backend.
+const MAX_IDLE: usize = 8;
+
+/// How long to wait for the answer to `FCGI_GET_VALUES`; applications
+/// that ignore management records are assumed not to multiplex.
+const GET_VALUES_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where the application listens: `host:port` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("`{}` has no socket path", s));
+            }
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        match s.rfind(':') {
+            Some(i) if i > 0 && s[i + 1..].parse::<u16>().is_ok() => Ok(Address::Tcp(s.to_string())),
+            _ => Err(format!("`{}` is neither `host:port` nor `unix:/path`", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(address: &Address, timeout: Duration) -> io::Result<Self> {
+        let stream = match address {
+            Address::Tcp(addr) => {
+                let mut last = io::Error::new(io::ErrorKind::NotFound, "no address found");
+                let mut connected = None;
+                for addr in addr.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, timeout) {
+                        Ok(stream) => {
+                            connected = Some(stream);
+                            break;
+                        }
+                        Err(e) => last = e,
+                    }
+                }
+                let stream = connected.ok_or(last)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            Address::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        };
+        match &stream {
+            Stream::Tcp(s) => s.set_write_timeout(Some(timeout))?,
+            Stream::Unix(s) => s.set_write_timeout(Some(timeout))?,
+        }
+        Ok(stream)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    kind: u8,
+    id: u16,
+    content: Vec<u8>,
+}
+
+/// Append `content` to `buf` as records of type `kind`, split as needed.
+/// Empty content makes a single empty record, closing a stream.
+fn push_records(buf: &mut Vec<u8>, kind: u8, id: u16, content: &[u8]) {
+    let mut chunks: Vec<&[u8]> = content.chunks(MAX_CONTENT).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    for chunk in chunks {
+        // pad records to a multiple of 8 bytes, as the specification recommends
+        let padding = (8 - chunk.len() % 8) % 8;
+        buf.extend_from_slice(&[VERSION_1, kind, (id >> 8) as u8, id as u8,
+            (chunk.len() >> 8) as u8, chunk.len() as u8, padding as u8, 0]);
+        buf.extend_from_slice(chunk);
+        buf.extend_from_slice(&[0; 8][..padding]);
+    }
+}
+
+fn read_record<R: Read>(r: &mut R) -> io::Result<Record> {
+    let mut header = [0; 8];
+    r.read_exact(&mut header)?;
+    if header[0] != VERSION_1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported version {}", header[0])));
+    }
+    let length = (header[4] as usize) << 8 | header[5] as usize;
+    let mut content = vec![0; length + header[6] as usize];
+    r.read_exact(&mut content)?;
+    content.truncate(length);
+    Ok(Record {
+        kind: header[1],
+        id: (header[2] as u16) << 8 | header[3] as u16,
+        content,
+    })
+}
+
+/// Encode name-value pairs: lengths below 128 take one byte, longer ones
+/// four with the high bit set.
+fn encode_pairs<'a, I: IntoIterator<Item=(&'a str, &'a str)>>(pairs: I) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in pairs {
+        for len in &[name.len(), value.len()] {
+            if *len < 128 {
+                buf.push(*len as u8);
+            } else {
+                buf.extend_from_slice(&[(len >> 24) as u8 | 0x80, (len >> 16) as u8, (len >> 8) as u8, *len as u8]);
+            }
+        }
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf
+}
+
+fn decode_pairs(mut buf: &[u8]) -> Vec<(String, String)> {
+    fn length(buf: &mut &[u8]) -> Option<usize> {
+        let first = *buf.first()?;
+        if first < 128 {
+            *buf = &buf[1..];
+            Some(first as usize)
+        } else if buf.len() >= 4 {
+            let len = ((first & 0x7f) as usize) << 24 | (buf[1] as usize) << 16 | (buf[2] as usize) << 8 | buf[3] as usize;
+            *buf = &buf[4..];
+            Some(len)
+        } else {
+            None
+        }
+    }
+    let mut pairs = Vec::new();
+    while let (Some(n), Some(v)) = (length(&mut buf), length(&mut buf)) {
+        if buf.len() < n + v {
+            break;
+        }
+        pairs.push((String::from_utf8_lossy(&buf[..n]).into_owned(),
+                    String::from_utf8_lossy(&buf[n..n + v]).into_owned()));
+        buf = &buf[n + v..];
+    }
+    pairs
+}
+
+type Pending = Arc<Mutex<HashMap<u16, Sender<Record>>>>;
+
+/// A connection to the application. A reader thread hands the records it
+/// receives to the request they belong to.
+#[derive(Debug)]
+struct Conn {
+    stream: Mutex<Stream>,
+    pending: Pending,
+    broken: Arc<AtomicBool>,
+    next_id: Mutex<u16>,
+}
+
+impl Conn {
+    fn open(address: &Address, timeout: Duration) -> io::Result<Arc<Conn>> {
+        let stream = Stream::connect(address, timeout)?;
+        let mut reader = stream.try_clone()?;
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let broken = Arc::new(AtomicBool::new(false));
+        {
+            let pending = pending.clone();
+            let broken = broken.clone();
+            let address = address.clone();
+            thread::spawn(move || {
+                loop {
+                    match read_record(&mut reader) {
+                        Ok(record) => {
+                            let pending = pending.lock().unwrap();
+                            if let Some(tx) = pending.get(&record.id) {
+                                let _ = tx.send(record);
+                            }
+                        }
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::UnexpectedEof {
+                                debug!("fastcgi {}: {}", address, e);
+                            }
+                            break;
+                        }
+                    }
+                }
+                broken.store(true, Ordering::SeqCst);
+                // wakes every request still waiting on this connection
+                pending.lock().unwrap().clear();
+            });
+        }
+        Ok(Arc::new(Conn {
+            stream: Mutex::new(stream),
+            pending,
+            broken,
+            next_id: Mutex::new(0),
+        }))
+    }
+
+    fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::SeqCst)
+    }
+
+    /// Take a request id not in use on this connection.
+    fn register(&self) -> (u16, Receiver<Record>) {
+        let (tx, rx) = channel();
+        let mut pending = self.pending.lock().unwrap();
+        let mut next_id = self.next_id.lock().unwrap();
+        loop {
+            *next_id = next_id.wrapping_add(1);
+            if *next_id != 0 && !pending.contains_key(&*next_id) {
+                break;
+            }
+        }
+        pending.insert(*next_id, tx);
+        (*next_id, rx)
+    }
+
+    fn unregister(&self, id: u16) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        let result = self.stream.lock().unwrap().write_all(buf);
+        if result.is_err() {
+            self.broken.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Ask whether the application multiplexes connections.
+    fn multiplexes(&self) -> bool {
+        let (tx, rx) = channel();
+        self.pending.lock().unwrap().insert(0, tx);
+        let mut buf = Vec::new();
+        push_records(&mut buf, GET_VALUES, 0, &encode_pairs(vec![("FCGI_MPXS_CONNS", "")]));
+        let mpxs = self.send(&buf).is_ok() && match rx.recv_timeout(GET_VALUES_TIMEOUT) {
+            Ok(ref r) if r.kind == GET_VALUES_RESULT => decode_pairs(&r.content).iter()
+                .any(|(k, v)| k == "FCGI_MPXS_CONNS" && v == "1"),
+            _ => false,
+        };
+        self.unregister(0);
+        mpxs
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.stream.lock().unwrap().shutdown();
+    }
+}
+
+/// A FastCGI application and the connections open to it.
+#[derive(Debug)]
+pub struct Backend {
+    address: Address,
+    /// Whether the application multiplexes connections, once asked.
+    mpxs: Mutex<Option<bool>>,
+    /// The connection shared by all requests, if it does.
+    shared: Mutex<Option<Arc<Conn>>>,
+    /// Idle connections, if it does not.
+    idle: Mutex<Vec<Arc<Conn>>>,
+}
+
+impl Backend {
+    pub fn new(address: Address) -> Arc<Self> {
+        Arc::new(Backend {
+            address,
+            mpxs: Mutex::new(None),
+            shared: Mutex::new(None),
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// A connection for a new request, and whether it was reused.
+    fn conn(&self, timeout: Duration) -> io::Result<(Arc<Conn>, bool)> {
+        let mut mpxs = self.mpxs.lock().unwrap();
+        let mpxs = match *mpxs {
+            Some(mpxs) => mpxs,
+            None => {
+                let conn = Conn::open(&self.address, timeout)?;
+                let multiplexes = conn.multiplexes();
+                info!("fastcgi {}: FCGI_MPXS_CONNS={}", self.address, multiplexes as u8);
+                *mpxs = Some(multiplexes);
+                if multiplexes {
+                    *self.shared.lock().unwrap() = Some(conn.clone());
+                }
+                return Ok((conn, false));
+            }
+        };
+        if mpxs {
+            let mut shared = self.shared.lock().unwrap();
+            match &*shared {
+                Some(conn) if !conn.is_broken() => return Ok((conn.clone(), true)),
+                _ => {}
+            }
+            let conn = Conn::open(&self.address, timeout)?;
+            *shared = Some(conn.clone());
+            Ok((conn, false))
+        } else {
+            let mut idle = self.idle.lock().unwrap();
+            while let Some(conn) = idle.pop() {
+                if !conn.is_broken() {
+                    return Ok((conn, true));
+                }
+            }
+            drop(idle);
+            Ok((Conn::open(&self.address, timeout)?, false))
+        }
+    }
+
+    fn release(&self, conn: Arc<Conn>) {
+        if *self.mpxs.lock().unwrap() == Some(true) || conn.is_broken() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
+    }
+
+    /// Begin a responder request with `params` and the body `stdin`. A
+    /// reused connection that turns out to be closed is retried once on a
+    /// new one.
+    fn begin(backend: &Arc<Backend>, params: &[(String, String)], stdin: &[u8], timeout: Duration) -> io::Result<Exchange> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (conn, reused) = backend.conn(timeout)?;
+            let (id, rx) = conn.register();
+            let mut buf = Vec::new();
+            push_records(&mut buf, BEGIN_REQUEST, id, &[(RESPONDER >> 8) as u8, RESPONDER as u8, KEEP_CONN, 0, 0, 0, 0, 0]);
+            push_records(&mut buf, PARAMS, id, &encode_pairs(params.iter().map(|(k, v)| (k.as_str(), v.as_str()))));
+            push_records(&mut buf, PARAMS, id, &[]);
+            if !stdin.is_empty() {
+                push_records(&mut buf, STDIN, id, stdin);
+            }
+            push_records(&mut buf, STDIN, id, &[]);
+            match conn.send(&buf) {
+                Ok(()) => return Ok(Exchange {
+                    backend: backend.clone(),
+                    conn: Some(conn),
+                    id,
+                    rx,
+                    timeout,
+                    done: false,
+                }),
+                Err(ref e) if reused && attempts == 1 => {
+                    debug!("fastcgi {}: reused connection failed: {}", backend.address, e);
+                    conn.unregister(id);
+                }
+                Err(e) => {
+                    conn.unregister(id);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// A request in progress, read as the application's stdout.
+struct Exchange {
+    backend: Arc<Backend>,
+    conn: Option<Arc<Conn>>,
+    id: u16,
+    rx: Receiver<Record>,
+    timeout: Duration,
+    done: bool,
+}
+
+impl Exchange {
+    /// The next piece of stdout, or `None` once the request has ended.
+    /// Stderr output is logged.
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.done {
+            let record = match self.rx.recv_timeout(self.timeout) {
+                Ok(record) => record,
+                Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(io::ErrorKind::TimedOut, "application timed out")),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by the application")),
+            };
+            match record.kind {
+                STDOUT if !record.content.is_empty() => return Ok(Some(record.content)),
+                STDERR if !record.content.is_empty() => {
+                    for line in String::from_utf8_lossy(&record.content).lines() {
+                        warn!("fastcgi {}: {}", self.backend.address, line);
+                    }
+                }
+                END_REQUEST => {
+                    self.done = true;
+                    let conn = self.conn.take().unwrap();
+                    conn.unregister(self.id);
+                    let status = record.content.get(4).cloned().unwrap_or(REQUEST_COMPLETE);
+                    match status {
+                        REQUEST_COMPLETE => self.backend.release(conn),
+                        CANT_MPX_CONN => {
+                            *self.backend.mpxs.lock().unwrap() = Some(false);
+                            return Err(io::Error::other("application cannot multiplex"));
+                        }
+                        OVERLOADED => return Err(io::Error::other("application overloaded")),
+                        _ => return Err(io::Error::other("unknown role")),
+                    }
+                }
+                UNKNOWN_TYPE => return Err(io::Error::new(io::ErrorKind::InvalidData, "record type unknown to the application")),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            conn.unregister(self.id);
+            if *self.backend.mpxs.lock().unwrap() == Some(true) {
+                let mut buf = Vec::new();
+                push_records(&mut buf, ABORT_REQUEST, self.id, &[]);
+                let _ = conn.send(&buf);
+            }
+            // otherwise the connection is not released and closes with the last reference
+        }
+    }
+}
+
+/// The rest of the application's stdout, streamed to the client.
+struct Body {
+    exchange: Exchange,
+    buf: io::Cursor<Vec<u8>>,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.buf.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.exchange.next()? {
+                Some(data) => self.buf = io::Cursor::new(data),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Pass `req` to the application behind `backend` for the script it
+/// addresses below `root`, trying `index` for directories: 404 if there is
+/// no such script, 502 or 504 if the application fails or times out.
+pub fn run(backend: &Arc<Backend>, root: &Root, index: &[String], timeout: Duration, req: &Request) -> Response {
+    let mut path = req.path().to_string();
+    if let Err(status_code) = root.resolve(&path) {
+        return Response::error(status_code);
+    }
+    if path.ends_with('/') {
+        match index.iter().find(|i| root.resolve(&format!("{}{}", path, i)).is_ok_and(|f| f.is_file())) {
+            Some(i) => path.push_str(i),
+            None => return Response::error(StatusCode::NotFound),
+        }
+    }
+    let root = root.path();
+    let (script, script_name, path_info) = match cgi::find_script(root, &path) {
+        Some(found) => found,
+        None => return Response::error(StatusCode::NotFound),
+    };
+    let mut params = cgi::environment(root, &script, &script_name, &path_info, req);
+    params.retain(|(k, _)| k != "PATH");
+    // PHP refuses to run without it when built with force-cgi-redirect
+    params.push(("REDIRECT_STATUS".to_string(), "200".to_string()));
+
+    let stdin = req.body().unwrap_or(&[]);
+    let mut exchange = match Backend::begin(backend, &params, stdin, timeout) {
+        Ok(exchange) => exchange,
+        Err(e) => {
+            warn!("fastcgi {}: {}", backend.address, e);
+            return gateway_error(&e);
+        }
+    };
+
+    // read up to the end of the header block
+    let mut head = Vec::new();
+    while head.len() <= cgi::MAX_HEAD
+        && !(head.windows(4).any(|w| w == b"\r\n\r\n") || head.windows(2).any(|w| w == b"\n\n")) {
+        match exchange.next() {
+            Ok(Some(data)) => head.extend(data),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("fastcgi {}: {}", backend.address, e);
+                return gateway_error(&e);
+            }
+        }
+    }
+    let mut buf = io::Cursor::new(head);
+    let mut res = match cgi::read_head(&mut buf) {
+        Ok(res) => res,
+        Err(message) => {
+            warn!("fastcgi {}: {}", backend.address, message);
+            return Response::error(StatusCode::BadGateway);
+        }
+    };
+    res.set_stream(Body {
+        exchange,
+        buf,
+    });
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// A stand-in application answering `FCGI_MPXS_CONNS` with `mpxs`, or
+    /// not at all if `None`, and every request with `hello`, counting the
+    /// connections it accepts.
+    struct Application {
+        address: Address,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl Application {
+        fn start(mpxs: Option<bool>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = Address::Tcp(listener.local_addr().unwrap().to_string());
+            let connections = Arc::new(AtomicUsize::new(0));
+            let application = Application {
+                address,
+                connections: connections.clone(),
+            };
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn(move || answer(stream, mpxs));
+                }
+            });
+            application
+        }
+
+        fn connections(&self) -> usize {
+            self.connections.load(Ordering::SeqCst)
+        }
+    }
+
+    fn answer(mut stream: TcpStream, mpxs: Option<bool>) {
+        while let Ok(record) = read_record(&mut stream) {
+            let mut buf = Vec::new();
+            match record.kind {
+                GET_VALUES => match mpxs {
+                    Some(mpxs) => push_records(&mut buf, GET_VALUES_RESULT, 0,
+                                               &encode_pairs(vec![("FCGI_MPXS_CONNS", if mpxs { "1" } else { "0" })])),
+                    None => continue,
+                },
+                STDIN if record.content.is_empty() => {
+                    push_records(&mut buf, STDERR, record.id, b"a warning");
+                    push_records(&mut buf, STDOUT, record.id, b"Content-Type: text/plain\r\n\r\nhello");
+                    push_records(&mut buf, STDOUT, record.id, &[]);
+                    push_records(&mut buf, END_REQUEST, record.id, &[0, 0, 0, 0, REQUEST_COMPLETE, 0, 0, 0]);
+                }
+                _ => continue,
+            }
+            if stream.write_all(&buf).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn stdout(exchange: &mut Exchange) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(data) = exchange.next().unwrap() {
+            out.extend(data);
+        }
+        out
+    }
+
+    fn begin(backend: &Arc<Backend>) -> Exchange {
+        let params = vec![("SCRIPT_NAME".to_string(), "/index.php".to_string())];
+        Backend::begin(backend, &params, b"body", Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn records_are_split_and_padded() {
+        let content: Vec<u8> = (0..MAX_CONTENT + 10).map(|i| i as u8).collect();
+        let mut buf = Vec::new();
+        push_records(&mut buf, STDIN, 0x0102, &content);
+        push_records(&mut buf, STDIN, 0x0102, &[]);
+        assert_eq!(buf.len() % 8, 0);
+        // 65535 bytes take 1 byte of padding, 10 bytes take 6, the empty record none
+        assert_eq!(buf.len(), 8 + MAX_CONTENT + 1 + 8 + 10 + 6 + 8);
+
+        let mut reader = io::Cursor::new(buf);
+        let mut read = Vec::new();
+        let mut lengths = Vec::new();
+        for _ in 0..3 {
+            let record = read_record(&mut reader).unwrap();
+            assert_eq!((record.kind, record.id), (STDIN, 0x0102));
+            lengths.push(record.content.len());
+            read.extend(record.content);
+        }
+        assert_eq!(lengths, vec![MAX_CONTENT, 10, 0]);
+        assert_eq!(read, content);
+        assert!(read_record(&mut reader).is_err());
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let mut buf = Vec::new();
+        push_records(&mut buf, STDOUT, 1, b"x");
+        buf[0] = 2;
+        assert_eq!(read_record(&mut io::Cursor::new(buf)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pairs_round_trip() {
+        let long = "v".repeat(300);
+        let pairs = vec![("SCRIPT_NAME", "/index.php"), ("EMPTY", ""), ("LONG", long.as_str())];
+        let buf = encode_pairs(pairs.clone());
+        // "LONG" is 1 byte of length, its value 4 with the high bit set
+        assert_eq!(&buf[buf.len() - 300 - 4 - 5..][..5], &[4, 0x80, 0, 1, 44]);
+        let decoded = decode_pairs(&buf);
+        let decoded: Vec<(&str, &str)> = decoded.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(decoded, pairs);
+        // a truncated pair is dropped
+        assert_eq!(decode_pairs(&buf[..buf.len() - 1]).len(), 2);
+    }
+
+    #[test]
+    fn multiplexing_application_shares_a_connection() {
+        let application = Application::start(Some(true));
+        let backend = Backend::new(application.address.clone());
+        let mut first = begin(&backend);
+        let mut second = begin(&backend);
+        assert_eq!(*backend.mpxs.lock().unwrap(), Some(true));
+        assert_ne!(first.id, second.id);
+        assert_eq!(stdout(&mut second), b"Content-Type: text/plain\r\n\r\nhello");
+        assert_eq!(stdout(&mut first), b"Content-Type: text/plain\r\n\r\nhello");
+        assert_eq!(application.connections(), 1);
+        assert!(backend.idle.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_applications_get_a_connection_per_request() {
+        let application = Application::start(Some(false));
+        let backend = Backend::new(application.address.clone());
+        let mut first = begin(&backend);
+        let mut second = begin(&backend);
+        assert_eq!(*backend.mpxs.lock().unwrap(), Some(false));
+        assert!(backend.shared.lock().unwrap().is_none());
+        stdout(&mut first);
+        stdout(&mut second);
+        assert_eq!(application.connections(), 2);
+        assert_eq!(backend.idle.lock().unwrap().len(), 2);
+
+        // finished connections are kept and reused
+        stdout(&mut begin(&backend));
+        assert_eq!(application.connections(), 2);
+        assert_eq!(backend.idle.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn silent_application_is_assumed_not_to_multiplex() {
+        let application = Application::start(None);
+        let backend = Backend::new(application.address.clone());
+        assert_eq!(stdout(&mut begin(&backend)), b"Content-Type: text/plain\r\n\r\nhello");
+        assert_eq!(*backend.mpxs.lock().unwrap(), Some(false));
+        assert_eq!(backend.idle.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!("127.0.0.1:9000".parse(), Ok(Address::Tcp("127.0.0.1:9000".to_string())));
+        assert_eq!("unix:/run/php.sock".parse(), Ok(Address::Unix(PathBuf::from("/run/php.sock"))));
+        assert!("unix:".parse::<Address>().is_err());
+        assert!("localhost".parse::<Address>().is_err());
+        assert!(":9000".parse::<Address>().is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Forward proxy
+//!
+//! Clients configured to use the proxy send absolute-form requests
+//! (`GET http://example.com/ HTTP/1.1`), which are forwarded like a reverse
+//! proxied request, or `CONNECT example.com:443`, which opens an opaque
+//! TCP tunnel [[RFC7231, Section 4.3.6](https://tools.ietf.org/html/rfc7231#section-4.3.6)].
+use std::io;
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use super::config::ForwardProxy;
+use super::proxy;
+use super::proxy::Upstream;
+use super::request::{Method, Request, RequestLimits};
+use super::response::Response;
+use super::send;
+use super::status::StatusCode;
+use super::uri::Form;
+use super::upstream::{KeepAlive, Pool, Strategy};
+
+/// Answer `req`; `received` holds what the client sent after it, passed
+/// on through a tunnel.
+pub fn handle(proxy: &ForwardProxy, limits: &RequestLimits, req: Request, received: Vec<u8>, stream: &TcpStream) {
+    if *req.method() == Method::CONNECT {
+        return tunnel(proxy, req, received, stream);
+    }
+    let res = forward(proxy, limits, &req);
+    let status_code = res.status_code();
+    let sent = send(stream, res, *req.method() == Method::HEAD);
+    if let Some(log) = &proxy.access_log {
+        log.log(req.peer_addr(), &req, status_code, sent);
+    }
+}
+
+/// Forward an absolute-form `http://` request to its origin server.
+fn forward(proxy: &ForwardProxy, limits: &RequestLimits, req: &Request) -> Response {
+    let target = req.target();
+    if target.scheme() != Some("http") {
+        return Response::error(StatusCode::BadRequest);
+    }
+    let (host, port) = match target.host().filter(|host| !host.is_empty()) {
+        Some(host) => (host.to_string(), target.port().unwrap_or(80)),
+        None => return Response::error(StatusCode::BadRequest),
+    };
+    if !proxy.allows(&host, port) {
+        info!("forward proxy: {}:{} is not allowed", host, port);
+        return Response::error(StatusCode::Forbidden);
+    }
+    // a single-use pool: connections to arbitrary origins are not kept
+    let upstream = Upstream {
+        host,
+        port,
+        path: String::new(),
+    };
+    let keep_alive = KeepAlive {
+        max_idle: 0,
+        ..KeepAlive::default()
+    };
+    let pool = Pool::new(format!("{}:{}", upstream.host, upstream.port), vec![upstream], Strategy::RoundRobin, 0,
+                         Duration::from_secs(0), keep_alive, None);
+    proxy::forward(&pool, proxy.timeout, limits, req)
+}
+
+/// Connect to the `host:port` of a `CONNECT` request, answer 200 and relay
+/// bytes both ways on a thread of its own until either side closes,
+/// starting with those already `received` from the client, e.g. a TLS
+/// ClientHello sent without waiting for the answer.
+fn tunnel(proxy: &ForwardProxy, req: Request, received: Vec<u8>, stream: &TcpStream) {
+    let target = req.target();
+    let res = match (target.form(), target.host(), target.port()) {
+        (Form::Authority, Some(host), Some(port)) if port != 0 => if proxy.allows(host, port) {
+            connect(host, port, proxy.timeout).map_err(|e| {
+                warn!("forward proxy: {}:{}: {}", host, port, e);
+                proxy::gateway_error(&e)
+            })
+        } else {
+            info!("forward proxy: {}:{} is not allowed", host, port);
+            Err(Response::error(StatusCode::Forbidden))
+        },
+        _ => Err(Response::error(StatusCode::BadRequest)),
+    };
+    let (client, upstream) = match res.and_then(|upstream| {
+        stream.try_clone()
+            .map(|client| (client, upstream))
+            .map_err(|_| Response::error(StatusCode::InternalServerError))
+    }) {
+        Ok(streams) => streams,
+        Err(res) => {
+            let status_code = res.status_code();
+            let sent = send(stream, res, false);
+            if let Some(log) = &proxy.access_log {
+                log.log(req.peer_addr(), &req, status_code, sent);
+            }
+            return;
+        }
+    };
+
+    // the tunnel follows: no Content-Length [RFC7231, Section 4.3.6]
+    let mut established = Response::new(StatusCode::OK);
+    established.set_stream(io::empty());
+    send(stream, established, false);
+    // either side may stay quiet for as long as it likes
+    if let Err(e) = client.set_read_timeout(None).and_then(|_| client.set_write_timeout(None)) {
+        warn!("{}", e);
+    }
+    let access_log = proxy.access_log.clone();
+    thread::spawn(move || {
+        let (sent, relayed) = match (&upstream).write_all(&received) {
+            Ok(()) => splice(client, upstream),
+            Err(e) => {
+                warn!("forward proxy: {}: {}", req.uri(), e);
+                (0, 0)
+            }
+        };
+        let received = received.len() as u64 + relayed;
+        info!("tunnel to {} closed, {} bytes sent, {} bytes received", req.uri(), sent, received);
+        if let Some(log) = access_log {
+            log.log(req.peer_addr(), &req, StatusCode::OK, sent);
+        }
+    });
+}
+
+fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = (host.trim_start_matches('[').trim_end_matches(']'), port)
+        .to_socket_addrs()?
+        .collect();
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no address found");
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// Copy between the client and the upstream until both directions are
+/// closed, returning the bytes sent to the client and received from it.
+fn splice(client: TcpStream, upstream: TcpStream) -> (u64, u64) {
+    let downstream = match (client.try_clone(), upstream.try_clone()) {
+        (Ok(client), Ok(upstream)) => thread::spawn(move || copy(upstream, client)),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("{}", e);
+            return (0, 0);
+        }
+    };
+    let received = copy(client, upstream);
+    let sent = downstream.join().unwrap_or(0);
+    (sent, received)
+}
+
+/// Copy `from` into `to`, then pass the end of stream on.
+fn copy(mut from: TcpStream, mut to: TcpStream) -> u64 {
+    let copied = io::copy(&mut from, &mut to).unwrap_or(0);
+    let _ = to.shutdown(Shutdown::Write);
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    use super::*;
+    use super::super::common::HTTPVersion;
+    use super::super::header::Header;
+
+    /// A forward proxy allowing `allow`.
+    fn proxy(allow: &[String]) -> ForwardProxy {
+        ForwardProxy {
+            listen: Vec::new(),
+            allow: allow.iter().map(|a| a.parse().unwrap()).collect(),
+            timeout: Duration::from_secs(5),
+            access_log: None,
+        }
+    }
+
+    /// Hand `method uri` to `proxy` over a loopback connection, with
+    /// `received` pipelined after it; the client's end.
+    fn request(proxy: &ForwardProxy, method: Method, uri: &str, received: &[u8]) -> BufReader<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut header = Header::new();
+        header.append("Host", "ignored.example");
+        let req = Request::from_parts(method, uri, HTTPVersion::new(1, 1), header, None, None, None).unwrap();
+        handle(proxy, &RequestLimits::default(), req, received.to_vec(), &server);
+        BufReader::new(client)
+    }
+
+    /// The status code and the header block of the response.
+    fn head(client: &mut BufReader<TcpStream>) -> (u16, String) {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(client.read_line(&mut head).unwrap() > 0, "closed after {:?}", head);
+        }
+        (head[9..12].parse().unwrap(), head)
+    }
+
+    /// An origin answering one request with its request line as the body,
+    /// and sending the whole request head on the channel.
+    fn origin() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") && reader.read_line(&mut head).unwrap() > 0 {}
+            let line = head.lines().next().unwrap().to_string();
+            write!(&stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", line.len(), line).unwrap();
+            tx.send(head).unwrap();
+        });
+        (port, rx)
+    }
+
+    /// An origin sending back what it reads, prefixed with `echo: `.
+    fn echo() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 {
+                    return;
+                }
+                let mut reply = b"echo: ".to_vec();
+                reply.extend_from_slice(&buf[..n]);
+                if stream.write_all(&reply).is_err() {
+                    return;
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn absolute_form_requests_are_forwarded() {
+        let (port, heads) = origin();
+        let proxy = proxy(&[format!("127.0.0.1:{}", port)]);
+        let mut client = request(&proxy, Method::GET, &format!("http://127.0.0.1:{}/path?q=1", port), b"");
+        let (status, head) = head(&mut client);
+        assert_eq!(status, 200, "{}", head);
+        let mut body = String::new();
+        client.read_to_string(&mut body).unwrap();
+        // in origin form, for the host of the target rather than `Host`
+        assert_eq!(body, "GET /path?q=1 HTTP/1.1");
+        assert!(heads.recv().unwrap().contains(&format!("\r\nHost: 127.0.0.1:{}\r\n", port)));
+
+        assert_eq!(self::head(&mut request(&proxy, Method::GET, &format!("https://127.0.0.1:{}/", port), b"")).0, 400);
+        assert_eq!(self::head(&mut request(&proxy, Method::GET, "/relative", b"")).0, 400);
+    }
+
+    #[test]
+    fn destinations_must_be_allowed() {
+        let (port, _) = origin();
+        let proxy = proxy(&["*.example.com".to_string(), "127.0.0.1:1".to_string()]);
+        assert_eq!(head(&mut request(&proxy, Method::GET, &format!("http://127.0.0.1:{}/", port), b"")).0, 403);
+        assert_eq!(head(&mut request(&proxy, Method::CONNECT, &format!("127.0.0.1:{}", port), b"")).0, 403);
+        assert_eq!(head(&mut request(&proxy, Method::CONNECT, "www.example.com:22", b"")).0, 403);
+        assert_eq!(head(&mut request(&proxy, Method::GET, "http://example.com/", b"")).0, 403);
+        // nothing listens on port 1
+        assert_eq!(head(&mut request(&proxy, Method::CONNECT, "127.0.0.1:1", b"")).0, 502);
+    }
+
+    #[test]
+    fn tunnels_relay_pipelined_bytes_first() {
+        let port = echo();
+        let proxy = proxy(&[format!("127.0.0.1:{}", port)]);
+        let mut client = request(&proxy, Method::CONNECT, &format!("127.0.0.1:{}", port), b"hello");
+        let (status, head) = head(&mut client);
+        assert_eq!(status, 200);
+        assert!(!head.to_lowercase().contains("content-length"), "{}", head);
+        let mut echoed = [0; 11];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"echo: hello");
+
+        client.get_mut().write_all(b"world").unwrap();
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"echo: world");
+
+        // a close is passed on both ways
+        client.get_ref().shutdown(Shutdown::Write).unwrap();
+        assert_eq!(client.read(&mut echoed).unwrap(), 0);
+    }
+}
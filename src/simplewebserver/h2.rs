@@ -0,0 +1,1521 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! HTTP/2
+//! [[RFC7540](https://tools.ietf.org/html/rfc7540)]
+//!
+//! A connection is read on a thread of its own, which answers the
+//! connection level frames and collects the requests. Each complete
+//! request is routed and answered on another thread, so that a slow
+//! response does not hold up the rest of the connection. Header blocks are
+//! decoded with HPACK [[RFC7541](https://tools.ietf.org/html/rfc7541)];
+//! ours are made of literals that are never indexed, so there is no
+//! dynamic table of the client's to keep track of.
+//!
+//! Responses that take the connection over, WebSocket and SSE, are
+//! answered with 501: an HTTP/2 stream cannot be handed off, and extended
+//! CONNECT [[RFC8441](https://tools.ietf.org/html/rfc8441)] is not
+//! supported.
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base64;
+use hpack;
+
+use super::common::HTTPVersion;
+use super::config::Config;
+use super::error::ParseError;
+use super::header::Header;
+use super::request::{is_token, Method, Request};
+use super::respond;
+use super::response::Response;
+use super::status::StatusCode;
+
+/// The client connection preface
+/// [[RFC7540, Section 3.5](https://tools.ietf.org/html/rfc7540#section-3.5)]
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types [RFC7540, Section 6]
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings [RFC7540, Section 6.5.2]
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Error codes [RFC7540, Section 7]
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// The largest frame payload we accept, which is also the largest any
+/// peer must accept.
+const MAX_FRAME_SIZE: usize = 16_384;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// The largest header block we buffer before decoding it.
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+/// The size of the HPACK dynamic table, the default SETTINGS_HEADER_TABLE_SIZE
+/// we never raise, which clients may not exceed
+/// [[RFC7541, Section 4.2](https://tools.ietf.org/html/rfc7541#section-4.2)].
+const HEADER_TABLE_SIZE: usize = 4096;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// The connection an HTTP/2 session runs over. One thread receives while
+/// others send.
+pub trait Transport: Send + Sync {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn send(&self, data: &[u8]) -> io::Result<()>;
+
+    fn close(&self);
+
+    /// The socket underneath, e.g. to set its timeouts.
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Transport for TcpStream {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stream: &TcpStream = self;
+        stream.read(buf)
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        let mut stream: &TcpStream = self;
+        stream.write_all(data)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+/// Whether the client on `stream` opened with the connection preface,
+/// i.e. speaks HTTP/2 with prior knowledge
+/// [[RFC7540, Section 3.4](https://tools.ietf.org/html/rfc7540#section-3.4)].
+/// Nothing is consumed; failing to read anything, e.g. before the read
+/// timeout, is an error.
+pub fn has_preface(stream: &TcpStream) -> io::Result<bool> {
+    let mut buf = [0; 24];
+    // the preface may arrive in pieces
+    for _ in 0..100 {
+        let n = stream.peek(&mut buf)?;
+        if n == 0 || buf[..n] != PREFACE[..n] {
+            return Ok(false);
+        }
+        if n == PREFACE.len() {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(false)
+}
+
+/// The settings of an HTTP/1.1 request asking to upgrade to HTTP/2 over
+/// cleartext
+/// [[RFC7540, Section 3.2](https://tools.ietf.org/html/rfc7540#section-3.2)].
+pub fn upgrade_settings(req: &Request) -> Option<Vec<u8>> {
+    if req.version() != HTTPVersion::new(1, 1) {
+        return None;
+    }
+    if !req.header("Upgrade").is_some_and(|u| u.split(',').any(|p| p.trim() == "h2c")) {
+        return None;
+    }
+    let settings = base64::decode_config(req.header("HTTP2-Settings")?, base64::URL_SAFE_NO_PAD).ok()?;
+    if settings.len().is_multiple_of(6) {
+        Some(settings)
+    } else {
+        None
+    }
+}
+
+/// Answer `101 Switching Protocols` to a request returned by
+/// `upgrade_settings`.
+pub fn switching_protocols() -> Response {
+    let mut res = Response::new(StatusCode::SwitchingProtocols);
+    res.set_version(HTTPVersion::new(1, 1));
+    res.set_header("Connection", "Upgrade");
+    res.set_header("Upgrade", "h2c");
+    res
+}
+
+/// Serve an HTTP/2 connection on a new thread, starting with the bytes
+/// already `received` from the transport. After an h2c upgrade, the
+/// request that asked for it is answered as stream 1, with the settings
+/// it carried.
+pub fn start(config: Arc<Config>, transport: Box<dyn Transport>, peer: Option<SocketAddr>, local: Option<SocketAddr>,
+             upgrade: Option<(Request, Vec<u8>)>, received: Vec<u8>) {
+    thread::spawn(move || serve(config, transport, peer, local, upgrade, received));
+}
+
+fn serve(config: Arc<Config>, transport: Box<dyn Transport>, peer: Option<SocketAddr>, local: Option<SocketAddr>,
+         upgrade: Option<(Request, Vec<u8>)>, received: Vec<u8>) {
+    let shared = Arc::new(Shared {
+        transport,
+        writing: Mutex::new(()),
+        flow: Mutex::new(Flow {
+            window: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame: MAX_FRAME_SIZE,
+            closed: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let mut conn = Conn {
+        config,
+        shared: shared.clone(),
+        peer,
+        local,
+        buf: received,
+        decoder: hpack::Decoder::new(),
+        last_stream: 0,
+        receiving: HashMap::new(),
+        continuation: None,
+    };
+    match conn.run(upgrade) {
+        Ok(()) => {
+            shared.wait_idle();
+            let _ = shared.goaway(conn.last_stream, NO_ERROR, "");
+        }
+        Err(Error::Io(e)) => debug!("HTTP/2 connection from {:?}: {}", peer, e),
+        Err(Error::Connection(code, reason)) => {
+            debug!("HTTP/2 connection from {:?}: error {}: {}", peer, code, reason);
+            let _ = shared.goaway(conn.last_stream, code, reason);
+        }
+    }
+    shared.close();
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+fn encode_frame(buf: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    let len = payload.len();
+    buf.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8, kind, flags]);
+    buf.extend_from_slice(&(stream & 0x7fff_ffff).to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3])
+}
+
+enum Error {
+    Io(io::Error),
+    /// A connection error, answered with GOAWAY
+    Connection(u32, &'static str),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// The part of a connection shared with the threads answering requests.
+struct Shared {
+    transport: Box<dyn Transport>,
+    /// Held while a frame, or a header block, is written, so that frames
+    /// are never interleaved.
+    writing: Mutex<()>,
+    flow: Mutex<Flow>,
+    /// Signalled whenever a send window grows or a stream ends.
+    changed: Condvar,
+}
+
+/// Flow control of what we send
+/// [[RFC7540, Section 5.2](https://tools.ietf.org/html/rfc7540#section-5.2)].
+struct Flow {
+    window: i64,
+    /// The send windows of the open streams
+    streams: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame: usize,
+    closed: bool,
+}
+
+impl Shared {
+    fn send(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(9 + payload.len());
+        encode_frame(&mut buf, kind, flags, stream, payload);
+        let _writing = self.writing.lock().unwrap();
+        self.transport.send(&buf)
+    }
+
+    /// Send a header block, split into CONTINUATION frames as needed.
+    fn send_headers(&self, stream: u32, block: &[u8], end_stream: bool) -> io::Result<()> {
+        let max_frame = self.flow.lock().unwrap().max_frame;
+        let mut buf = Vec::with_capacity(block.len() + 9);
+        let mut chunks = block.chunks(max_frame).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        if chunks.peek().is_none() {
+            encode_frame(&mut buf, kind, flags | END_HEADERS, stream, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            encode_frame(&mut buf, kind, flags, stream, chunk);
+            kind = CONTINUATION;
+            flags = 0;
+        }
+        let _writing = self.writing.lock().unwrap();
+        self.transport.send(&buf)
+    }
+
+    fn goaway(&self, last_stream: u32, code: u32, debug: &str) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(8 + debug.len());
+        payload.extend_from_slice(&last_stream.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(debug.as_bytes());
+        self.send(GOAWAY, 0, 0, &payload)
+    }
+
+    fn reset(&self, stream: u32, code: u32) -> io::Result<()> {
+        self.finish(stream);
+        self.send(RST_STREAM, 0, stream, &code.to_be_bytes())
+    }
+
+    fn window_update(&self, stream: u32, increment: usize) -> io::Result<()> {
+        self.send(WINDOW_UPDATE, 0, stream, &(increment as u32).to_be_bytes())
+    }
+
+    /// Wait until up to `want` bytes may be sent on `stream`, and take them
+    /// from the send windows.
+    fn reserve(&self, stream: u32, want: usize) -> io::Result<usize> {
+        let mut flow = self.flow.lock().unwrap();
+        loop {
+            if flow.closed {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"));
+            }
+            let window = match flow.streams.get(&stream) {
+                Some(window) => *window,
+                None => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset")),
+            };
+            let n = cmp::min(want as i64, cmp::min(flow.window, window));
+            if n > 0 {
+                flow.window -= n;
+                *flow.streams.get_mut(&stream).unwrap() -= n;
+                return Ok(n as usize);
+            }
+            flow = self.changed.wait(flow).unwrap();
+        }
+    }
+
+    fn open(&self, stream: u32) {
+        let mut flow = self.flow.lock().unwrap();
+        let window = flow.initial_window;
+        flow.streams.insert(stream, window);
+    }
+
+    fn finish(&self, stream: u32) {
+        self.flow.lock().unwrap().streams.remove(&stream);
+        self.changed.notify_all();
+    }
+
+    fn open_streams(&self) -> usize {
+        self.flow.lock().unwrap().streams.len()
+    }
+
+    fn is_open(&self, stream: u32) -> bool {
+        self.flow.lock().unwrap().streams.contains_key(&stream)
+    }
+
+    /// Wait for the streams still being answered.
+    fn wait_idle(&self) {
+        let mut flow = self.flow.lock().unwrap();
+        while !flow.streams.is_empty() && !flow.closed {
+            flow = self.changed.wait(flow).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.flow.lock().unwrap().closed = true;
+        self.changed.notify_all();
+        self.transport.close();
+    }
+}
+
+/// A request whose header block has been received.
+struct Head {
+    method: String,
+    uri: String,
+    header: Header,
+    content_length: Option<usize>,
+}
+
+/// A stream whose request is still being received.
+struct Incoming {
+    head: Head,
+    body: Vec<u8>,
+}
+
+/// A header block waiting for its CONTINUATION frames.
+struct Continuation {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// The reading side of a connection.
+struct Conn {
+    config: Arc<Config>,
+    shared: Arc<Shared>,
+    peer: Option<SocketAddr>,
+    local: Option<SocketAddr>,
+    buf: Vec<u8>,
+    decoder: hpack::Decoder<'static>,
+    last_stream: u32,
+    receiving: HashMap<u32, Incoming>,
+    continuation: Option<Continuation>,
+}
+
+impl Conn {
+    /// Read frames until the client closes the connection or sends GOAWAY.
+    fn run(&mut self, upgrade: Option<(Request, Vec<u8>)>) -> Result<(), Error> {
+        // our SETTINGS come first
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        settings.extend_from_slice(&SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+        settings.extend_from_slice(&(self.config.limits.max_header_size as u32).to_be_bytes());
+        self.shared.send(SETTINGS, 0, 0, &settings)?;
+
+        let mut upgraded = None;
+        if let Some((req, settings)) = upgrade {
+            self.apply_settings(&settings)?;
+            self.last_stream = 1;
+            self.shared.open(1);
+            upgraded = Some(req);
+        }
+
+        if !self.fill(PREFACE.len())? {
+            return Ok(());
+        }
+        if &self.buf[..PREFACE.len()] != PREFACE {
+            return Err(Error::Connection(PROTOCOL_ERROR, "invalid connection preface"));
+        }
+        self.buf.drain(..PREFACE.len());
+
+        let mut first = true;
+        while let Some(frame) = self.next_frame()? {
+            if first && frame.kind != SETTINGS {
+                return Err(Error::Connection(PROTOCOL_ERROR, "the preface must end with SETTINGS"));
+            }
+            if frame.kind == GOAWAY {
+                if frame.stream != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                break;
+            }
+            self.handle(frame)?;
+            if first {
+                first = false;
+                if let Some(req) = upgraded.take() {
+                    // answered only now, so that the client is not sent
+                    // frames before it has switched to HTTP/2
+                    self.answer(1, Ok(req));
+                }
+            }
+        }
+        // the requests still incomplete will never be answered
+        if upgraded.is_some() {
+            self.shared.finish(1);
+        }
+        for stream in self.receiving.keys() {
+            self.shared.finish(*stream);
+        }
+        Ok(())
+    }
+
+    /// Buffer at least `n` bytes, returning false at the end of the
+    /// connection.
+    fn fill(&mut self, n: usize) -> Result<bool, Error> {
+        let mut chunk = [0; 16 * 1024];
+        while self.buf.len() < n {
+            let read = match self.shared.transport.recv(&mut chunk) {
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            if read == 0 {
+                return Ok(false);
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+        Ok(true)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if !self.fill(9)? {
+            return Ok(None);
+        }
+        let len = (self.buf[0] as usize) << 16 | (self.buf[1] as usize) << 8 | self.buf[2] as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::Connection(FRAME_SIZE_ERROR, "frame larger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        if !self.fill(9 + len)? {
+            return Ok(None);
+        }
+        let frame = Frame {
+            kind: self.buf[3],
+            flags: self.buf[4],
+            stream: read_u32(&self.buf[5..9]) & 0x7fff_ffff,
+            payload: self.buf[9..9 + len].to_vec(),
+        };
+        self.buf.drain(..9 + len);
+        Ok(Some(frame))
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), Error> {
+        if let Some(c) = &self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != c.stream {
+                return Err(Error::Connection(PROTOCOL_ERROR, "expected CONTINUATION"));
+            }
+        }
+        match frame.kind {
+            DATA => self.data(frame),
+            HEADERS => self.headers(frame),
+            CONTINUATION => self.continuation(frame),
+            PRIORITY => {
+                if frame.stream == 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "PRIORITY on stream 0"));
+                }
+                if frame.payload.len() != 5 {
+                    self.reset(frame.stream, FRAME_SIZE_ERROR)?;
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if frame.stream == 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "RST_STREAM on stream 0"));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR, "RST_STREAM must be 4 bytes"));
+                }
+                if frame.stream > self.last_stream {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+                self.receiving.remove(&frame.stream);
+                self.shared.finish(frame.stream);
+                Ok(())
+            }
+            SETTINGS => {
+                if frame.stream != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "SETTINGS on a stream"));
+                }
+                if frame.flags & ACK != 0 {
+                    if !frame.payload.is_empty() {
+                        return Err(Error::Connection(FRAME_SIZE_ERROR, "SETTINGS acknowledgement with a payload"));
+                    }
+                    return Ok(());
+                }
+                self.apply_settings(&frame.payload)?;
+                self.shared.send(SETTINGS, ACK, 0, &[])?;
+                Ok(())
+            }
+            PUSH_PROMISE => Err(Error::Connection(PROTOCOL_ERROR, "clients cannot push")),
+            PING => {
+                if frame.stream != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR, "PING must be 8 bytes"));
+                }
+                if frame.flags & ACK == 0 {
+                    self.shared.send(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            }
+            WINDOW_UPDATE => self.window_update(frame),
+            // unknown frame types are ignored [RFC7540, Section 4.1]
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self, stream: u32, code: u32) -> Result<(), Error> {
+        self.receiving.remove(&stream);
+        self.shared.reset(stream, code)?;
+        Ok(())
+    }
+
+    /// Apply the client's SETTINGS, from a frame or an HTTP2-Settings header.
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(FRAME_SIZE_ERROR, "SETTINGS must be a multiple of 6 bytes"));
+        }
+        let mut flow = self.shared.flow.lock().unwrap();
+        for setting in payload.chunks(6) {
+            let id = u16::from(setting[0]) << 8 | u16::from(setting[1]);
+            let value = read_u32(&setting[2..]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(Error::Connection(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH"));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR, "invalid SETTINGS_INITIAL_WINDOW_SIZE"));
+                    }
+                    // [RFC7540, Section 6.9.2]
+                    let delta = value - flow.initial_window;
+                    for window in flow.streams.values_mut() {
+                        *window += delta;
+                        if *window > MAX_WINDOW {
+                            return Err(Error::Connection(FLOW_CONTROL_ERROR, "stream window too large"));
+                        }
+                    }
+                    flow.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if value < MAX_FRAME_SIZE as u32 || value > 0xff_ffff {
+                        return Err(Error::Connection(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    flow.max_frame = value as usize;
+                }
+                _ => {}
+            }
+        }
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return Err(Error::Connection(FRAME_SIZE_ERROR, "WINDOW_UPDATE must be 4 bytes"));
+        }
+        let increment = i64::from(read_u32(&frame.payload) & 0x7fff_ffff);
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(Error::Connection(PROTOCOL_ERROR, "WINDOW_UPDATE of 0"));
+            }
+            let mut flow = self.shared.flow.lock().unwrap();
+            flow.window += increment;
+            if flow.window > MAX_WINDOW {
+                return Err(Error::Connection(FLOW_CONTROL_ERROR, "connection window too large"));
+            }
+            self.shared.changed.notify_all();
+            return Ok(());
+        }
+        if frame.stream > self.last_stream {
+            return Err(Error::Connection(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"));
+        }
+        if increment == 0 {
+            return self.reset(frame.stream, PROTOCOL_ERROR);
+        }
+        let overflow = {
+            let mut flow = self.shared.flow.lock().unwrap();
+            match flow.streams.get_mut(&frame.stream) {
+                Some(window) => {
+                    *window += increment;
+                    *window > MAX_WINDOW
+                }
+                // closed meanwhile
+                None => false,
+            }
+        };
+        self.shared.changed.notify_all();
+        if overflow {
+            return self.reset(frame.stream, FLOW_CONTROL_ERROR);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        if frame.stream > self.last_stream {
+            return Err(Error::Connection(PROTOCOL_ERROR, "DATA on an idle stream"));
+        }
+        let len = frame.payload.len();
+        let data = unpad(&frame)?;
+        // the whole payload counts against flow control; give it back
+        // right away
+        if len > 0 {
+            self.shared.window_update(0, len)?;
+        }
+        let end_stream = frame.flags & END_STREAM != 0;
+        let max_body = self.config.limits.max_body;
+        match self.receiving.get_mut(&frame.stream) {
+            Some(incoming) if incoming.body.len() + data.len() > max_body => {
+                // answered right away; the rest of the body is refused
+                self.receiving.remove(&frame.stream);
+                self.answer(frame.stream, Err(ParseError::BodyTooLarge(max_body)));
+                return Ok(());
+            }
+            Some(incoming) => incoming.body.extend_from_slice(data),
+            // already answered without its body
+            None if self.shared.is_open(frame.stream) => return Ok(()),
+            None => return self.reset(frame.stream, STREAM_CLOSED),
+        }
+        if end_stream {
+            self.dispatch(frame.stream);
+        } else if len > 0 {
+            self.shared.window_update(frame.stream, len)?;
+        }
+        Ok(())
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR, "HEADERS on stream 0"));
+        }
+        let mut block = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return Err(Error::Connection(FRAME_SIZE_ERROR, "HEADERS too short for its priority"));
+            }
+            if read_u32(block) & 0x7fff_ffff == frame.stream {
+                return self.reset(frame.stream, PROTOCOL_ERROR);
+            }
+            block = &block[5..];
+        }
+        let continuation = Continuation {
+            stream: frame.stream,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: block.to_vec(),
+        };
+        if frame.flags & END_HEADERS != 0 {
+            self.header_block(continuation)
+        } else {
+            self.continuation = Some(continuation);
+            Ok(())
+        }
+    }
+
+    fn continuation(&mut self, frame: Frame) -> Result<(), Error> {
+        let mut continuation = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => return Err(Error::Connection(PROTOCOL_ERROR, "unexpected CONTINUATION")),
+        };
+        continuation.block.extend_from_slice(&frame.payload);
+        if continuation.block.len() > MAX_HEADER_BLOCK {
+            return Err(Error::Connection(ENHANCE_YOUR_CALM, "header block too large"));
+        }
+        if frame.flags & END_HEADERS != 0 {
+            self.header_block(continuation)
+        } else {
+            self.continuation = Some(continuation);
+            Ok(())
+        }
+    }
+
+    /// Decode a complete header block: a new request, or its trailers.
+    fn header_block(&mut self, c: Continuation) -> Result<(), Error> {
+        // decode even if the stream is to be refused, to keep the
+        // decoder's table in step with the client's, one field at a time
+        // so that a small block referring to large table entries over and
+        // over cannot decode into a header list of any size
+        let max_size = self.config.limits.max_header_size;
+        let mut fields = Vec::new();
+        let mut size = 0;
+        for representation in split_block(&c.block).map_err(|reason| Error::Connection(COMPRESSION_ERROR, reason))? {
+            let decoded = self.decoder.decode(representation)
+                .map_err(|_| Error::Connection(COMPRESSION_ERROR, "invalid header block"))?;
+            for (name, value) in decoded {
+                // as SETTINGS_MAX_HEADER_LIST_SIZE counts [RFC7540, Section 6.5.2]
+                size += name.len() + value.len() + 32;
+                if size <= max_size {
+                    fields.push((name, value));
+                }
+            }
+        }
+
+        if self.receiving.contains_key(&c.stream) {
+            // trailers, which we do not use
+            if !c.end_stream {
+                return self.reset(c.stream, PROTOCOL_ERROR);
+            }
+            self.dispatch(c.stream);
+            return Ok(());
+        }
+        if c.stream <= self.last_stream {
+            if self.shared.is_open(c.stream) {
+                return self.reset(c.stream, STREAM_CLOSED);
+            }
+            return Err(Error::Connection(STREAM_CLOSED, "HEADERS on a closed stream"));
+        }
+        if c.stream.is_multiple_of(2) {
+            return Err(Error::Connection(PROTOCOL_ERROR, "even stream identifier"));
+        }
+        self.last_stream = c.stream;
+
+        if self.shared.open_streams() >= MAX_CONCURRENT_STREAMS {
+            return self.reset(c.stream, REFUSED_STREAM);
+        }
+        if size > max_size {
+            self.shared.open(c.stream);
+            self.answer(c.stream, Err(ParseError::HeaderTooLarge(format!("larger than {} bytes", max_size))));
+            return Ok(());
+        }
+        let head = match parse_head(fields) {
+            Ok(head) => head,
+            Err(reason) => {
+                debug!("malformed HTTP/2 request on stream {}: {}", c.stream, reason);
+                self.shared.reset(c.stream, PROTOCOL_ERROR)?;
+                return Ok(());
+            }
+        };
+        self.shared.open(c.stream);
+        let limits = &self.config.limits;
+        if head.header.len() > limits.max_headers {
+            let e = ParseError::HeaderTooLarge(format!("more than {} fields", limits.max_headers));
+            self.answer(c.stream, Err(e));
+            return Ok(());
+        }
+        if head.content_length.is_some_and(|n| n > limits.max_body) {
+            self.answer(c.stream, Err(ParseError::BodyTooLarge(limits.max_body)));
+            return Ok(());
+        }
+        self.receiving.insert(c.stream, Incoming {
+            head,
+            body: Vec::new(),
+        });
+        if c.end_stream {
+            self.dispatch(c.stream);
+        }
+        Ok(())
+    }
+
+    /// The request on `stream` is complete: answer it.
+    fn dispatch(&mut self, stream: u32) {
+        let Incoming { head, body } = match self.receiving.remove(&stream) {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        if head.content_length.is_some_and(|n| n != body.len()) {
+            // [RFC7540, Section 8.1.2.6]
+            let _ = self.shared.reset(stream, PROTOCOL_ERROR);
+            return;
+        }
+        let (peer, local) = (self.peer, self.local);
+        let secure = local.is_some_and(|local| self.config.tls.contains_key(&local));
+        let req = Method::from_str(&head.method).and_then(|method| {
+            let body = if body.is_empty() && head.content_length.is_none() {
+                None
+            } else {
+                Some(body)
+            };
+            let mut req = Request::from_parts(method, &head.uri, HTTPVersion::new(2, 0), head.header, body, peer, local)?;
+            req.set_secure(secure);
+            Ok(req)
+        });
+        self.answer(stream, req);
+    }
+
+    /// Route and answer `req` on a thread of its own.
+    fn answer(&self, stream: u32, req: Result<Request, ParseError>) {
+        let config = self.config.clone();
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            let req = match req {
+                Ok(req) => req,
+                Err(e) => {
+                    debug!("invalid request on HTTP/2 stream {}: {}", stream, e);
+                    let status_code = e.status_code().unwrap_or(StatusCode::BadRequest);
+                    let _ = send_response(&shared, stream, Response::error(status_code), false);
+                    shared.finish(stream);
+                    return;
+                }
+            };
+            debug!("{:?}", req);
+            let (mut res, access_log) = respond(&config, &req);
+            if res.take_handoff().is_some() {
+                // only an HTTP/1.x connection can be taken over, see above
+                res = Response::error(StatusCode::NotImplemented);
+            }
+            let status_code = res.status_code();
+            let sent = match send_response(&shared, stream, res, *req.method() == Method::HEAD) {
+                Ok(sent) => sent,
+                Err(e) => {
+                    debug!("HTTP/2 stream {}: {}", stream, e);
+                    0
+                }
+            };
+            shared.finish(stream);
+            if let Some(log) = access_log {
+                log.log(req.peer_addr(), &req, status_code, sent);
+            }
+        });
+    }
+}
+
+/// The payload of a DATA or HEADERS frame without its padding.
+fn unpad(frame: &Frame) -> Result<&[u8], Error> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let pad = match frame.payload.first() {
+        Some(pad) => *pad as usize,
+        None => return Err(Error::Connection(FRAME_SIZE_ERROR, "padded frame without a pad length")),
+    };
+    if pad >= frame.payload.len() {
+        return Err(Error::Connection(PROTOCOL_ERROR, "padding longer than the payload"));
+    }
+    Ok(&frame.payload[1..frame.payload.len() - pad])
+}
+
+/// Split a header block into its field representations, so they can be
+/// decoded one by one
+/// [[RFC7541, Section 6](https://tools.ietf.org/html/rfc7541#section-6)].
+/// Dynamic table size updates beyond `HEADER_TABLE_SIZE` are refused.
+fn split_block(mut block: &[u8]) -> Result<Vec<&[u8]>, &'static str> {
+    fn integer(buf: &[u8], at: usize, prefix: u8) -> Result<(usize, usize), &'static str> {
+        let first = *buf.get(at).ok_or("truncated integer")?;
+        let max = (1usize << prefix) - 1;
+        let mut value = (first as usize) & max;
+        let mut end = at + 1;
+        if value < max {
+            return Ok((value, end));
+        }
+        let mut shift = 0;
+        loop {
+            let b = *buf.get(end).ok_or("truncated integer")?;
+            end += 1;
+            if shift > 21 {
+                return Err("integer too large");
+            }
+            value += ((b & 0x7f) as usize) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok((value, end));
+            }
+        }
+    }
+    fn string(buf: &[u8], at: usize) -> Result<usize, &'static str> {
+        let (len, start) = integer(buf, at, 7)?;
+        let end = start.checked_add(len).ok_or("string too long")?;
+        if end > buf.len() {
+            return Err("truncated string");
+        }
+        Ok(end)
+    }
+
+    let mut representations = Vec::new();
+    while let Some(&first) = block.first() {
+        let end = if first & 0x80 != 0 {
+            // indexed
+            integer(block, 0, 7)?.1
+        } else if first & 0xe0 == 0x20 {
+            // dynamic table size update
+            let (size, end) = integer(block, 0, 5)?;
+            if size > HEADER_TABLE_SIZE {
+                return Err("table size update beyond SETTINGS_HEADER_TABLE_SIZE");
+            }
+            end
+        } else {
+            // literal, with incremental indexing or not, and either an
+            // indexed or a literal name
+            let (index, mut end) = integer(block, 0, if first & 0x40 != 0 { 6 } else { 4 })?;
+            if index == 0 {
+                end = string(block, end)?;
+            }
+            string(block, end)?
+        };
+        representations.push(&block[..end]);
+        block = &block[end..];
+    }
+    Ok(representations)
+}
+
+/// Whether `value` may be sent on in a field: no CR, LF or NUL, which
+/// would end the line, or the field, when written as HTTP/1.1
+/// [[RFC7540, Section 10.3](https://tools.ietf.org/html/rfc7540#section-10.3)].
+fn is_field_value(value: &str) -> bool {
+    !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+/// Check the decoded header fields of a request
+/// [[RFC7540, Section 8.1.2](https://tools.ietf.org/html/rfc7540#section-8.1.2)].
+fn parse_head(fields: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Head, &'static str> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut header = Header::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        let name = String::from_utf8(name).map_err(|_| "header name is not UTF-8")?;
+        let value = String::from_utf8(value).map_err(|_| "header value is not UTF-8")?;
+        if !is_field_value(&value) {
+            return Err("CR, LF or NUL in a header value");
+        }
+        if name.starts_with(':') {
+            if !header.is_empty() || !cookies.is_empty() {
+                return Err("pseudo-header after a regular header");
+            }
+            let slot = match name.as_str() {
+                ":method" => &mut method,
+                ":scheme" => &mut scheme,
+                ":path" => &mut path,
+                ":authority" => &mut authority,
+                _ => return Err("unknown pseudo-header"),
+            };
+            if slot.is_some() {
+                return Err("repeated pseudo-header");
+            }
+            // these end up in a request line
+            if value.contains([' ', '\t']) {
+                return Err("whitespace in a pseudo-header");
+            }
+            *slot = Some(value);
+            continue;
+        }
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err("header names must be in lower case");
+        }
+        if !is_token(&name) {
+            return Err("header name is not a token");
+        }
+        match name.as_str() {
+            "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" => {
+                return Err("connection-specific header");
+            }
+            "te" if value != "trailers" => return Err("TE other than trailers"),
+            _ => {}
+        }
+        // crumbs are joined back into one field [RFC7540, Section 8.1.2.5]
+        if name == "cookie" {
+            cookies.push(value);
+            continue;
+        }
+        header.append(name, value);
+    }
+    if !cookies.is_empty() {
+        header.append("cookie", cookies.join("; "));
+    }
+
+    let method = method.ok_or("missing :method")?;
+    let uri = if method == "CONNECT" {
+        if scheme.is_some() || path.is_some() {
+            return Err(":scheme or :path in CONNECT");
+        }
+        authority.clone().ok_or("missing :authority")?
+    } else {
+        if scheme.is_none() {
+            return Err("missing :scheme");
+        }
+        match path {
+            Some(path) if !path.is_empty() => path,
+            _ => return Err("missing :path"),
+        }
+    };
+    if let Some(authority) = authority {
+        if !header.contains("host") {
+            header.insert("host", authority);
+        }
+    }
+    let content_length = header.content_length().map_err(|_| "invalid content-length")?;
+    Ok(Head {
+        method,
+        uri,
+        header,
+        content_length,
+    })
+}
+
+/// Send `res` on `stream`, returning the number of body bytes sent.
+fn send_response(shared: &Shared, stream: u32, mut res: Response, head: bool) -> io::Result<u64> {
+    res.complete_header();
+    let status_code = res.status_code().to_u16();
+    let mut block = Vec::new();
+    encode_field(&mut block, ":status", &status_code.to_string());
+    for (name, value) in res.headers() {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" => continue,
+            _ => encode_field(&mut block, &name, value),
+        }
+    }
+
+    let mut body = res.take_stream();
+    let mut chunk = vec![0; MAX_FRAME_SIZE];
+    let no_body = head || status_code == 204 || status_code == 304;
+    let mut n = if no_body { 0 } else { read_chunk(&mut body, &mut chunk)? };
+    shared.send_headers(stream, &block, n == 0)?;
+    if n == 0 {
+        return Ok(0);
+    }
+    let mut sent = 0;
+    while n > 0 {
+        let mut data = &chunk[..n];
+        while !data.is_empty() {
+            let credit = shared.reserve(stream, data.len())?;
+            shared.send(DATA, 0, stream, &data[..credit])?;
+            data = &data[credit..];
+            sent += credit as u64;
+        }
+        n = match read_chunk(&mut body, &mut chunk) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = shared.send(RST_STREAM, 0, stream, &INTERNAL_ERROR.to_be_bytes());
+                return Err(e);
+            }
+        };
+    }
+    shared.send(DATA, END_STREAM, stream, &[])?;
+    Ok(sent)
+}
+
+fn read_chunk(body: &mut Box<dyn Read + Send>, chunk: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match body.read(chunk) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// A literal header field without indexing, never Huffman coded
+/// [[RFC7541, Section 6.2.2](https://tools.ietf.org/html/rfc7541#section-6.2.2)].
+fn encode_field(block: &mut Vec<u8>, name: &str, value: &str) {
+    block.push(0);
+    encode_string(block, name.as_bytes());
+    encode_string(block, value.as_bytes());
+}
+
+fn encode_string(block: &mut Vec<u8>, s: &[u8]) {
+    encode_integer(block, 7, s.len());
+    block.extend_from_slice(s);
+}
+
+/// [[RFC7541, Section 5.1](https://tools.ietf.org/html/rfc7541#section-5.1)]
+fn encode_integer(block: &mut Vec<u8>, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    value -= max;
+    while value >= 128 {
+        block.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    static CONFIG: &str = r#"
+        [[listen]]
+        addr = "127.0.0.1:8080"
+
+        [limits]
+        max_header_size = 2048
+
+        [[server]]
+          [[server.location]]
+          prefix = "/"
+          handler = "redirect"
+          to = "/new"
+    "#;
+
+    /// The client side of a connection to `serve`.
+    struct Client {
+        stream: TcpStream,
+        encoder: hpack::Encoder<'static>,
+        decoder: hpack::Decoder<'static>,
+        /// The payload of the server's SETTINGS.
+        settings: Vec<u8>,
+    }
+
+    impl Client {
+        fn connect(config: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            start(Arc::new(config.parse().unwrap()), Box::new(server), None, None, None, Vec::new());
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut client = Client {
+                stream,
+                encoder: hpack::Encoder::new(),
+                decoder: hpack::Decoder::new(),
+                settings: Vec::new(),
+            };
+            client.stream.write_all(PREFACE).unwrap();
+            client.send(SETTINGS, 0, 0, &[]);
+            let settings = client.next().unwrap();
+            assert_eq!((settings.kind, settings.flags), (SETTINGS, 0));
+            client.settings = settings.payload;
+            client
+        }
+
+        fn send(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+            let mut buf = Vec::new();
+            encode_frame(&mut buf, kind, flags, stream, payload);
+            self.stream.write_all(&buf).unwrap();
+        }
+
+        /// The next frame, or `None` once the connection is closed.
+        fn next(&mut self) -> Option<Frame> {
+            let mut head = [0; 9];
+            if self.stream.read_exact(&mut head).is_err() {
+                return None;
+            }
+            let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+            let mut payload = vec![0; len];
+            self.stream.read_exact(&mut payload).unwrap();
+            Some(Frame {
+                kind: head[3],
+                flags: head[4],
+                stream: read_u32(&head[5..9]) & 0x7fff_ffff,
+                payload,
+            })
+        }
+
+        /// The next frame other than a SETTINGS acknowledgement.
+        fn recv(&mut self) -> Option<Frame> {
+            loop {
+                match self.next() {
+                    Some(ref frame) if frame.kind == SETTINGS && frame.flags & ACK != 0 => continue,
+                    frame => return frame,
+                }
+            }
+        }
+
+        fn block(&mut self, fields: &[(&str, &str)]) -> Vec<u8> {
+            let fields = fields.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect();
+            self.encoder.encode(&fields)
+        }
+
+        fn get(&mut self, stream: u32, path: &str) {
+            let block = self.block(&[(":method", "GET"), (":scheme", "http"), (":path", path), (":authority", "localhost")]);
+            self.send(HEADERS, END_HEADERS | END_STREAM, stream, &block);
+        }
+
+        /// The status of the response on `stream`, or the RST_STREAM code
+        /// it was reset with.
+        fn response(&mut self, stream: u32) -> Result<u16, u32> {
+            while let Some(frame) = self.recv() {
+                match frame.kind {
+                    HEADERS if frame.stream == stream => {
+                        let fields = self.decoder.decode(&frame.payload).unwrap();
+                        let status = &fields.iter().find(|(k, _)| k == b":status").unwrap().1;
+                        return Ok(String::from_utf8_lossy(status).parse().unwrap());
+                    }
+                    RST_STREAM if frame.stream == stream => return Err(read_u32(&frame.payload)),
+                    GOAWAY => panic!("GOAWAY {}", read_u32(&frame.payload[4..])),
+                    _ => {}
+                }
+            }
+            panic!("connection closed");
+        }
+
+        /// The error code of the GOAWAY that closes the connection.
+        fn goaway(&mut self) -> u32 {
+            while let Some(frame) = self.recv() {
+                if frame.kind == GOAWAY {
+                    return read_u32(&frame.payload[4..]);
+                }
+            }
+            panic!("connection closed without GOAWAY");
+        }
+    }
+
+    fn literal(name: &str, value: &[u8]) -> Vec<u8> {
+        // literal without indexing, new name
+        let mut block = vec![0];
+        push_integer(&mut block, name.len());
+        block.extend_from_slice(name.as_bytes());
+        push_integer(&mut block, value.len());
+        block.extend_from_slice(value);
+        block
+    }
+
+    fn push_integer(block: &mut Vec<u8>, value: usize) {
+        let encoded = hpack::encoder::encode_integer(value, 7);
+        block.extend(encoded);
+    }
+
+    fn setting(id: u16, value: u32) -> Vec<u8> {
+        let mut setting = id.to_be_bytes().to_vec();
+        setting.extend_from_slice(&value.to_be_bytes());
+        setting
+    }
+
+    /// The GOAWAY code a fresh connection is closed with after `frames`.
+    fn refused(frames: &[(u8, u8, u32, &[u8])]) -> u32 {
+        let mut client = Client::connect(CONFIG);
+        for &(kind, flags, stream, payload) in frames {
+            client.send(kind, flags, stream, payload);
+        }
+        client.goaway()
+    }
+
+    #[test]
+    fn frames_and_integers_are_encoded() {
+        let mut buf = Vec::new();
+        // the reserved bit of the stream identifier is never sent
+        encode_frame(&mut buf, WINDOW_UPDATE, 0, 0x8000_0003, &[0, 0, 1, 0]);
+        assert_eq!(buf, [0, 0, 4, WINDOW_UPDATE, 0, 0, 0, 0, 3, 0, 0, 1, 0]);
+
+        // [RFC7541, Appendix C.1]
+        let mut block = Vec::new();
+        encode_integer(&mut block, 5, 10);
+        assert_eq!(block, [10]);
+        block.clear();
+        encode_integer(&mut block, 5, 1337);
+        assert_eq!(block, [31, 154, 10]);
+        block.clear();
+        encode_integer(&mut block, 7, 127);
+        assert_eq!(block, [127, 0]);
+
+        block.clear();
+        encode_field(&mut block, "location", "/new");
+        assert_eq!(hpack::Decoder::new().decode(&block).unwrap(), vec![(b"location".to_vec(), b"/new".to_vec())]);
+    }
+
+    #[test]
+    fn oversized_frames_close_the_connection() {
+        assert_eq!(refused(&[(PING, 0, 0, &[0; MAX_FRAME_SIZE + 1])]), FRAME_SIZE_ERROR);
+        assert_eq!(refused(&[(PING, 0, 0, &[0; 7])]), FRAME_SIZE_ERROR);
+    }
+
+    #[test]
+    fn header_blocks_continue_in_continuation_frames() {
+        let mut client = Client::connect(CONFIG);
+        let block = client.block(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")]);
+        let (first, rest) = block.split_at(3);
+        client.send(HEADERS, END_STREAM, 1, first);
+        client.send(CONTINUATION, 0, 1, &rest[..1]);
+        client.send(CONTINUATION, END_HEADERS, 1, &rest[1..]);
+        assert_eq!(client.response(1), Ok(302));
+
+        // nothing may come between a header block's frames
+        client.send(HEADERS, END_STREAM, 3, first);
+        client.send(PING, 0, 0, &[0; 8]);
+        assert_eq!(client.goaway(), PROTOCOL_ERROR);
+
+        assert_eq!(refused(&[(CONTINUATION, END_HEADERS, 1, &[0x82])]), PROTOCOL_ERROR);
+        assert_eq!(refused(&[(HEADERS, END_STREAM, 1, &[0x82]), (CONTINUATION, END_HEADERS, 3, &[0x86])]), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn settings_are_announced_acknowledged_and_checked() {
+        let mut client = Client::connect(CONFIG);
+        let settings: Vec<_> = client.settings.chunks(6).map(|s| (u16::from(s[0]) << 8 | u16::from(s[1]), read_u32(&s[2..]))).collect();
+        assert!(settings.contains(&(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS as u32)));
+        assert!(settings.contains(&(SETTINGS_MAX_HEADER_LIST_SIZE, 2048)));
+        // our empty SETTINGS from the preface, then another
+        for _ in 0..2 {
+            let ack = client.next().unwrap();
+            assert_eq!((ack.kind, ack.flags, ack.payload.len()), (SETTINGS, ACK, 0));
+            client.send(SETTINGS, 0, 0, &setting(SETTINGS_MAX_FRAME_SIZE, 32_768));
+        }
+        client.send(PING, 0, 0, b"12345678");
+        let pong = client.recv().unwrap();
+        assert_eq!((pong.kind, pong.flags, &pong.payload[..]), (PING, ACK, &b"12345678"[..]));
+
+        assert_eq!(refused(&[(SETTINGS, 0, 0, &[0; 5])]), FRAME_SIZE_ERROR);
+        assert_eq!(refused(&[(SETTINGS, 0, 1, &[])]), PROTOCOL_ERROR);
+        assert_eq!(refused(&[(SETTINGS, ACK, 0, &setting(SETTINGS_ENABLE_PUSH, 0))]), FRAME_SIZE_ERROR);
+        assert_eq!(refused(&[(SETTINGS, 0, 0, &setting(SETTINGS_ENABLE_PUSH, 2))]), PROTOCOL_ERROR);
+        assert_eq!(refused(&[(SETTINGS, 0, 0, &setting(SETTINGS_INITIAL_WINDOW_SIZE, 1 << 31))]), FLOW_CONTROL_ERROR);
+        assert_eq!(refused(&[(SETTINGS, 0, 0, &setting(SETTINGS_MAX_FRAME_SIZE, 100))]), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn data_waits_for_the_send_window() {
+        let root = env::temp_dir().join(format!("h2-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("hundred"), [b'x'; 100]).unwrap();
+        let config = format!("
+            [[listen]]
+            addr = \"127.0.0.1:8080\"
+
+            [[server]]
+              [[server.location]]
+              prefix = \"/\"
+              handler = \"static\"
+              root = {:?}
+        ", root);
+
+        let mut client = Client::connect(&config);
+        client.send(SETTINGS, 0, 0, &setting(SETTINGS_INITIAL_WINDOW_SIZE, 10));
+        client.get(1, "/hundred");
+        assert_eq!(client.response(1), Ok(200));
+        let data = client.recv().unwrap();
+        assert_eq!((data.kind, data.flags, data.payload.len()), (DATA, 0, 10));
+        // the window is spent: the PING is answered before any more DATA
+        client.send(PING, 0, 0, &[0; 8]);
+        assert_eq!(client.recv().unwrap().kind, PING);
+        client.send(WINDOW_UPDATE, 0, 1, &90u32.to_be_bytes());
+        let mut received = 0;
+        loop {
+            let data = client.recv().unwrap();
+            assert_eq!(data.kind, DATA);
+            received += data.payload.len();
+            if data.flags & END_STREAM != 0 {
+                break;
+            }
+        }
+        assert_eq!(received, 90);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(refused(&[(WINDOW_UPDATE, 0, 0, &0x7fff_ffffu32.to_be_bytes())]), FLOW_CONTROL_ERROR);
+        assert_eq!(refused(&[(WINDOW_UPDATE, 0, 0, &[0; 4])]), PROTOCOL_ERROR);
+        assert_eq!(refused(&[(WINDOW_UPDATE, 0, 1, &[0, 0, 0, 1])]), PROTOCOL_ERROR);
+        assert_eq!(refused(&[(DATA, END_STREAM, 1, b"x")]), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn goaway_ends_the_connection() {
+        let mut client = Client::connect(CONFIG);
+        client.get(1, "/");
+        assert_eq!(client.response(1), Ok(302));
+        client.send(GOAWAY, 0, 0, &[0; 8]);
+        let goaway = loop {
+            let frame = client.recv().unwrap();
+            if frame.kind == GOAWAY {
+                break frame;
+            }
+        };
+        // the last stream it saw, and no error
+        assert_eq!((read_u32(&goaway.payload), read_u32(&goaway.payload[4..])), (1, NO_ERROR));
+        assert!(client.next().is_none());
+
+        assert_eq!(refused(&[(GOAWAY, 0, 1, &[0; 8])]), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn header_blocks_are_split_into_representations() {
+        let mut block = vec![0x82, 0x86, 0x20 | 0x1f, 0x20];
+        block.extend(literal("x-a", b"1"));
+        block.extend_from_slice(&[0x41, 3]);
+        block.extend_from_slice(b"b.c");
+        let representations = split_block(&block).unwrap();
+        assert_eq!(representations.len(), 5);
+        assert_eq!(representations[2], &[0x3f, 0x20][..]);
+        assert_eq!(representations[3].len(), 1 + 1 + 3 + 1 + 1);
+
+        // a size update of 4097, a truncated string, a truncated integer
+        assert!(split_block(&[0x3f, 0xe2, 0x1f]).is_err());
+        assert!(split_block(&[0x00, 5, b'a']).is_err());
+        assert!(split_block(&[0xff, 0x80]).is_err());
+    }
+
+    #[test]
+    fn malformed_fields_are_refused() {
+        let head = |extra: (&str, &str)| {
+            let mut fields: Vec<(Vec<u8>, Vec<u8>)> = vec![(":method", "GET"), (":scheme", "http"), (":path", "/")]
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect();
+            fields.push((extra.0.as_bytes().to_vec(), extra.1.as_bytes().to_vec()));
+            parse_head(fields).map(|head| head.header)
+        };
+        assert!(head(("x-ok", "a value")).is_ok());
+        assert!(head(("x-a", "1\r\nx-b: 2")).is_err());
+        assert!(head(("x-a", "1\nx-b: 2")).is_err());
+        assert!(head(("x-a", "1\0")).is_err());
+        assert!(head(("x a", "1")).is_err());
+        assert!(head(("x-a:", "1")).is_err());
+        assert!(head(("", "1")).is_err());
+        assert!(head(("X-Upper", "1")).is_err());
+        assert!(head((":authority", "a b")).is_err());
+    }
+
+    #[test]
+    fn injected_fields_reset_the_stream() {
+        let mut client = Client::connect(CONFIG);
+        let mut block = client.block(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")]);
+        block.extend(literal("x-forwarded-for", b"1.2.3.4\r\nGET /smuggled HTTP/1.1"));
+        client.send(HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        assert_eq!(client.response(1), Err(PROTOCOL_ERROR));
+        // the connection goes on
+        client.get(3, "/");
+        assert_eq!(client.response(3), Ok(302));
+    }
+
+    #[test]
+    fn decoded_header_list_is_limited() {
+        let mut client = Client::connect(CONFIG);
+        let mut block = client.block(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")]);
+        // a 1000-byte field added to the table, then referred to 1000 times
+        // in 1000 bytes: a megabyte once decoded
+        block.extend_from_slice(&[0x40, 5]);
+        block.extend_from_slice(b"x-big");
+        push_integer(&mut block, 1000);
+        block.extend(vec![b'x'; 1000]);
+        block.extend(vec![0x80 | 62; 1000]);
+        client.send(HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        assert_eq!(client.response(1), Ok(431));
+        client.get(3, "/");
+        assert_eq!(client.response(3), Ok(302));
+    }
+
+    #[test]
+    fn oversized_table_updates_close_the_connection() {
+        let mut client = Client::connect(CONFIG);
+        let mut block = vec![0x3f, 0xe2, 0x1f];
+        block.extend(client.block(&[(":method", "GET"), (":scheme", "http"), (":path", "/")]));
+        client.send(HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        assert_eq!(client.goaway(), COMPRESSION_ERROR);
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Request handlers
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::cache::Cache;
+use super::cgi;
+use super::config::{Auth, Handler, Location};
+use super::fastcgi;
+use super::header::{Allow, Authorization};
+use super::index;
+use super::proxy;
+use super::request::{Method, Request, RequestLimits};
+use super::response::Response;
+use super::root::Root;
+use super::sse;
+use super::status::StatusCode;
+use super::upstream::Pool;
+use super::websocket;
+
+/// Answer `req` with the handler of `location`, applying the location's
+/// authentication, limits and extra headers. A proxied response head must
+/// fit in the request `limits`.
+pub fn handle(location: &Location, limits: &RequestLimits, req: &Request) -> Response {
+    let mut res = match check(location, req) {
+        Some(res) => res,
+        None => match &location.handler {
+            Handler::Static { root, index } => serve(root, index, false, req),
+            Handler::Autoindex { root, index } => serve(root, index, true, req),
+            Handler::Redirect { to, status } => Response::redirect(to, *status),
+            Handler::Proxy { pool, timeout, cache: Some(cache) } => Cache::handle(cache, pool, *timeout, limits, req),
+            Handler::Proxy { pool, timeout, cache: None } => proxy::forward(pool, *timeout, limits, req),
+            Handler::Purge { cache } => purge(cache, req),
+            Handler::Status { pools } => status(pools),
+            Handler::Cgi { root, timeout } => cgi::run(root, *timeout, req),
+            Handler::FastCgi { backend, root, index, timeout } => fastcgi::run(backend, root, index, *timeout, req),
+            Handler::WebSocket { service, max_message } => websocket::upgrade(service, *max_message, req),
+            Handler::Sse { source, heartbeat } => sse::respond(source, *heartbeat, req),
+        }
+    };
+    for (k, v) in &location.headers {
+        res.set_header(k.as_str(), v.as_str());
+    }
+    res
+}
+
+/// Enforce `auth` and `limits`, returning the error response if the
+/// request is rejected.
+fn check(location: &Location, req: &Request) -> Option<Response> {
+    if let Some(auth) = &location.auth {
+        if !authorized(auth, req) {
+            let mut res = Response::error(StatusCode::Unauthorized);
+            res.set_header("WWW-Authenticate", format!("Basic realm=\"{}\"", auth.realm));
+            return Some(res);
+        }
+    }
+    if let Some(max_body) = location.limits.max_body {
+        if req.body().map_or(0, |b| b.len()) > max_body {
+            return Some(Response::error(StatusCode::RequestEntityTooLarge));
+        }
+    }
+    None
+}
+
+fn authorized(auth: &Auth, req: &Request) -> bool {
+    match req.header_map().authorization() {
+        Ok(Some(Authorization::BaseAuthorization { username, password })) =>
+            auth.users.get(&username).is_some_and(|p| *p == password),
+        _ => false,
+    }
+}
+
+/// Plain text report of every upstream peer: health, requests in flight,
+/// consecutive failures and keep-alive pool hits, misses and idle
+/// connections.
+fn status(pools: &[Arc<Pool>]) -> Response {
+    let mut body = String::new();
+    for pool in pools {
+        body.push_str(&format!("upstream {}\n", pool.name));
+        for peer in &pool.peers {
+            body.push_str(&format!("  {} {} active={} fails={} hits={} misses={} idle={}\n",
+                                   peer.upstream,
+                                   if peer.is_healthy() { "up" } else { "down" },
+                                   peer.active(),
+                                   peer.fails(),
+                                   peer.hits(),
+                                   peer.misses(),
+                                   peer.idle()));
+        }
+    }
+    Response::text(body)
+}
+
+/// Purge the cache entry named by the `key` query parameter, e.g.
+/// `?key=example.com/index.html`, or the whole cache without one. Only
+/// `PURGE`, `DELETE` and `POST` purge, so that a link followed or
+/// prefetched does not.
+fn purge(cache: &Cache, req: &Request) -> Response {
+    match req.method() {
+        Method::DELETE | Method::POST => {}
+        Method::Extension(method) if method == "PURGE" => {}
+        _ => {
+            // [RFC7231, Section 6.5.5]
+            let mut res = Response::error(StatusCode::MethodNotAllowed);
+            res.set_allow(&Allow::new(vec![Method::Extension("PURGE".to_string()), Method::DELETE, Method::POST]));
+            return res;
+        }
+    }
+    let key = req.query_pairs().into_iter()
+        .find(|(k, _)| k == "key")
+        .map(|(_, v)| v);
+    let purged = cache.purge(key.as_deref());
+    info!("cache `{}`: purged {} entries", cache.name, purged);
+    Response::text(format!("purged {}\n", purged))
+}
+
+
+fn serve(root: &Root, index: &[String], autoindex: bool, req: &Request) -> Response {
+    match req.method() {
+        Method::GET | Method::HEAD => {}
+        Method::Extension(_) => return Response::error(StatusCode::NotImplemented),
+        _ => {
+            // [RFC7231, Section 6.5.5]
+            let mut res = Response::error(StatusCode::MethodNotAllowed);
+            res.set_allow(&Allow::new(vec![Method::GET, Method::HEAD]));
+            return res;
+        }
+    }
+
+    let path = req.path();
+    let mut file = match root.resolve(path) {
+        Ok(file) => file,
+        Err(status_code) => return Response::error(status_code),
+    };
+
+    if file.is_dir() {
+        if !path.ends_with('/') {
+            return Response::redirect(&format!("{}/", path), StatusCode::MovedPermanently);
+        }
+        // the index file is held to the same policy as the request path
+        let found = index.iter()
+            .filter_map(|i| root.resolve(&format!("{}{}", path, i)).ok())
+            .find(|f| f.is_file());
+        match found {
+            Some(f) => file = f,
+            None if autoindex => return match index::render(root, &file, path) {
+                Ok(html) => Response::html(html),
+                Err(e) => {
+                    warn!("{}: {}", file.display(), e);
+                    Response::error(StatusCode::Forbidden)
+                }
+            },
+            None => return Response::error(StatusCode::Forbidden),
+        }
+    }
+
+    // streamed, so that large files are never held in memory
+    let res = File::open(&file).and_then(|f| {
+        Response::builder(StatusCode::OK)
+            .header("Content-Type", content_type(&file))
+            .file(f)
+    });
+    match res {
+        Ok(builder) => builder.build(),
+        Err(_) => Response::error(StatusCode::NotFound),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("gif") => "image/gif",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
@@ -21,13 +21,15 @@
 
 //! HTTP header
 use core::fmt::{Display, Error, Formatter};
-use std::collections::HashMap;
 use std::convert::From;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
-use chrono::{DateTime, Local};
+use base64;
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Utc};
 
-use super::request::Method;
+use super::error::ParseError;
+use super::request::{is_token, Method};
 
 /// ## Allow
 /// The Allow entity-header field lists the set of methods supported by
@@ -37,21 +39,30 @@ use super::request::Method;
 /// using the POST method, and thus should be ignored if it is received
 /// as part of a POST entity.
 #[derive(Debug)]
-struct Allow {
+pub struct Allow {
     methods: Vec<Method>,
 }
 
+impl Allow {
+    pub fn new(methods: Vec<Method>) -> Self {
+        Allow {
+            methods
+        }
+    }
+
+    pub fn methods(&self) -> &[Method] {
+        &self.methods
+    }
+}
+
 impl FromStr for Allow {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
         let mut methods = Vec::new();
         let arr = s.split(",");
         for item in arr {
-            match Method::from_str(item) {
-                Ok(method) => methods.push(method),
-                Err(e) => unimplemented!()
-            }
+            methods.push(Method::from_str(item.trim())?);
         }
         Ok(Allow {
             methods
@@ -61,15 +72,23 @@ impl FromStr for Allow {
 
 impl Display for Allow {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        for item in &self.methods {
-            write!(f, "{},", item);
+        for (i, item) in self.methods.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
         }
-        write!(f, "")
+        Ok(())
     }
 }
 
+/// ## Authorization
+/// [[RFC1945, Section 11.1](https://tools.ietf.org/html/rfc1945#section-11.1)]
+/// The "basic" authentication scheme is based on the model that the
+/// user agent must authenticate itself with a user-ID and a password
+/// for each realm.
 #[derive(Debug)]
-enum Authorization {
+pub enum Authorization {
     BaseAuthorization {
         username: String,
         password: String,
@@ -77,79 +96,107 @@ enum Authorization {
 }
 
 impl FromStr for Authorization {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        let mut cursor = s.splitn(2, " ");
+        let invalid = |message: &str| ParseError::invalid("Authorization", message);
+        let mut cursor = s.trim().splitn(2, " ");
         match cursor.next() {
-            Some("Basic") => Ok(Authorization::BaseAuthorization {
-                username: String::new(),
-                password: String::new(),
-            }),
-            _ => unimplemented!()
+            Some(scheme) if scheme.eq_ignore_ascii_case("Basic") => {
+                let credentials = cursor.next().ok_or_else(|| invalid("missing basic-cookie"))?.trim();
+                let decoded = base64::decode(credentials).map_err(|_| invalid("basic-cookie is not base64"))?;
+                let decoded = String::from_utf8(decoded).map_err(|_| invalid("basic-cookie is not UTF-8"))?;
+                let mut cursor = decoded.splitn(2, ":");
+                Ok(Authorization::BaseAuthorization {
+                    username: cursor.next().unwrap_or("").to_string(),
+                    password: cursor.next().ok_or_else(|| invalid("basic-cookie has no `:`"))?.to_string(),
+                })
+            }
+            _ => Err(invalid("only the Basic scheme is supported"))
         }
     }
 }
 
 impl Display for Authorization {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        match self {
+            Authorization::BaseAuthorization { username, password } =>
+                write!(f, "Basic {}", base64::encode(&format!("{}:{}", username, password)))
+        }
     }
 }
 
 #[derive(Debug)]
-enum ContentEncoding {
+pub enum ContentEncoding {
     Gzip,
     Compress,
 }
 
 impl FromStr for ContentEncoding {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
+        match s.to_ascii_lowercase().as_str() {
             "x-gzip" => Ok(ContentEncoding::Gzip),
             "gzip" => Ok(ContentEncoding::Gzip),
             "x-compress" => Ok(ContentEncoding::Compress),
             "compress" => Ok(ContentEncoding::Compress),
-            _ => unimplemented!()
+            _ => Err(ParseError::invalid("Content-Encoding", format!("unknown content-coding `{}`", s)))
         }
     }
 }
 
 impl Display for ContentEncoding {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{:?}", self)
+        write!(f, "{}", match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Compress => "compress",
+        })
     }
 }
 
-type ContentLength = usize;
+pub type ContentLength = usize;
 
 /// # Multipurpose Internet Mail Extensions (MIME) type
+/// Subtypes are matched ignoring case; any other is kept as `Other`.
 #[derive(Debug)]
-enum Text {
+pub enum Text {
     /// Any document that contains text and is theoretically human readable
     Any,
+    Plain,
+    HTML,
+    CSS,
+    Other(String),
 }
 
 impl FromStr for Text {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
-            _ => Ok(Text::Any),
-        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "*" => Text::Any,
+            "plain" => Text::Plain,
+            "html" => Text::HTML,
+            "css" => Text::CSS,
+            _ => Text::Other(s.to_string()),
+        })
     }
 }
 
 impl Display for Text {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        write!(f, "{}", match self {
+            Text::Any => "*",
+            Text::Plain => "plain",
+            Text::HTML => "html",
+            Text::CSS => "css",
+            Text::Other(subtype) => subtype,
+        })
     }
 }
 
 #[derive(Debug)]
-enum Image {
+pub enum Image {
     /// Any kind of image. Videos are not included, though animated images (like animated GIF) are
     /// described with an image type.
     Any,
@@ -158,127 +205,187 @@ enum Image {
     PNG,
     SVG,
     WEBP,
+    Other(String),
 }
 
 impl FromStr for Image {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
-            _ => Ok(Image::Any),
-        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "*" => Image::Any,
+            "gif" => Image::GIF,
+            "jpeg" => Image::JPEG,
+            "png" => Image::PNG,
+            "svg+xml" => Image::SVG,
+            "webp" => Image::WEBP,
+            _ => Image::Other(s.to_string()),
+        })
     }
 }
 
 impl Display for Image {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        write!(f, "{}", match self {
+            Image::Any => "*",
+            Image::GIF => "gif",
+            Image::JPEG => "jpeg",
+            Image::PNG => "png",
+            Image::SVG => "svg+xml",
+            Image::WEBP => "webp",
+            Image::Other(subtype) => subtype,
+        })
     }
 }
 
 #[derive(Debug)]
-enum Audio {
+pub enum Audio {
     /// Any kind of audio file
     Any,
     WAVE,
     WAV,
     WEBM,
     OGG,
+    Other(String),
 }
 
 impl FromStr for Audio {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
-            _ => Ok(Audio::Any),
-        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "*" => Audio::Any,
+            "wave" => Audio::WAVE,
+            "wav" => Audio::WAV,
+            "webm" => Audio::WEBM,
+            "ogg" => Audio::OGG,
+            _ => Audio::Other(s.to_string()),
+        })
     }
 }
 
 impl Display for Audio {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        write!(f, "{}", match self {
+            Audio::Any => "*",
+            Audio::WAVE => "wave",
+            Audio::WAV => "wav",
+            Audio::WEBM => "webm",
+            Audio::OGG => "ogg",
+            Audio::Other(subtype) => subtype,
+        })
     }
 }
 
 #[derive(Debug)]
-enum Video {
+pub enum Video {
     /// Any kind of video file
     Any,
     WEBM,
     OGG,
+    Other(String),
 }
 
 impl FromStr for Video {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
-            _ => Ok(Video::Any),
-        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "*" => Video::Any,
+            "webm" => Video::WEBM,
+            "ogg" => Video::OGG,
+            _ => Video::Other(s.to_string()),
+        })
     }
 }
 
 impl Display for Video {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        write!(f, "{}", match self {
+            Video::Any => "*",
+            Video::WEBM => "webm",
+            Video::OGG => "ogg",
+            Video::Other(subtype) => subtype,
+        })
     }
 }
 
 #[derive(Debug)]
-enum Application {
+pub enum Application {
     /// Any kind of binary data, especially data that will be executed or interpreted somehow.
     Any,
     OctetStream,
     JavaScript,
     OGG,
+    Other(String),
 }
 
 impl Display for Application {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        write!(f, "{}", match self {
+            Application::Any => "*",
+            Application::OctetStream => "octet-stream",
+            Application::JavaScript => "javascript",
+            Application::OGG => "ogg",
+            Application::Other(subtype) => subtype,
+        })
     }
 }
 
 impl FromStr for Application {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
-            "octet-stream" => Ok(Application::OctetStream),
-            _ => Ok(Application::Any),
-        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "*" => Application::Any,
+            "octet-stream" => Application::OctetStream,
+            "javascript" => Application::JavaScript,
+            "ogg" => Application::OGG,
+            _ => Application::Other(s.to_string()),
+        })
     }
 }
 
 #[derive(Debug)]
-enum Multipart {
+pub enum Multipart {
     /// Multipart types indicate a category of document broken into pieces, often with different
     /// MIME types. They represent a composite document.
     Any,
     FormData,
     ByteRanges,
+    Other(String),
 }
 
 impl FromStr for Multipart {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        match s {
-            _ => Ok(Multipart::Any),
-        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "*" => Multipart::Any,
+            "form-data" => Multipart::FormData,
+            "byteranges" => Multipart::ByteRanges,
+            _ => Multipart::Other(s.to_string()),
+        })
     }
 }
 
 impl Display for Multipart {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        unimplemented!()
+        write!(f, "{}", match self {
+            Multipart::Any => "*",
+            Multipart::FormData => "form-data",
+            Multipart::ByteRanges => "byteranges",
+            Multipart::Other(subtype) => subtype,
+        })
     }
 }
 
+/// ## Content-Type
+/// [[RFC1945, Section 10.5](https://tools.ietf.org/html/rfc1945#section-10.5)]
+/// The media type of the Entity-Body. Parameters such as `charset` are
+/// not kept; read the raw field for those.
 #[derive(Debug)]
-enum ContentType {
+pub enum ContentType {
     None,
     Text(Text),
     Image(Image),
@@ -289,50 +396,121 @@ enum ContentType {
 }
 
 impl FromStr for ContentType {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        let mut types = s.splitn(2, "/");
-        Ok(match types.next() {
-            Some(t) => match t {
-                "text" => ContentType::Text(Text::from_str(types.next().unwrap()).unwrap()),
-                "image" => ContentType::Image(Image::from_str(types.next().unwrap()).unwrap()),
-                "audio" => ContentType::Audio(Audio::from_str(types.next().unwrap()).unwrap()),
-                "video" => ContentType::Video(Video::from_str(types.next().unwrap()).unwrap()),
-                "application" => ContentType::Application(Application::from_str(types.next().unwrap()).unwrap()),
-                "multipart" => ContentType::Multipart(Multipart::from_str(types.next().unwrap()).unwrap()),
-                _ => unimplemented!()
-            },
-            _ => ContentType::None,
+        let media_type = s.split(';').next().unwrap_or("").trim();
+        if media_type.is_empty() {
+            return Ok(ContentType::None);
+        }
+        let mut types = media_type.splitn(2, "/");
+        let t = types.next().unwrap_or("");
+        let subtype = types.next()
+            .map(|subtype| subtype.trim())
+            .filter(|subtype| is_token(subtype))
+            .ok_or_else(|| ParseError::invalid("Content-Type", format!("`{}` has no subtype", s)))?;
+        Ok(match t.to_ascii_lowercase().as_str() {
+            "text" => ContentType::Text(Text::from_str(subtype)?),
+            "image" => ContentType::Image(Image::from_str(subtype)?),
+            "audio" => ContentType::Audio(Audio::from_str(subtype)?),
+            "video" => ContentType::Video(Video::from_str(subtype)?),
+            "application" => ContentType::Application(Application::from_str(subtype)?),
+            "multipart" => ContentType::Multipart(Multipart::from_str(subtype)?),
+            _ => return Err(ParseError::invalid("Content-Type", format!("unknown media type `{}`", t))),
         })
     }
 }
 
-type Date = DateTime<Local>;
-type Expires = Date;
-// type From = String;
-type IfModifiedSince = Date;
-type LastModified = Date;
-type Location = String;
-type Referer = String;
-type Server = String;
-type UserAgent = String;
-type WWWAuthenticate = String;
-type Accept = ContentType;
+impl Display for ContentType {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            ContentType::None => Ok(()),
+            ContentType::Text(subtype) => write!(f, "text/{}", subtype),
+            ContentType::Image(subtype) => write!(f, "image/{}", subtype),
+            ContentType::Audio(subtype) => write!(f, "audio/{}", subtype),
+            ContentType::Video(subtype) => write!(f, "video/{}", subtype),
+            ContentType::Application(subtype) => write!(f, "application/{}", subtype),
+            ContentType::Multipart(subtype) => write!(f, "multipart/{}", subtype),
+        }
+    }
+}
 
-#[derive(Debug)]
-enum Charset {}
+pub type Date = DateTime<Local>;
+pub type Expires = Date;
+pub type IfModifiedSince = Date;
+pub type LastModified = Date;
+
+/// Parse an HTTP-date [[RFC7231, Section 7.1.1.1](https://tools.ietf.org/html/rfc7231#section-7.1.1.1)]
+/// in the preferred RFC 1123 format, `Sun, 06 Nov 1994 08:49:37 GMT`, or
+/// one of the obsolete RFC 850, `Sunday, 06-Nov-94 08:49:37 GMT`, and
+/// asctime, `Sun Nov  6 08:49:37 1994`, formats.
+pub fn parse_date(s: &str) -> Option<Date> {
+    let s = s.trim();
+    if let Ok(date) = DateTime::parse_from_rfc2822(s) {
+        return Some(date.with_timezone(&Local));
+    }
+    // the weekday is left out, as it depends on the century
+    let rfc850 = s.split_once(", ").map(|(_, date)| NaiveDateTime::parse_from_str(date, "%d-%b-%y %H:%M:%S GMT"));
+    if let Some(Ok(date)) = rfc850 {
+        // a two-digit year more than 50 years ahead is in the past
+        let this_year = Utc::now().year();
+        let mut year = this_year - this_year % 100 + date.year() % 100;
+        if year > this_year + 50 {
+            year -= 100;
+        }
+        return date.with_year(year).map(|date| Utc.from_utc_datetime(&date).with_timezone(&Local));
+    }
+    NaiveDateTime::parse_from_str(s, "%a %b %e %H:%M:%S %Y").ok()
+        .map(|date| Utc.from_utc_datetime(&date).with_timezone(&Local))
+}
+
+/// Format `date` as an RFC 1123 HTTP-date, always in GMT.
+pub fn format_date(date: &Date) -> String {
+    date.with_timezone(&Utc).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// ## Cache-Control
+/// [[RFC7234, Section 5.2](https://tools.ietf.org/html/rfc7234#section-5.2)]
+/// Directives for caches along the request/response chain, e.g.
+/// `max-age=60, stale-while-revalidate=30`.
+#[derive(Debug, Default)]
+pub struct CacheControl {
+    directives: Vec<(String, Option<String>)>,
+}
+
+impl CacheControl {
+    pub fn has(&self, name: &str) -> bool {
+        self.directives.iter().any(|(k, _)| k == name)
+    }
 
-type AcceptCharset = Charset;
-type AcceptEncoding = ContentType;
-type AcceptLanguage = String;
-type ContentLanguage = String;
-type Link = String;
-type MIMEVersion = String;
-type RetryAfter = String;
-type Title = String;
-type URI = String;
+    /// The delta-seconds argument of directive `name`.
+    pub fn seconds(&self, name: &str) -> Option<u64> {
+        self.directives.iter()
+            .find(|(k, _)| k == name)
+            .and_then(|(_, v)| v.as_ref())
+            .and_then(|v| u64::from_str(v).ok())
+    }
+}
 
+impl FromStr for CacheControl {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        let directives = s.split(',')
+            .map(|d| d.trim())
+            .filter(|d| !d.is_empty())
+            .map(|d| {
+                let mut cursor = d.splitn(2, '=');
+                let name = cursor.next().unwrap_or("").trim().to_lowercase();
+                let value = cursor.next().map(|v| v.trim().trim_matches('"').to_string());
+                (name, value)
+            })
+            .collect();
+        Ok(CacheControl {
+            directives
+        })
+    }
+}
 
 /// # Header Field Definitions
 /// [[RFC1945, Section 10](https://tools.ietf.org/html/rfc1945#section-10)]
@@ -340,7 +518,11 @@ type URI = String;
 /// HTTP/1.0 header fields. For general and entity header fields, both
 /// sender and recipient refer to either the client or the server,
 /// depending on who sends and who receives the message.
-#[derive(Debug, Eq, PartialEq)]
+///
+/// Field names are case-insensitive
+/// [[RFC7230, Section 3.2](https://tools.ietf.org/html/rfc7230#section-3.2)],
+/// so is comparing two `HeaderName`s.
+#[derive(Debug, Clone)]
 pub enum HeaderName {
     Allow,
     Authorization,
@@ -370,40 +552,340 @@ pub enum HeaderName {
     RetryAfter,
     Title,
     URI,
-    Other(&'static str)
-}
-
-impl From<&'static str> for HeaderName {
-    fn from(item: &'static str) -> Self {
-        match item {
-            "Allow" | "allow" => HeaderName::Allow,
-            "Authorization" | "authorization" => HeaderName::Authorization,
-            "Content-Encoding" | "content-encoding" => HeaderName::ContentEncoding,
-            "Content-Length" | "content-length" => HeaderName::ContentLength,
-            "Content-Type" | "content-type" => HeaderName::ContentType,
-            "Date" | "date" => HeaderName::Date,
-            "Expires" | "expires" => HeaderName::Expires,
+    /// Any other field, spelled as it was received or set
+    Other(String),
+}
+
+impl HeaderName {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HeaderName::Allow => "Allow",
+            HeaderName::Authorization => "Authorization",
+            HeaderName::ContentEncoding => "Content-Encoding",
+            HeaderName::ContentLength => "Content-Length",
+            HeaderName::ContentType => "Content-Type",
+            HeaderName::Date => "Date",
+            HeaderName::Expires => "Expires",
+            HeaderName::IfModifiedSince => "If-Modified-Since",
+            HeaderName::LastModified => "Last-Modified",
+            HeaderName::Location => "Location",
+            HeaderName::Referer => "Referer",
+            HeaderName::Server => "Server",
+            HeaderName::UserAgent => "User-Agent",
+            HeaderName::WWWAuthenticate => "WWW-Authenticate",
+            HeaderName::Accept => "Accept",
+            HeaderName::AcceptCharset => "Accept-Charset",
+            HeaderName::AcceptEncoding => "Accept-Encoding",
+            HeaderName::AcceptLanguage => "Accept-Language",
+            HeaderName::ContentLanguage => "Content-Language",
+            HeaderName::Link => "Link",
+            HeaderName::MIMEVersion => "MIME-Version",
+            HeaderName::RetryAfter => "Retry-After",
+            HeaderName::Title => "Title",
+            HeaderName::URI => "URI",
+            HeaderName::Other(name) => name,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for HeaderName {
+    fn from(item: &'a str) -> Self {
+        match item.to_ascii_lowercase().as_str() {
+            "allow" => HeaderName::Allow,
+            "authorization" => HeaderName::Authorization,
+            "content-encoding" => HeaderName::ContentEncoding,
+            "content-length" => HeaderName::ContentLength,
+            "content-type" => HeaderName::ContentType,
+            "date" => HeaderName::Date,
+            "expires" => HeaderName::Expires,
             // "from" => HeaderName::From,
-            "If-Modified-Since" | "if-modified-since" => HeaderName::IfModifiedSince,
-            "Last-Modified" | "last-modified" => HeaderName::LastModified,
-            "Location" | "location" => HeaderName::Location,
-            "Referer" | "referer" => HeaderName::Referer,
-            "Server" | "server" => HeaderName::Server,
-            "User-Agent" | "user-agent" => HeaderName::UserAgent,
-            "WWW-Authenticate" | "www-authenticate" => HeaderName::WWWAuthenticate,
-            "Accept" | "accept" => HeaderName::Accept,
-            "Accept-Charset" | "accept-charset" => HeaderName::AcceptCharset,
-            "Accept-Encoding" | "accept-encoding" => HeaderName::AcceptEncoding,
-            "Accept-Language" | "accept-language" => HeaderName::AcceptLanguage,
-            "Content-Language" | "content-language" => HeaderName::ContentLanguage,
-            "Link" | "link" => HeaderName::Link,
-            "MIME-Version" | "mime-version" => HeaderName::MIMEVersion,
-            "Retry-After" | "retry-after" => HeaderName::RetryAfter,
-            "Title" | "title" => HeaderName::Title,
-            "Uri" | "uri" => HeaderName::URI,
-            e => HeaderName::Other(e),
+            "if-modified-since" => HeaderName::IfModifiedSince,
+            "last-modified" => HeaderName::LastModified,
+            "location" => HeaderName::Location,
+            "referer" => HeaderName::Referer,
+            "server" => HeaderName::Server,
+            "user-agent" => HeaderName::UserAgent,
+            "www-authenticate" => HeaderName::WWWAuthenticate,
+            "accept" => HeaderName::Accept,
+            "accept-charset" => HeaderName::AcceptCharset,
+            "accept-encoding" => HeaderName::AcceptEncoding,
+            "accept-language" => HeaderName::AcceptLanguage,
+            "content-language" => HeaderName::ContentLanguage,
+            "link" => HeaderName::Link,
+            "mime-version" => HeaderName::MIMEVersion,
+            "retry-after" => HeaderName::RetryAfter,
+            "title" => HeaderName::Title,
+            "uri" => HeaderName::URI,
+            _ => HeaderName::Other(item.to_string()),
+        }
+    }
+}
+
+impl From<String> for HeaderName {
+    fn from(item: String) -> Self {
+        HeaderName::from(item.as_str())
+    }
+}
+
+impl FromStr for HeaderName {
+    type Err = ParseError;
+
+    /// A field name is a token [[RFC7230, Section 3.2](https://tools.ietf.org/html/rfc7230#section-3.2)].
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        if is_token(s) {
+            Ok(HeaderName::from(s))
+        } else {
+            Err(ParseError::invalid("field-name", format!("`{}` is not a token", s.escape_debug())))
+        }
+    }
+}
+
+impl AsRef<str> for HeaderName {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for HeaderName {
+    fn eq(&self, other: &HeaderName) -> bool {
+        self.as_str().eq_ignore_ascii_case(other.as_str())
+    }
+}
+
+impl Eq for HeaderName {}
+
+impl Hash for HeaderName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.as_str().bytes() {
+            state.write_u8(b.to_ascii_lowercase());
+        }
+    }
+}
+
+impl Display for HeaderName {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The header fields of a message, in the order they were received or
+/// set. A field may be repeated, e.g. `Set-Cookie`; lookups by name
+/// ignore case.
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    fields: Vec<(HeaderName, String)>,
+}
+
+impl Header {
+    pub fn new() -> Self {
+        Header {
+            fields: Vec::new(),
+        }
+    }
+
+    /// The value of the first field called `name`.
+    pub fn get<N: AsRef<str>>(&self, name: N) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// The values of all fields called `name`, in order.
+    pub fn get_all<N: AsRef<str>>(&self, name: N) -> impl Iterator<Item=&str> {
+        self.fields.iter()
+            .filter(move |(k, _)| k.as_str().eq_ignore_ascii_case(name.as_ref()))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains<N: AsRef<str>>(&self, name: N) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set field `name` to `value`, replacing any fields of that name.
+    pub fn insert<N: Into<HeaderName>, V: Into<String>>(&mut self, name: N, value: V) {
+        let name = name.into();
+        self.remove(&name);
+        self.fields.push((name, value.into()));
+    }
+
+    /// Add a field, keeping any fields of the same name.
+    pub fn append<N: Into<HeaderName>, V: Into<String>>(&mut self, name: N, value: V) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    pub fn remove<N: AsRef<str>>(&mut self, name: N) {
+        self.fields.retain(|(k, _)| !k.as_str().eq_ignore_ascii_case(name.as_ref()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&HeaderName, &str)> {
+        self.fields.iter().map(|(k, v)| (k, v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Parse the first field called `name`; `None` if there is none.
+    pub fn typed<T: FromStr<Err=ParseError>, N: AsRef<str>>(&self, name: N) -> Result<Option<T>, ParseError> {
+        self.get(name).map(T::from_str).transpose()
+    }
+
+    pub fn allow(&self) -> Result<Option<Allow>, ParseError> {
+        self.typed(HeaderName::Allow)
+    }
+
+    pub fn authorization(&self) -> Result<Option<Authorization>, ParseError> {
+        self.typed(HeaderName::Authorization)
+    }
+
+    pub fn cache_control(&self) -> Result<Option<CacheControl>, ParseError> {
+        self.typed("Cache-Control")
+    }
+
+    pub fn content_encoding(&self) -> Result<Option<ContentEncoding>, ParseError> {
+        self.typed(HeaderName::ContentEncoding)
+    }
+
+    /// The Content-Length; differing repeated values are an error
+    /// [[RFC7230, Section 3.3.2](https://tools.ietf.org/html/rfc7230#section-3.3.2)].
+    pub fn content_length(&self) -> Result<Option<ContentLength>, ParseError> {
+        let mut content_length = None;
+        for value in self.get_all(HeaderName::ContentLength) {
+            // 1*DIGIT: no sign, which `parse` would take
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::invalid("Content-Length", format!("`{}` is not a length", value)));
+            }
+            let n = value.parse::<ContentLength>()
+                .map_err(|_| ParseError::invalid("Content-Length", format!("`{}` is not a length", value)))?;
+            if content_length.is_some_and(|m| m != n) {
+                return Err(ParseError::invalid("Content-Length", "differing values"));
+            }
+            content_length = Some(n);
+        }
+        Ok(content_length)
+    }
+
+    pub fn content_type(&self) -> Result<Option<ContentType>, ParseError> {
+        self.typed(HeaderName::ContentType)
+    }
+
+    pub fn date(&self) -> Result<Option<Date>, ParseError> {
+        self.date_field(HeaderName::Date)
+    }
+
+    pub fn expires(&self) -> Result<Option<Expires>, ParseError> {
+        self.date_field(HeaderName::Expires)
+    }
+
+    pub fn if_modified_since(&self) -> Result<Option<IfModifiedSince>, ParseError> {
+        self.date_field(HeaderName::IfModifiedSince)
+    }
+
+    pub fn last_modified(&self) -> Result<Option<LastModified>, ParseError> {
+        self.date_field(HeaderName::LastModified)
+    }
+
+    fn date_field(&self, name: HeaderName) -> Result<Option<Date>, ParseError> {
+        match self.get(&name) {
+            Some(value) => parse_date(value)
+                .map(Some)
+                .ok_or_else(|| ParseError::invalid("HTTP-date", format!("{}: `{}` is not an RFC 1123 date", name, value))),
+            None => Ok(None),
         }
     }
 }
 
-pub type Header = HashMap<String, String>;
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_formats() {
+        let expected = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        for s in &["Sun, 06 Nov 1994 08:49:37 GMT", "Sunday, 06-Nov-94 08:49:37 GMT", "Sun Nov  6 08:49:37 1994"] {
+            assert_eq!(parse_date(s).map(|d| d.with_timezone(&Utc)), Some(expected), "{}", s);
+        }
+        assert_eq!(parse_date("06/11/1994"), None);
+    }
+
+    #[test]
+    fn parse_date_rfc850_year() {
+        let this_year = Utc::now().year();
+        let date = |yy: i32| parse_date(&format!("Sunday, 06-Nov-{:02} 08:49:37 GMT", yy)).unwrap().with_timezone(&Utc);
+        // never more than 50 years ahead
+        for yy in 0..100 {
+            let year = date(yy).year();
+            assert_eq!(year % 100, yy);
+            assert!(year <= this_year + 50 && year > this_year - 50, "{}", year);
+        }
+    }
+
+    #[test]
+    fn format_date_roundtrip() {
+        let date = parse_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(format_date(&date), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn lookups_ignore_case() {
+        let mut header = Header::new();
+        header.append("content-TYPE", "text/plain");
+        header.append("X-Custom", "1");
+        assert_eq!(header.get("Content-Type"), Some("text/plain"));
+        assert_eq!(header.get(HeaderName::ContentType), Some("text/plain"));
+        assert_eq!(header.get("x-custom"), Some("1"));
+        assert!(header.contains("X-CUSTOM"));
+        assert!(!header.contains("X-Other"));
+
+        // known names are spelled canonically, others as given
+        let names: Vec<String> = header.iter().map(|(k, _)| k.to_string()).collect();
+        assert_eq!(names, vec!["Content-Type", "X-Custom"]);
+        assert_eq!(HeaderName::from("x-custom"), HeaderName::from("X-Custom"));
+        assert_ne!(HeaderName::from("X-Custom"), HeaderName::from("X-Custom2"));
+        assert_eq!(HeaderName::from("LOCATION"), HeaderName::Location);
+        assert!("Bad Name".parse::<HeaderName>().is_err());
+        assert!("".parse::<HeaderName>().is_err());
+    }
+
+    #[test]
+    fn append_keeps_and_insert_replaces() {
+        let mut header = Header::new();
+        header.append("Set-Cookie", "a=1");
+        header.append("Vary", "Accept");
+        header.append("set-cookie", "b=2");
+        assert_eq!(header.len(), 3);
+        assert_eq!(header.get("Set-Cookie"), Some("a=1"));
+        assert_eq!(header.get_all("SET-COOKIE").collect::<Vec<_>>(), vec!["a=1", "b=2"]);
+
+        header.insert("SET-cookie", "c=3");
+        assert_eq!(header.get_all("Set-Cookie").collect::<Vec<_>>(), vec!["c=3"]);
+        let fields: Vec<(String, &str)> = header.iter().map(|(k, v)| (k.to_string(), v)).collect();
+        assert_eq!(fields, vec![("Vary".to_string(), "Accept"), ("SET-cookie".to_string(), "c=3")]);
+
+        header.remove("vary");
+        header.remove("set-COOKIE");
+        assert!(header.is_empty());
+    }
+
+    #[test]
+    fn typed_fields() {
+        let mut header = Header::new();
+        assert_eq!(header.content_length().unwrap(), None);
+        header.append("Content-Length", "42");
+        header.append("content-length", "42");
+        assert_eq!(header.content_length().unwrap(), Some(42));
+        header.append("Content-Length", "43");
+        assert!(header.content_length().is_err());
+        for value in &["", "+42", "-1", "4 2", "0x10"] {
+            header.insert("Content-Length", *value);
+            assert!(header.content_length().is_err(), "{}", value);
+        }
+
+        header.insert("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(header.last_modified().unwrap().is_some());
+        header.insert("Last-Modified", "yesterday");
+        assert!(header.last_modified().is_err());
+    }
+}
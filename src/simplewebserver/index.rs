@@ -19,33 +19,54 @@
 // CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+//! Directory listing
 use std::fmt::Display;
 use std::fmt::Error;
 use std::fmt::Formatter;
 use std::fs::DirEntry;
+use std::io;
 use std::path::Path;
 
+use super::root::Root;
+
 trait Element {
-    fn to_html(&self) -> String;
+    fn to_html(&self, base: &str) -> String;
 }
 
 impl Element for DirEntry {
-    fn to_html(&self) -> String {
-        format!("<li><a href=\"{path}\">{filename}</a></li>",
-                path = self.path().to_str().unwrap(),
-                filename = self.file_name().to_str().unwrap())
+    fn to_html(&self, base: &str) -> String {
+        let filename = self.file_name().to_string_lossy().into_owned();
+        let suffix = if self.path().is_dir() { "/" } else { "" };
+        format!("<li><a href=\"{base}{path}{suffix}\">{filename}{suffix}</a></li>",
+                base = base,
+                path = escape(&filename),
+                suffix = suffix,
+                filename = escape(&filename))
     }
 }
 
+/// Minimal HTML escaping for names coming from the file system.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 struct Index<'a> {
-    path: &'a Path
+    base: &'a str,
+    entries: Vec<DirEntry>,
 }
 
 impl<'a> Display for Index<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        f.write_str("<ul>").unwrap();
-        for p in self.path.read_dir().unwrap().map(|p| p.unwrap()) {
-            f.write_fmt(format_args!("{}", p.to_html())).unwrap();
+        write!(f, "<h1>Index of {}</h1>", escape(self.base))?;
+        f.write_str("<ul>")?;
+        if self.base != "/" {
+            f.write_str("<li><a href=\"../\">../</a></li>")?;
+        }
+        for p in &self.entries {
+            f.write_str(&p.to_html(self.base))?;
         }
         f.write_str("</ul>")
     }
@@ -57,18 +78,25 @@ struct Page<T> where T: Display {
 
 impl<T> Display for Page<T> where T: Display {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        f.write_str("<!DOCTYPE html>");
-        f.write_str("<html>");
-        f.write_fmt(format_args!("{}", self.ele));
+        f.write_str("<!DOCTYPE html>")?;
+        f.write_str("<html>")?;
+        f.write_fmt(format_args!("{}", self.ele))?;
         f.write_str("</html>")
     }
 }
 
-fn main() {
-    let page: Page<Index> = Page {
+/// Render the listing of `path`, linking entries relative to the request
+/// path `base` (which must end with `/`). Entries `root` does not serve
+/// are left out.
+pub fn render(root: &Root, path: &Path, base: &str) -> io::Result<String> {
+    let mut entries = path.read_dir()?.collect::<io::Result<Vec<DirEntry>>>()?;
+    entries.retain(|e| root.is_visible(&e.file_name().to_string_lossy()));
+    entries.sort_by_key(|e| e.file_name());
+    let page = Page {
         ele: Index {
-            path: Path::new(".")
+            base,
+            entries,
         }
     };
-    println!("{}", page)
-}
\ No newline at end of file
+    Ok(page.to_string())
+}
@@ -0,0 +1,131 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Listening sockets
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::config::Config;
+use super::handle_client;
+
+/// The configuration currently in effect. Every connection takes its own
+/// snapshot when it is accepted, so swapping the inner `Arc` on reload
+/// only affects new connections.
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+/// How long an idle listener sleeps before polling for connections again.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Most connections a listener serves at once; more clients wait in the
+/// backlog until one is done.
+const MAX_CONNECTIONS: usize = 1024;
+
+pub struct Listener {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    worker: JoinHandle<()>,
+}
+
+impl Listener {
+    /// Bind `addr` and start accepting connections on a worker thread.
+    pub fn bind(addr: SocketAddr, config: SharedConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_ttl(10)?;
+        // non-blocking, so the worker notices when it is asked to stop
+        listener.set_nonblocking(true)?;
+        info!("Listening on {}", addr);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let stop = stop.clone();
+            thread::spawn(move || accept(listener, addr, config, stop))
+        };
+        Ok(Listener {
+            addr,
+            stop,
+            worker,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting connections. Those in progress are served to the end
+    /// on their own threads.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.worker.join();
+        info!("Stopped listening on {}", self.addr);
+    }
+}
+
+// accept connections and serve each on a thread of its own, so that a
+// slow client only holds up itself
+fn accept(listener: TcpListener, addr: SocketAddr, config: SharedConfig, stop: Arc<AtomicBool>) {
+    let active = Arc::new(AtomicUsize::new(0));
+    while !stop.load(Ordering::SeqCst) {
+        if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            thread::sleep(ACCEPT_INTERVAL);
+            continue;
+        }
+        match listener.accept() {
+            Ok((mut stream, _)) => {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    warn!("{}", e);
+                    continue;
+                }
+                let config = config.read().unwrap().clone();
+                let active = Active::new(&active);
+                let spawned = thread::Builder::new().spawn(move || {
+                    let _active = active;
+                    handle_client(&config, addr, &mut stream);
+                });
+                if let Err(e) = spawned {
+                    warn!("{}", e);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Counts a connection as being served until it is dropped.
+struct Active(Arc<AtomicUsize>);
+
+impl Active {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Active(count.clone())
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
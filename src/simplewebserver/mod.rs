@@ -19,48 +19,322 @@
 // CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use core::borrow::Borrow;
-use std::io::{Result, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::io::{BufReader, BufWriter, Result, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
+use signal_hook;
+
+use self::access_log::AccessLog;
+use self::config::Config;
+use self::error::ParseError;
+use self::h2::Transport;
+use self::listener::{Listener, SharedConfig};
+use self::request::RequestLimits;
+use self::status::StatusCode;
+use self::tls::Tls;
+
+pub mod access_log;
+pub mod cache;
+pub mod cgi;
 pub mod common;
 pub mod status;
 pub mod header;
 pub mod request;
 pub mod response;
+pub mod root;
 pub mod index;
+pub mod config;
+pub mod error;
+pub mod fastcgi;
+pub mod forward;
+pub mod h2;
+pub mod router;
+pub mod handler;
+pub mod listener;
+pub mod proxy;
+pub mod sse;
+pub mod tls;
+pub mod upstream;
+pub mod uri;
+pub mod websocket;
 
 
-fn handle_client(stream: &mut TcpStream) {
-    let req = request::Request::from(stream.borrow());
+/// Serve a connection accepted on `local`, one of the listen addresses.
+pub fn handle_client(config: &Arc<Config>, local: SocketAddr, stream: &mut TcpStream) {
+    let peer = stream.peer_addr().ok();
+    set_timeouts(stream, &config.limits);
+    if let Some(proxy) = &config.forward_proxy {
+        if proxy.listen.contains(&local) {
+            let mut reader = BufReader::new(&*stream);
+            let req = match request::Request::read_from(&mut reader, peer, Some(local), &config.limits,
+                                                        request::Parsing::Lenient) {
+                Ok(req) => req,
+                Err(e) => return reject(&*stream, e),
+            };
+            debug!("{:?}", req);
+            let received = reader.buffer().to_vec();
+            return forward::handle(proxy, &config.limits, req, received, stream);
+        }
+    }
+    if let Some(tls) = config.tls.get(&local) {
+        return handle_tls(config, tls, stream);
+    }
+
+    let strict = config.strict_http10.contains(&local);
+    match if strict { Ok(false) } else { h2::has_preface(stream) } {
+        Ok(true) => {
+            // HTTP/2 connections idle between requests on their own thread
+            let _ = stream.set_read_timeout(None);
+            match stream.try_clone() {
+                Ok(stream) => h2::start(config.clone(), Box::new(stream), peer, Some(local), None, Vec::new()),
+                Err(e) => warn!("{}", e),
+            }
+            return;
+        }
+        Ok(false) => {}
+        // nothing arrived within the header timeout
+        Err(e) => return reject(&*stream, e.into()),
+    }
+
+    let mut reader = BufReader::new(&*stream);
+    let req = match request::Request::read_from(&mut reader, peer, Some(local), &config.limits,
+                                                config.parsing(local)) {
+        Ok(req) => req,
+        Err(e) => return reject(&*stream, e),
+    };
     debug!("{:?}", req);
+    // pipelined after the request, for whoever takes the connection over
+    let received = reader.buffer().to_vec();
+
+    if let Some(settings) = h2::upgrade_settings(&req).filter(|_| !strict) {
+        send(&*stream, h2::switching_protocols(), false);
+        let _ = stream.set_read_timeout(None);
+        match stream.try_clone() {
+            Ok(stream) => h2::start(config.clone(), Box::new(stream), peer, Some(local), Some((req, settings)), received),
+            Err(e) => warn!("{}", e),
+        }
+        return;
+    }
 
-    // Write the header and the html body
-    let res = response::Response::default();
+    let (mut res, access_log) = respond(config, &req);
+    if req.version() < common::HTTPVersion::new(1, 0) {
+        res.set_version(req.version());
+    }
+    let status_code = res.status_code();
+    let handoff = res.take_handoff();
+    let sent = send(&*stream, res, *req.method() == request::Method::HEAD);
+    if let Some(log) = access_log {
+        log.log(req.peer_addr(), &req, status_code, sent);
+    }
+    if let Some(handoff) = handoff {
+        // the connection lives on on its own thread, not counted against
+        // the listener's connections, and may stay quiet for as long as it
+        // likes
+        let _ = stream.set_read_timeout(None);
+        match stream.try_clone() {
+            Ok(stream) => {
+                thread::spawn(move || handoff(response::Connection::new(stream), received));
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Serve a connection accepted on a TLS listener, with HTTP/2 if the
+/// client offered it.
+fn handle_tls(config: &Arc<Config>, tls: &Tls, stream: &TcpStream) {
+    let peer = stream.peer_addr().ok();
+    let local = stream.local_addr().ok();
+    let stream = match stream.try_clone().and_then(|s| tls.accept(s)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("TLS handshake with {:?} failed: {}", peer, e);
+            return;
+        }
+    };
+    if stream.alpn().as_deref() == Some(tls::H2) {
+        let _ = stream.socket().set_read_timeout(None);
+        return h2::start(config.clone(), Box::new(stream), peer, local, None, Vec::new());
+    }
+
+    let parsing = local.map_or(request::Parsing::default(), |local| config.parsing(local));
+    let mut reader = BufReader::new(&stream);
+    let mut req = match request::Request::read_from(&mut reader, peer, local, &config.limits, parsing) {
+        Ok(req) => req,
+        Err(e) => {
+            reject(&stream, e);
+            return stream.close();
+        }
+    };
+    let received = reader.buffer().to_vec();
+    req.set_secure(true);
+    debug!("{:?}", req);
+    let (mut res, access_log) = respond(config, &req);
+    let status_code = res.status_code();
+    let handoff = res.take_handoff();
+    let sent = send(&stream, res, *req.method() == request::Method::HEAD);
+    if let Some(log) = access_log {
+        log.log(req.peer_addr(), &req, status_code, sent);
+    }
+    match handoff {
+        // as on a plain connection, on a thread of its own
+        Some(handoff) => {
+            let _ = stream.socket().set_read_timeout(None);
+            thread::spawn(move || handoff(response::Connection::new(stream), received));
+        }
+        None => stream.close(),
+    }
+}
+
+/// Bound how long a client may take to start a request and to take the
+/// response. The request parser then narrows the read timeout to the time
+/// left in each phase.
+fn set_timeouts(stream: &TcpStream, limits: &RequestLimits) {
+    if let Err(e) = stream.set_read_timeout(Some(limits.header_timeout)).and_then(|_| stream.set_write_timeout(Some(limits.send_timeout))) {
+        warn!("{}", e);
+    }
+}
+
+/// Answer a request that could not be parsed, unless the connection
+/// itself failed.
+fn reject<W: Write>(stream: W, e: ParseError) {
+    debug!("invalid request: {}", e);
+    if let Some(status_code) = e.status_code() {
+        send(stream, response::Response::error(status_code), false);
+    }
+}
+
+/// Route `req` to its server and location, whichever protocol it came
+/// with. Returns the response and the server's access log.
+pub fn respond<'a>(config: &'a Config, req: &request::Request) -> (response::Response, Option<&'a AccessLog>) {
+    let server = router::find_server(config, req);
+    let strict = req.local_addr().is_some_and(|local| config.strict_http10.contains(&local));
+    let checked = if strict { req.check_rfc1945() } else { Ok(()) };
+    let res = if let Err(status_code) = checked {
+        response::Response::error(status_code)
+    } else if req.version() < common::HTTPVersion::new(1, 0) && *req.method() != request::Method::GET {
+        // only GET can be a Simple-Request [RFC1945, Section 5]
+        response::Response::error(StatusCode::BadRequest)
+    } else if req.version() >= common::HTTPVersion::new(1, 1) && req.header("Host").is_none() {
+        // [RFC2616, Section 14.23]
+        response::Response::error(StatusCode::BadRequest)
+    } else if *req.method() == request::Method::CONNECT {
+        // only the forward proxy opens tunnels
+        response::Response::error(StatusCode::NotImplemented)
+    } else {
+        match router::find_location(server, req.path()) {
+            Some(location) => handler::handle(location, &config.limits, req),
+            None => response::Response::error(StatusCode::NotFound),
+        }
+    };
+    (res, server.access_log.as_ref())
+}
+
+/// Write `res` to the client, returning the number of body bytes sent.
+/// `head` leaves the body out, as the answer to a HEAD request.
+pub fn send<W: Write>(stream: W, res: response::Response, head: bool) -> u64 {
     debug!("{:?}", res);
-    stream.write_fmt(format_args!("{}", res));
+    let mut w = BufWriter::new(stream);
+    match res.write_to(&mut w, head).and_then(|sent| w.flush().map(|_| sent)) {
+        Ok(sent) => sent,
+        Err(e) => {
+            warn!("{}", e);
+            0
+        }
+    }
 }
 
 pub struct App {
-    addr: SocketAddr,
+    config: SharedConfig,
+    config_path: Option<PathBuf>,
 }
 
 impl App {
-    pub fn new(addr: SocketAddr) -> Self {
+    pub fn new(config: Config) -> Self {
         App {
-            addr
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            config_path: None,
         }
     }
 
+    /// Re-read the configuration from `path` whenever SIGHUP is received.
+    pub fn reload_from(mut self, path: PathBuf) -> Self {
+        self.config_path = Some(path);
+        self
+    }
+
     pub fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(self.addr)?;
-        info!("Listening on {}", self.addr);
-        listener.set_ttl(10)?;
+        let mut listeners = Vec::new();
+        for addr in self.config.read().unwrap().addrs() {
+            listeners.push(Listener::bind(addr, self.config.clone())?);
+        }
+
+        let reload = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::SIGHUP, reload.clone())?;
+        loop {
+            thread::sleep(Duration::from_millis(100));
+            if reload.swap(false, Ordering::SeqCst) {
+                self.reload(&mut listeners);
+            }
+        }
+    }
+
+    /// Load and validate the configuration file, swap it in and only then
+    /// bind the listeners it adds, so they never serve a connection with
+    /// the old one. If one cannot be bound, the old configuration and
+    /// listeners are put back.
+    fn reload(&self, listeners: &mut Vec<Listener>) {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => {
+                warn!("SIGHUP received, but no configuration file was given");
+                return;
+            }
+        };
+        info!("Reloading configuration from {}", path.display());
+        let current = self.config.read().unwrap().clone();
+        let config = match Config::reload(path, &current) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("invalid configuration, keeping the current one: {}", e);
+                return;
+            }
+        };
 
-        // accept connections and process them serially
-        for stream in listener.incoming() {
-            handle_client(&mut stream?)
+        let addrs = config.addrs();
+        let previous = mem::replace(&mut *self.config.write().unwrap(), Arc::new(config));
+        let mut added = Vec::new();
+        for addr in &addrs {
+            if listeners.iter().any(|l| l.addr() == *addr) {
+                continue;
+            }
+            match Listener::bind(*addr, self.config.clone()) {
+                Ok(listener) => added.push(listener),
+                Err(e) => {
+                    error!("cannot listen on {}, keeping the current configuration: {}", addr, e);
+                    *self.config.write().unwrap() = previous;
+                    for listener in added {
+                        listener.stop();
+                    }
+                    return;
+                }
+            }
         }
-        Ok(())
+
+        let (kept, removed): (Vec<_>, Vec<_>) = mem::take(listeners)
+            .into_iter()
+            .partition(|l| addrs.contains(&l.addr()));
+        for listener in removed {
+            listener.stop();
+        }
+        listeners.extend(kept);
+        listeners.extend(added);
+        info!("Configuration reloaded");
     }
-}
\ No newline at end of file
+}
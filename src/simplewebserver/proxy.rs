@@ -0,0 +1,662 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Reverse proxy
+use core::fmt;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::common::HTTPVersion;
+use super::request::{Method, Request, RequestLimits};
+use super::response::Response;
+use super::status::StatusCode;
+use super::upstream::{Connection, Lease, Pool};
+
+/// Header fields that only apply to a single connection and must not be
+/// forwarded [[RFC2616, Section 13.5.1](https://tools.ietf.org/html/rfc2616#section-13.5.1)].
+static HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Longest chunk-size line or trailer field accepted in a chunked body.
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Header fields making a request conditional or partial.
+static CONDITIONAL: &[&str] = &[
+    "If-Match",
+    "If-None-Match",
+    "If-Modified-Since",
+    "If-Unmodified-Since",
+    "If-Range",
+    "Range",
+];
+
+/// An upstream server, written as `http://host:port/path`. The path is
+/// prepended to the Request-URI of forwarded requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        if !s.starts_with("http://") {
+            return Err(format!("`{}` must be an `http://` URL", s));
+        }
+        let rest = &s["http://".len()..];
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority.ends_with(']') => (&authority[..i], u16::from_str(&authority[i + 1..])
+                .map_err(|_| format!("`{}` has an invalid port", s))?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("`{}` has no host", s));
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Upstream {
+    fn authority(&self) -> String {
+        if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Forward `req` to a peer of `pool` and relay its response. A peer that
+/// cannot be connected to is counted as failed and the next one is tried;
+/// an idempotent request failing on a reused keep-alive connection, which
+/// the peer may have closed meanwhile, is sent again on a new connection.
+/// Failing to reach any peer answers 502 Bad Gateway, running out of
+/// `timeout` while connecting or waiting for the response head 504
+/// Gateway Timeout, and a response head beyond the sizes in `limits` 502.
+pub fn forward(pool: &Arc<Pool>, timeout: Duration, limits: &RequestLimits, req: &Request) -> Response {
+    forward_with(pool, timeout, limits, req, None)
+}
+
+/// Forward `req` on behalf of a cache: the client's conditional and
+/// `Range` header fields are replaced by `validators`, so the response is
+/// either complete or a 304 Not Modified for the cached entry.
+pub fn forward_for_cache(pool: &Arc<Pool>, timeout: Duration, limits: &RequestLimits, req: &Request,
+                         validators: &[(&str, String)]) -> Response {
+    forward_with(pool, timeout, limits, req, Some(validators))
+}
+
+fn forward_with(pool: &Arc<Pool>, timeout: Duration, limits: &RequestLimits, req: &Request,
+                validators: Option<&[(&str, String)]>) -> Response {
+    let keep_alive = pool.keep_alive.max_idle > 0;
+    let mut tried = Vec::new();
+    let mut last_error = None;
+    while let Some(lease) = Pool::lease(pool, req, &tried) {
+        tried.push(lease.index());
+        let (conn, reused) = match lease.connect(timeout) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("{}: {}", lease.upstream(), e);
+                lease.failure();
+                last_error = Some(e);
+                continue;
+            }
+        };
+        let result = match exchange(conn, lease.upstream(), keep_alive, validators, limits, req) {
+            Err(ref e) if reused && req.method().is_idempotent() && e.kind() != io::ErrorKind::TimedOut
+                && e.kind() != io::ErrorKind::WouldBlock => {
+                debug!("{}: retrying on a new connection: {}", lease.upstream(), e);
+                lease.connect_new(timeout).and_then(|conn| exchange(conn, lease.upstream(), keep_alive, validators, limits, req))
+            }
+            result => result,
+        };
+        return match result {
+            Ok((mut res, body)) => {
+                lease.success();
+                res.set_stream(UpstreamBody {
+                    body: Some(body),
+                    lease,
+                });
+                res
+            }
+            Err(e) => {
+                warn!("{}: {}", lease.upstream(), e);
+                lease.failure();
+                gateway_error(&e)
+            }
+        };
+    }
+    match last_error {
+        Some(e) => gateway_error(&e),
+        None => {
+            warn!("no live upstream in `{}`", pool.name);
+            Response::error(StatusCode::BadGateway)
+        }
+    }
+}
+
+/// 504 for an upstream that timed out, 502 for any other failure.
+pub fn gateway_error(e: &io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Response::error(StatusCode::GatewayTimeout),
+        _ => Response::error(StatusCode::BadGateway),
+    }
+}
+
+/// Send `req` over `conn` and read the response head, returning the
+/// response and its body.
+fn exchange(mut conn: Connection, upstream: &Upstream, keep_alive: bool,
+            validators: Option<&[(&str, String)]>, limits: &RequestLimits, req: &Request) -> io::Result<(Response, Framed)> {
+    {
+        let mut w = io::BufWriter::new(conn.stream.get_ref());
+        write_request(&mut w, upstream, keep_alive, validators, req.scheme(), req)?;
+        w.flush()?;
+    }
+
+    // interim responses are not relayed [RFC7231, Section 6.2]
+    let (version, status_code, reason_phrase, header) = loop {
+        let head = read_head(&mut conn.stream, limits)?;
+        match head.1 {
+            Some(code) if code.is_informational() && code != StatusCode::SwitchingProtocols => continue,
+            _ => break head,
+        }
+    };
+    let status_code = match status_code {
+        Some(status_code) => status_code,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported status code")),
+    };
+
+    let mut res = Response::new(status_code);
+    res.set_reason_phrase(&reason_phrase);
+    let connection = connection_tokens(header.iter().find(|(k, _)| k.eq_ignore_ascii_case("Connection")).map(|(_, v)| v.as_str()));
+    // the codings of every Transfer-Encoding field, in order
+    let codings: Vec<String> = header.iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|c| c.trim().to_lowercase())
+        .filter(|c| !c.is_empty())
+        .collect();
+    for (k, v) in header {
+        if is_hop_by_hop(&k, &connection) {
+            continue;
+        }
+        // a Transfer-Encoding overrides Content-Length, which must not be
+        // passed on with a body framed otherwise [RFC7230, Section 3.3.3]
+        if !codings.is_empty() && k.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        res.append_header(k, v);
+    }
+
+    // persistent by default from HTTP/1.1 on [RFC2616, Section 8.1.2.1]
+    let reusable = keep_alive && if version >= HTTPVersion::new(1, 1) {
+        !connection.iter().any(|c| c == "close")
+    } else {
+        connection.iter().any(|c| c == "keep-alive")
+    };
+    let body = match status_code {
+        // [RFC2616, Section 4.4]
+        StatusCode::NoContent | StatusCode::NotModified => Framed::Length(conn, 0, reusable),
+        _ if *req.method() == Method::HEAD => Framed::Length(conn, 0, reusable),
+        // chunked last, or else the body runs to the end of the connection
+        _ if codings.last().is_some_and(|c| c == "chunked") => Framed::Chunked(Chunked::new(conn), reusable),
+        _ if !codings.is_empty() => Framed::Close(conn),
+        _ => match res.header("Content-Length").and_then(|l| u64::from_str(l).ok()) {
+            Some(length) => Framed::Length(conn, length, reusable),
+            None => Framed::Close(conn),
+        },
+    };
+    Ok((res, body))
+}
+
+/// How the end of an upstream response body is found
+/// [[RFC2616, Section 4.4](https://tools.ietf.org/html/rfc2616#section-4.4)],
+/// and whether the connection may be reused afterwards.
+enum Framed {
+    Length(Connection, u64, bool),
+    Chunked(Chunked<Connection>, bool),
+    Close(Connection),
+}
+
+/// A response body keeping its peer counted as active until it has been
+/// relayed, returning the connection to the pool once it is complete.
+struct UpstreamBody {
+    body: Option<Framed>,
+    lease: Lease,
+}
+
+impl Read for UpstreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match &mut self.body {
+            Some(Framed::Length(conn, remaining, _)) => {
+                if *remaining == 0 {
+                    0
+                } else {
+                    let max = buf.len().min(*remaining as usize);
+                    let n = conn.stream.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated body"));
+                    }
+                    *remaining -= n as u64;
+                    n
+                }
+            }
+            Some(Framed::Chunked(chunked, _)) => chunked.read(buf)?,
+            Some(Framed::Close(conn)) => conn.stream.read(buf)?,
+            None => 0,
+        };
+        if n == 0 {
+            match self.body.take() {
+                Some(Framed::Length(conn, _, true)) => self.lease.release(conn),
+                Some(Framed::Chunked(chunked, true)) => self.lease.release(chunked.into_inner()),
+                _ => {}
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Write `req` for `upstream`, telling it that the client spoke `proto` to us.
+fn write_request<W: Write>(w: &mut W, upstream: &Upstream, keep_alive: bool,
+                          validators: Option<&[(&str, String)]>, proto: &str, req: &Request) -> io::Result<()> {
+    // only the path and query of an absolute-form, still percent-encoded
+    let target = req.target();
+    let uri = match target.query() {
+        Some(query) => format!("{}?{}", target.path(), query),
+        None => target.path().to_string(),
+    };
+    write!(w, "{} {}{} HTTP/1.1\r\n", req.method(), upstream.path, uri)?;
+    write!(w, "Host: {}\r\n", upstream.authority())?;
+
+    let connection = connection_tokens(req.header("Connection"));
+    let mut forwarded_for = None;
+    let mut forwarded = None;
+    for (k, v) in req.headers() {
+        if k.eq_ignore_ascii_case("Host") || k.eq_ignore_ascii_case("Content-Length") || is_hop_by_hop(k, &connection) {
+            continue;
+        }
+        if k.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(v);
+            continue;
+        }
+        if k.eq_ignore_ascii_case("Forwarded") {
+            forwarded = Some(v);
+            continue;
+        }
+        if k.eq_ignore_ascii_case("X-Forwarded-Proto") || k.eq_ignore_ascii_case("X-Forwarded-Host") {
+            continue;
+        }
+        if validators.is_some() && CONDITIONAL.iter().any(|c| c.eq_ignore_ascii_case(k)) {
+            continue;
+        }
+        write!(w, "{}: {}\r\n", k, v)?;
+    }
+    for (k, v) in validators.unwrap_or(&[]) {
+        write!(w, "{}: {}\r\n", k, v)?;
+    }
+
+    let client = req.peer_addr().map_or("unknown".to_string(), |p| p.ip().to_string());
+    match forwarded_for {
+        Some(v) => write!(w, "X-Forwarded-For: {}, {}\r\n", v, client)?,
+        None => write!(w, "X-Forwarded-For: {}\r\n", client)?,
+    }
+    write!(w, "X-Forwarded-Proto: {}\r\n", proto)?;
+    if let Some(host) = req.header("Host") {
+        write!(w, "X-Forwarded-Host: {}\r\n", host)?;
+    }
+    // [RFC7239, Section 4]
+    let node = match req.peer_addr() {
+        Some(p) if p.is_ipv6() => format!("\"[{}]\"", p.ip()),
+        Some(p) => p.ip().to_string(),
+        None => "unknown".to_string(),
+    };
+    let element = match req.header("Host") {
+        Some(host) => format!("for={};host=\"{}\";proto={}", node, host.replace('"', ""), proto),
+        None => format!("for={};proto={}", node, proto),
+    };
+    match forwarded {
+        Some(v) => write!(w, "Forwarded: {}, {}\r\n", v, element)?,
+        None => write!(w, "Forwarded: {}\r\n", element)?,
+    }
+
+    if !keep_alive {
+        write!(w, "Connection: close\r\n")?;
+    }
+    match req.body() {
+        Some(body) => {
+            write!(w, "Content-Length: {}\r\n\r\n", body.len())?;
+            w.write_all(body)
+        }
+        None => write!(w, "\r\n"),
+    }
+}
+
+/// The status line and header fields of an upstream response: the version,
+/// the status code if registered, the reason phrase and the fields.
+type Head = (HTTPVersion, Option<StatusCode>, String, Vec<(String, String)>);
+
+/// Read the status line and header fields of an upstream response, held
+/// to the same sizes as a request head: the status line to
+/// `max_request_line`, the fields to `max_headers` and `max_header_size`.
+fn read_head<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> io::Result<Head> {
+    let mut line = String::new();
+    let too_long = |what, max| io::Error::new(io::ErrorKind::InvalidData, format!("{} longer than {} bytes", what, max));
+    if read_line(reader, &mut line, limits.max_request_line, || too_long("status line", limits.max_request_line))? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    // HTTP/1.1 200 OK
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid status line {:?}", line));
+    let mut cursor = line.trim_end().splitn(3, ' ');
+    let version = cursor.next()
+        .filter(|v| v.starts_with("HTTP/") && v.len() == "HTTP/1.1".len())
+        .and_then(|v| HTTPVersion::from_str(v).ok())
+        .ok_or_else(invalid)?;
+    let code = cursor.next()
+        .and_then(|c| u16::from_str(c).ok())
+        .ok_or_else(invalid)?;
+    let reason_phrase = cursor.next().unwrap_or("").trim().to_string();
+
+    let mut header = Vec::new();
+    let mut remaining = limits.max_header_size;
+    loop {
+        line.clear();
+        let n = read_line(reader, &mut line, remaining, || too_long("header", limits.max_header_size))?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete response head"));
+        }
+        remaining -= n;
+        let l = line.trim_end();
+        if l.is_empty() {
+            break;
+        }
+        if header.len() == limits.max_headers {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("more than {} header fields", limits.max_headers)));
+        }
+        let mut cursor = l.splitn(2, ':');
+        let key = cursor.next().unwrap_or("").trim();
+        let value = cursor.next().unwrap_or("").trim();
+        header.push((key.to_string(), value.to_string()));
+    }
+    Ok((version, StatusCode::from_u16(code), reason_phrase, header))
+}
+
+/// Append a line of at most `max` bytes to `line`, returning the bytes
+/// read; `too_long` makes the error for a line that does not end within
+/// them.
+fn read_line<R, F>(reader: &mut R, line: &mut String, max: usize, too_long: F) -> io::Result<usize>
+    where R: BufRead, F: FnOnce() -> io::Error {
+    let n = (&mut *reader).take(max as u64).read_line(line)?;
+    if n == max && !line.ends_with('\n') {
+        return Err(too_long());
+    }
+    Ok(n)
+}
+
+/// Lower-cased field names listed by a `Connection` header.
+fn connection_tokens(value: Option<&str>) -> Vec<String> {
+    value.map_or(Vec::new(), |v| v.split(',').map(|t| t.trim().to_lowercase()).collect())
+}
+
+fn is_hop_by_hop(name: &str, connection: &[String]) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+        || connection.iter().any(|c| c.eq_ignore_ascii_case(name))
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body
+/// [[RFC2616, Section 3.6.1](https://tools.ietf.org/html/rfc2616#section-3.6.1)].
+pub struct Chunked<R> {
+    reader: R,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    pub fn new(reader: R) -> Self {
+        Chunked {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        line.clear();
+        read_line(&mut self.reader, line, MAX_CHUNK_LINE, || {
+            io::Error::new(io::ErrorKind::InvalidData, format!("chunk line longer than {} bytes", MAX_CHUNK_LINE))
+        })
+    }
+
+    /// Read a chunk-size line: hex digits only, then any extensions.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut line = String::new();
+        if self.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunked body"));
+        }
+        let size = line.trim_end_matches(&['\r', '\n'][..]).split(';').next().unwrap_or("");
+        let size = size.trim_end_matches(&[' ', '\t'][..]);
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size {:?}", line)));
+        }
+        self.remaining = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size {:?}", line)))?;
+        if self.remaining == 0 {
+            // skip the trailer
+            loop {
+                if self.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.next_chunk()?;
+            if self.done {
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.reader.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk"));
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            let mut crlf = String::new();
+            self.read_line(&mut crlf)?;
+            if crlf != "\r\n" && crlf != "\n" {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk data not followed by CRLF"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use super::super::header::Header;
+    use super::super::upstream::{KeepAlive, Strategy};
+
+    /// Forward a GET with the extra `header` fields to a stand-in upstream
+    /// that answers `response` and closes the connection. The response,
+    /// its body as read, and the request head the upstream received.
+    fn relay(response: &'static str, header: &[(&str, &str)]) -> (Response, io::Result<String>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                if reader.read_line(&mut head).unwrap() == 0 {
+                    break;
+                }
+            }
+            (&stream).write_all(response.as_bytes()).unwrap();
+            tx.send(head).unwrap();
+        });
+
+        let upstream = Upstream {
+            host: "127.0.0.1".to_string(),
+            port,
+            path: String::new(),
+        };
+        let keep_alive = KeepAlive {
+            max_idle: 0,
+            ..KeepAlive::default()
+        };
+        let pool = Pool::new("test".to_string(), vec![upstream], Strategy::RoundRobin, 0, Duration::from_secs(0), keep_alive, None);
+        let mut fields = Header::new();
+        fields.append("Host", "example.com");
+        for (k, v) in header {
+            fields.append(*k, *v);
+        }
+        let req = Request::from_parts(Method::GET, "/", HTTPVersion::new(1, 1), fields, None,
+                                      Some("192.0.2.1:1234".parse::<SocketAddr>().unwrap()), None).unwrap();
+        let mut res = forward(&pool, Duration::from_secs(5), &RequestLimits::default(), &req);
+        let mut body = String::new();
+        let read = res.take_stream().read_to_string(&mut body).map(|_| body);
+        (res, read, rx.recv().unwrap())
+    }
+
+    fn chunked(body: &str) -> io::Result<String> {
+        let mut decoded = String::new();
+        Chunked::new(io::Cursor::new(body.as_bytes().to_vec())).read_to_string(&mut decoded).map(|_| decoded)
+    }
+
+    #[test]
+    fn content_length_bodies() {
+        let (res, body, _) = relay("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, and more", &[]);
+        assert_eq!(res.header("Content-Length"), Some("5"));
+        assert_eq!(body.unwrap(), "hello");
+
+        let (_, body, _) = relay("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello", &[]);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: t\r\n\r\n";
+        let (res, body, _) = relay(response, &[]);
+        assert_eq!(body.unwrap(), "hello world");
+        assert_eq!(res.header("Content-Length"), None);
+        assert_eq!(res.header("Transfer-Encoding"), None);
+
+        // not chunked last: the body runs to the end of the connection
+        let (res, body, _) = relay("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\nContent-Length: 2\r\n\r\n5\r\nhello", &[]);
+        assert_eq!(body.unwrap(), "5\r\nhello");
+        assert_eq!(res.header("Content-Length"), None);
+
+        assert_eq!(chunked("A\r\n0123456789\r\n0\r\n\r\n").unwrap(), "0123456789");
+        assert_eq!(chunked("5 ;ext\r\nhello\r\n0\r\n\r\n").unwrap(), "hello");
+        for invalid in &["+5\r\nhello\r\n0\r\n\r\n", " 5\r\nhello\r\n0\r\n\r\n", "0x5\r\nhello\r\n0\r\n\r\n",
+                         "\r\nhello\r\n0\r\n\r\n", "-0\r\n\r\n", "5\r\nhelloXX0\r\n\r\n", "10000000000000000\r\n"] {
+            assert_eq!(chunked(invalid).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", invalid);
+        }
+        let long = format!("5;{}\r\nhello\r\n0\r\n\r\n", "x".repeat(MAX_CHUNK_LINE));
+        assert_eq!(chunked(&long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let long = format!("5\r\nhello\r\n0\r\nX-Trailer: {}\r\n\r\n", "x".repeat(MAX_CHUNK_LINE));
+        assert_eq!(chunked(&long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(chunked("5\r\nhel").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(chunked("5\r\nhello\r\n").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn close_delimited_bodies() {
+        let (res, body, _) = relay("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nuntil the end", &[]);
+        assert_eq!(res.header("Content-Length"), None);
+        assert_eq!(body.unwrap(), "until the end");
+
+        let (_, body, _) = relay("HTTP/1.0 204 No Content\r\n\r\nignored", &[]);
+        assert_eq!(body.unwrap(), "");
+    }
+
+    #[test]
+    fn hop_by_hop_fields_are_removed() {
+        let response = "HTTP/1.1 200 OK\r\nConnection: close, X-Internal\r\nX-Internal: 1\r\nKeep-Alive: timeout=5\r\n\
+                        Proxy-Authenticate: Basic\r\nUpgrade: h2c\r\nTrailer: X-T\r\nX-Kept: 1\r\nContent-Length: 0\r\n\r\n";
+        let header = [("Connection", "X-Secret"), ("X-Secret", "1"), ("Keep-Alive", "300"), ("TE", "trailers"),
+                      ("Proxy-Authorization", "Basic dXNlcjpwYXNz"), ("Upgrade", "websocket"), ("X-Kept", "1")];
+        let (res, _, head) = relay(response, &header);
+
+        let sent: Vec<String> = head.lines().skip(1).map(|l| l.split(':').next().unwrap().to_lowercase()).collect();
+        for name in &["x-secret", "keep-alive", "te", "proxy-authorization", "upgrade"] {
+            assert!(!sent.iter().any(|s| s == name), "{} forwarded in {:?}", name, head);
+        }
+        assert!(sent.iter().any(|s| s == "x-kept"));
+        // ours, not the client's
+        assert!(head.contains("\r\nConnection: close\r\n"));
+        assert!(head.contains("\r\nHost: 127.0.0.1:"));
+
+        for name in &["Connection", "X-Internal", "Keep-Alive", "Proxy-Authenticate", "Upgrade", "Trailer"] {
+            assert_eq!(res.header(name), None, "{}", name);
+        }
+        assert_eq!(res.header("X-Kept"), Some("1"));
+    }
+}
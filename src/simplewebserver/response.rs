@@ -21,13 +21,26 @@
 
 //! HTTP Response
 use core::fmt;
-use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use chrono::Local;
 
 use super::common::HTTPVersion;
+use super::h2::Transport;
+use super::header::{format_date, Allow, ContentLength, ContentType, Date, Header, HeaderName};
 use super::status::StatusCode;
 
 static HORIZONTAL_LINE_RESPONSE: &str = "<<<<<<<<<<<<<<<<<<<<<<<<<<<<<< RESPONSE <<<<<<<<<<<<<<<<<<<<<<<<<<<<<";
 
+/// The `Server` field sent unless a handler sets its own
+/// [[RFC1945, Section 10.14](https://tools.ietf.org/html/rfc1945#section-10.14)].
+pub static SERVER: &str = concat!("simplewebserver/", env!("CARGO_PKG_VERSION"));
+
 pub type ReasonPhrase = String;
 
 pub struct StatusLine {
@@ -38,80 +51,445 @@ pub struct StatusLine {
 
 impl fmt::Display for StatusLine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} ", self.version);
-        write!(f, "{} ", self.status_code.to_u16());
-        write!(f, "{}", self.status_code)
+        write!(f, "{} ", self.version)?;
+        write!(f, "{} ", self.status_code.to_u16())?;
+        if self.reason_phrase.is_empty() {
+            write!(f, "{}", self.status_code)
+        } else {
+            write!(f, "{}", self.reason_phrase)
+        }
     }
 }
 
 impl fmt::Debug for StatusLine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "{}", self)
     }
 }
 
-/// # Header Field Definitions
-/// Response Header Field
-/// * Location
-/// * Server
-/// * WWW-Authenticate
-///
-/// Entity Header Fields
-/// * Allow
-/// * Content-Encoding
-/// * Content-Length
-/// * Content-Type
-/// * Expires
-/// * Last-Modified
-pub type Header = HashMap<String, String>;
+/// # Entity Body
+/// Any bytes, either held in memory or read from a stream, e.g. a file or
+/// a proxied upstream response, while it is written to the client.
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    Stream(Box<dyn Read + Send>),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "None"),
+            Body::Bytes(body) => write!(f, "{:?}", String::from_utf8_lossy(body)),
+            Body::Stream(_) => write!(f, "<stream>"),
+        }
+    }
+}
+
+/// Takes over the connection once the response head has been written,
+/// e.g. after `101 Switching Protocols` or to stream events. It is given
+/// the bytes the client sent after the request that were already read.
+pub type Handoff = Box<dyn FnOnce(Connection, Vec<u8>) + Send>;
+
+/// A connection taken over by a handoff, over plain TCP or TLS. Clones
+/// share the connection, so one thread can read while others write.
+#[derive(Clone)]
+pub struct Connection(Arc<dyn Transport>);
+
+impl Connection {
+    pub fn new<T: Transport + 'static>(transport: T) -> Self {
+        Connection(Arc::new(transport))
+    }
+
+    /// The socket underneath, e.g. to set its timeouts.
+    pub fn socket(&self) -> &TcpStream {
+        self.0.socket()
+    }
+
+    /// Close the connection, telling a TLS client first.
+    pub fn close(&self) {
+        self.0.close()
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection({:?})", self.socket())
+    }
+}
 
 pub struct Response {
     status_line: StatusLine,
     // Header
     header: Header,
     // Entity-Body
-    body: Option<String>,
+    body: Body,
+    handoff: Option<Handoff>,
 }
 
 impl Response {
-    pub fn not_found() -> Self {
+    pub fn new(status_code: StatusCode) -> Self {
         Response {
             status_line: StatusLine {
                 version: HTTPVersion::new(1, 0),
-                status_code: StatusCode::NotFound,
+                status_code,
                 reason_phrase: String::new(),
             },
             header: Header::new(),
-            body: None,
+            body: Body::Empty,
+            handoff: None,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_line.status_code
+    }
+
+    pub fn set_version(&mut self, version: HTTPVersion) {
+        self.status_line.version = version;
+    }
+
+    /// Send `reason_phrase` instead of the standard one for the status
+    /// code. Control characters, which could end the line, are dropped
+    /// [[RFC7230, Section 3.1.2](https://tools.ietf.org/html/rfc7230#section-3.1.2)].
+    pub fn set_reason_phrase(&mut self, reason_phrase: &str) {
+        self.status_line.reason_phrase = reason_phrase.chars()
+            .filter(|c| *c == '\t' || !c.is_control())
+            .collect();
+    }
+
+    /// Whether the status code allows a body: not 1xx, 204 or 304
+    /// [[RFC7230, Section 3.3](https://tools.ietf.org/html/rfc7230#section-3.3)].
+    pub fn allows_body(&self) -> bool {
+        let code = self.status_code().to_u16();
+        code >= 200 && code != 204 && code != 304
+    }
+
+    /// Hand the connection to `handoff` after the response is written.
+    pub fn set_handoff<F: FnOnce(Connection, Vec<u8>) + Send + 'static>(&mut self, handoff: F) {
+        self.handoff = Some(Box::new(handoff));
+    }
+
+    /// Take the handoff out; the body is then whatever it writes, of
+    /// unknown length.
+    pub fn take_handoff(&mut self) -> Option<Handoff> {
+        let handoff = self.handoff.take();
+        if handoff.is_some() {
+            self.set_stream(io::empty());
+        }
+        handoff
+    }
+
+    /// Set header field `name`, replacing any fields of that name.
+    pub fn set_header<K: Into<HeaderName>, V: Into<String>>(&mut self, name: K, value: V) {
+        self.header.insert(name, value);
+    }
+
+    /// Add a header field, keeping any fields of the same name, e.g. for
+    /// several `Set-Cookie`s.
+    pub fn append_header<K: Into<HeaderName>, V: Into<String>>(&mut self, name: K, value: V) {
+        self.header.append(name, value);
+    }
+
+    /// Look up the first header field called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header.get(name)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.header.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// The header fields, with typed accessors.
+    pub fn header_map(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.header.remove(name);
+    }
+
+    /// # Response Header Fields
+    /// [[RFC1945, Section 6.2](https://tools.ietf.org/html/rfc1945#section-6.2)]
+    pub fn set_location(&mut self, location: &str) {
+        self.set_header(HeaderName::Location, location);
+    }
+
+    pub fn set_server(&mut self, server: &str) {
+        self.set_header(HeaderName::Server, server);
+    }
+
+    /// # Entity Header Fields
+    /// [[RFC1945, Section 7.1](https://tools.ietf.org/html/rfc1945#section-7.1)]
+    pub fn set_allow(&mut self, allow: &Allow) {
+        self.set_header(HeaderName::Allow, allow.to_string());
+    }
+
+    pub fn set_content_length(&mut self, content_length: ContentLength) {
+        self.set_header(HeaderName::ContentLength, content_length.to_string());
+    }
+
+    pub fn set_content_type(&mut self, content_type: &ContentType) {
+        self.set_header(HeaderName::ContentType, content_type.to_string());
+    }
+
+    pub fn set_expires(&mut self, expires: &Date) {
+        self.set_header(HeaderName::Expires, format_date(expires));
+    }
+
+    pub fn set_last_modified(&mut self, last_modified: &Date) {
+        self.set_header(HeaderName::LastModified, format_date(last_modified));
+    }
+
+    /// The general header field `Date`
+    /// [[RFC1945, Section 10.6](https://tools.ietf.org/html/rfc1945#section-10.6)].
+    pub fn set_date(&mut self, date: &Date) {
+        self.set_header(HeaderName::Date, format_date(date));
+    }
+
+    pub fn set_body<B: Into<Vec<u8>>>(&mut self, body: B) {
+        self.body = Body::Bytes(body.into());
+    }
+
+    /// Stream the body from `reader` when the response is written.
+    pub fn set_stream<R: Read + Send + 'static>(&mut self, reader: R) {
+        self.body = Body::Stream(Box::new(reader));
+    }
+
+    /// Take the body out of the response as a stream.
+    pub fn take_stream(&mut self) -> Box<dyn Read + Send> {
+        match mem::replace(&mut self.body, Body::Empty) {
+            Body::Empty => Box::new(io::empty()),
+            Body::Bytes(body) => Box::new(io::Cursor::new(body)),
+            Body::Stream(reader) => reader,
+        }
+    }
+
+    /// Add the header fields every response carries, unless already set:
+    /// `Date`, `Server` and, if the body is held in memory, `Content-Length`.
+    /// A streamed body only has a length if the handler set one.
+    pub fn complete_header(&mut self) {
+        if !self.header.contains(HeaderName::Date) {
+            self.set_date(&Local::now());
+        }
+        if !self.header.contains(HeaderName::Server) {
+            self.set_server(SERVER);
+        }
+        let len = match &self.body {
+            Body::Empty => Some(0),
+            Body::Bytes(body) => Some(body.len()),
+            Body::Stream(_) => None,
+        };
+        if let Some(len) = len.filter(|_| self.allows_body() && !self.header.contains(HeaderName::ContentLength)) {
+            self.set_content_length(len);
+        }
+    }
+
+    /// Write the response to `w`, returning the number of body bytes sent.
+    /// Lines end in CRLF [[RFC1945, Section 6](https://tools.ietf.org/html/rfc1945#section-6)],
+    /// and the body is left out for `head`, i.e. a HEAD request, and for
+    /// status codes that allow none. An HTTP/0.9 Simple-Response is the body
+    /// alone.
+    pub fn write_to<W: Write>(mut self, w: &mut W, head: bool) -> io::Result<u64> {
+        if self.status_line.version >= HTTPVersion::new(1, 0) {
+            self.complete_header();
+            write!(w, "{}\r\n", &self.status_line)?;
+            for (k, v) in self.header.iter() {
+                write!(w, "{}: {}\r\n", k, v)?;
+            }
+            write!(w, "\r\n")?;
+            if head || !self.allows_body() {
+                return Ok(0);
+            }
+        }
+        match self.body {
+            Body::Empty => Ok(0),
+            Body::Bytes(body) => w.write_all(&body).map(|_| body.len() as u64),
+            Body::Stream(mut reader) => io::copy(&mut reader, w),
+        }
+    }
+
+    pub fn builder(status_code: StatusCode) -> Builder {
+        Builder {
+            res: Response::new(status_code),
         }
     }
+
+    /// `200 OK` with a UTF-8 `text/plain` body.
+    pub fn text<B: Into<String>>(body: B) -> Self {
+        Response::builder(StatusCode::OK)
+            .header(HeaderName::ContentType, "text/plain; charset=utf-8")
+            .body(body.into())
+            .build()
+    }
+
+    /// `200 OK` with a UTF-8 `text/html` body.
+    pub fn html<B: Into<String>>(body: B) -> Self {
+        Response::builder(StatusCode::OK)
+            .header(HeaderName::ContentType, "text/html; charset=utf-8")
+            .body(body.into())
+            .build()
+    }
+
+    /// `200 OK` with an `application/json` body, which is taken as already
+    /// serialized.
+    pub fn json<B: Into<String>>(body: B) -> Self {
+        Response::builder(StatusCode::OK)
+            .header(HeaderName::ContentType, "application/json")
+            .body(body.into())
+            .build()
+    }
+
+    /// A small HTML page naming the status code.
+    pub fn error(status_code: StatusCode) -> Self {
+        Response::builder(status_code)
+            .header(HeaderName::ContentType, "text/html")
+            .body(format!("<!DOCTYPE html><html><h1>{} {}</h1></html>", status_code.to_u16(), status_code))
+            .build()
+    }
+
+    /// Redirect to `location` with a 3xx `status_code`
+    /// [[RFC1945, Section 9.3](https://tools.ietf.org/html/rfc1945#section-9.3)].
+    pub fn redirect(location: &str, status_code: StatusCode) -> Self {
+        let mut res = Response::error(status_code);
+        res.set_location(location);
+        res
+    }
+
+    pub fn not_found() -> Self {
+        Response::builder(StatusCode::NotFound).build()
+    }
 }
 
 impl Default for Response {
     fn default() -> Self {
-        let mut header = Header::new();
-        header.insert("Content-Type".to_string(), "text/html".to_string());
-        Response {
-            status_line: StatusLine {
-                version: HTTPVersion::new(1, 0),
-                status_code: StatusCode::OK,
-                reason_phrase: String::new(),
-            },
-            header,
-            body: Some("Hello World!".to_string()),
-        }
+        Response::builder(StatusCode::OK)
+            .header(HeaderName::ContentType, "text/html")
+            .body("Hello World!")
+            .build()
+    }
+}
+
+/// Builds a `Response` step by step, e.g.
+///
+/// ```notrust
+/// Response::builder(StatusCode::OK)
+///     .header("Cache-Control", "no-cache")
+///     .file(File::open("index.html")?)?
+///     .build()
+/// ```
+pub struct Builder {
+    res: Response,
+}
+
+impl Builder {
+    pub fn status(mut self, status_code: StatusCode) -> Self {
+        self.res.status_line.status_code = status_code;
+        self
+    }
+
+    /// See `Response::set_reason_phrase`.
+    pub fn reason_phrase(mut self, reason_phrase: &str) -> Self {
+        self.res.set_reason_phrase(reason_phrase);
+        self
+    }
+
+    pub fn version(mut self, version: HTTPVersion) -> Self {
+        self.res.set_version(version);
+        self
+    }
+
+    /// Add a header field, keeping any earlier ones of the same name.
+    pub fn header<K: Into<HeaderName>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.res.append_header(name, value);
+        self
+    }
+
+    pub fn allow(mut self, allow: &Allow) -> Self {
+        self.res.set_allow(allow);
+        self
+    }
+
+    pub fn content_length(mut self, content_length: ContentLength) -> Self {
+        self.res.set_content_length(content_length);
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &ContentType) -> Self {
+        self.res.set_content_type(content_type);
+        self
+    }
+
+    pub fn expires(mut self, expires: &Date) -> Self {
+        self.res.set_expires(expires);
+        self
+    }
+
+    pub fn last_modified(mut self, last_modified: &Date) -> Self {
+        self.res.set_last_modified(last_modified);
+        self
+    }
+
+    pub fn location(mut self, location: &str) -> Self {
+        self.res.set_location(location);
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.res.set_body(body);
+        self
+    }
+
+    /// Stream the body from `file`, whose size is the Content-Length.
+    pub fn file(mut self, file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        self.res.set_content_length(len as usize);
+        self.res.set_stream(file);
+        Ok(self)
+    }
+
+    /// Stream the body from `reader`; set a Content-Length if it is known.
+    pub fn reader<R: Read + Send + 'static>(mut self, reader: R) -> Self {
+        self.res.set_stream(reader);
+        self
+    }
+
+    /// See `Response::set_handoff`.
+    pub fn handoff<F: FnOnce(Connection, Vec<u8>) + Send + 'static>(mut self, handoff: F) -> Self {
+        self.res.set_handoff(handoff);
+        self
+    }
+
+    pub fn build(self) -> Response {
+        self.res
     }
 }
 
 impl fmt::Display for Response {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}", &self.status_line);
-        for (k, v) in &self.header {
-            writeln!(f, "{}: {}", k, v);
+        writeln!(f, "{}", &self.status_line)?;
+        for (k, v) in self.header.iter() {
+            writeln!(f, "{}: {}", k, v)?;
         }
-        writeln!(f, "");
+        writeln!(f)?;
         match &self.body {
-            Some(body) => write!(f, "{}", body),
+            Body::Bytes(body) => write!(f, "{}", String::from_utf8_lossy(body)),
             &_ => Ok(())
         }
     }
@@ -119,9 +497,155 @@ impl fmt::Display for Response {
 
 impl fmt::Debug for Response {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}", HORIZONTAL_LINE_RESPONSE);
-        writeln!(f, "{}", &self.status_line);
-        writeln!(f, "{:#?}", &self.header);
+        writeln!(f, "{}", HORIZONTAL_LINE_RESPONSE)?;
+        writeln!(f, "{}", &self.status_line)?;
+        writeln!(f, "{:#?}", &self.header)?;
         writeln!(f, "{:?}", &self.body)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use super::super::header::parse_date;
+    use super::super::request::Method;
+
+    fn body(mut res: Response) -> String {
+        let mut body = String::new();
+        res.take_stream().read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn builder() {
+        let res = Response::builder(StatusCode::OK)
+            .status(StatusCode::Created)
+            .version(HTTPVersion::new(1, 1))
+            .reason_phrase("Made\r\nX-Injected: 1")
+            .header("Set-Cookie", "a=1")
+            .header("set-cookie", "b=2")
+            .allow(&Allow::new(vec![Method::GET, Method::HEAD]))
+            .content_type(&"text/plain".parse().unwrap())
+            .content_length(5)
+            .location("/new")
+            .body("hello")
+            .build();
+        assert_eq!(res.status_code(), StatusCode::Created);
+        assert_eq!(res.status_line.to_string(), "HTTP/1.1 201 MadeX-Injected: 1");
+        assert_eq!(res.header_map().get_all("Set-Cookie").collect::<Vec<_>>(), vec!["a=1", "b=2"]);
+        assert_eq!(res.header("allow"), Some("GET, HEAD"));
+        assert_eq!(res.header("Content-Type"), Some("text/plain"));
+        assert_eq!(res.header("Content-Length"), Some("5"));
+        assert_eq!(res.header("Location"), Some("/new"));
+        assert_eq!(body(res), "hello");
+
+        // setters replace what the builder appended
+        let mut res = Response::builder(StatusCode::OK).header("X-A", "1").header("X-A", "2").build();
+        res.set_header("x-a", "3");
+        assert_eq!(res.header_map().get_all("X-A").collect::<Vec<_>>(), vec!["3"]);
+        res.remove_header("X-A");
+        assert_eq!(res.header("X-A"), None);
+        assert_eq!(res.status_line.to_string(), "HTTP/1.0 200 OK");
+    }
+
+    #[test]
+    fn bodies() {
+        assert_eq!(body(Response::not_found()), "");
+        assert_eq!(body(Response::builder(StatusCode::OK).reader(io::Cursor::new("streamed")).build()), "streamed");
+
+        let path = env::temp_dir().join(format!("simplewebserver-response-{}", process::id()));
+        fs::write(&path, "from a file").unwrap();
+        let res = Response::builder(StatusCode::OK).file(File::open(&path).unwrap()).unwrap().build();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(res.header("Content-Length"), Some("11"));
+        assert_eq!(body(res), "from a file");
+
+        let mut res = Response::builder(StatusCode::SwitchingProtocols).body("ignored").handoff(|_, _| {}).build();
+        assert!(res.take_handoff().is_some());
+        assert!(res.take_handoff().is_none());
+        assert_eq!(body(res), "");
+    }
+
+    #[test]
+    fn shorthands() {
+        let res = Response::text("hi");
+        assert_eq!((res.status_code(), res.header("Content-Type")), (StatusCode::OK, Some("text/plain; charset=utf-8")));
+        assert_eq!(body(res), "hi");
+        assert_eq!(Response::html("").header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(Response::json("{}").header("Content-Type"), Some("application/json"));
+
+        let res = Response::error(StatusCode::BadGateway);
+        assert_eq!(res.status_code(), StatusCode::BadGateway);
+        assert!(body(res).contains("502 Bad Gateway"));
+
+        let res = Response::redirect("/there", StatusCode::MovedPermanently);
+        assert_eq!((res.status_code(), res.header("Location")), (StatusCode::MovedPermanently, Some("/there")));
+    }
+
+    fn write(res: Response, head: bool) -> (String, u64) {
+        let mut out = Vec::new();
+        let sent = res.write_to(&mut out, head).unwrap();
+        (String::from_utf8(out).unwrap(), sent)
+    }
+
+    #[test]
+    fn responses_are_serialized_with_crlf() {
+        let res = Response::builder(StatusCode::OK)
+            .version(HTTPVersion::new(1, 1))
+            .header("X-A", "1")
+            .body("hello")
+            .build();
+        let (out, sent) = write(res, false);
+        assert_eq!(sent, 5);
+        let (head, body) = out.split_at(out.find("\r\n\r\n").unwrap() + 4);
+        assert_eq!(body, "hello");
+        assert!(!head.replace("\r\n", "").contains('\n'));
+        let lines: Vec<&str> = head.trim_end().split("\r\n").collect();
+        assert_eq!(lines[0], "HTTP/1.1 200 OK");
+        assert!(lines.contains(&"X-A: 1"));
+        assert!(lines.contains(&"Content-Length: 5"));
+        assert!(lines.contains(&format!("Server: {}", SERVER).as_str()));
+        let date = lines.iter().find(|l| l.starts_with("Date: ")).unwrap();
+        assert!(date.ends_with(" GMT"));
+        assert!(parse_date(&date["Date: ".len()..]).is_some());
+    }
+
+    #[test]
+    fn fields_set_by_handlers_are_kept() {
+        let res = Response::builder(StatusCode::OK)
+            .header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .header("Server", "upstream")
+            .reader(io::Cursor::new("streamed"))
+            .build();
+        let (out, sent) = write(res, false);
+        assert_eq!(sent, 8);
+        assert!(out.contains("\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+        assert!(out.contains("\r\nServer: upstream\r\n"));
+        // a stream has no length unless one is set
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\nstreamed"));
+    }
+
+    #[test]
+    fn some_responses_have_no_body() {
+        let (out, sent) = write(Response::text("hello"), true);
+        assert_eq!(sent, 0);
+        assert!(out.contains("\r\nContent-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+
+        for code in &[StatusCode::NoContent, StatusCode::NotModified, StatusCode::Continue] {
+            let (out, sent) = write(Response::builder(*code).body("hello").build(), false);
+            assert_eq!(sent, 0);
+            assert!(!out.contains("Content-Length"), "{}", out);
+            assert!(out.ends_with("\r\n\r\n"));
+        }
+    }
+
+    #[test]
+    fn simple_responses_are_the_body_alone() {
+        let res = Response::builder(StatusCode::OK).version(HTTPVersion::new(0, 9)).body("hello").build();
+        assert_eq!(write(res, false), ("hello".to_string(), 5));
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Document roots
+//!
+//! Every handler that maps the request path onto the file system goes
+//! through `Root::resolve`, so that no request can reach a file outside
+//! the configured directory: `..` segments, including those hidden as
+//! `%2e%2e` or behind an encoded `%2f`, are refused, and so are symbolic
+//! links the root's policy does not allow and, unless enabled, hidden
+//! files.
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use super::status::StatusCode;
+
+/// What to do with symbolic links met below a document root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Symlinks {
+    /// Never follow them.
+    Deny,
+    /// Follow them as long as their target stays below the root.
+    #[default]
+    WithinRoot,
+    /// Follow them wherever they point.
+    Follow,
+}
+
+/// A directory files are served from, with its access policy.
+#[derive(Debug, Clone)]
+pub struct Root {
+    path: PathBuf,
+    symlinks: Symlinks,
+    dotfiles: bool,
+}
+
+impl Root {
+    /// `dotfiles` allows serving files and directories whose name starts
+    /// with a `.`.
+    pub fn new<P: Into<PathBuf>>(path: P, symlinks: Symlinks, dotfiles: bool) -> Self {
+        Root {
+            path: path.into(),
+            symlinks,
+            dotfiles,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the entry `name` may be served, or listed.
+    pub fn is_visible(&self, name: &str) -> bool {
+        // .well-known is meant to be reachable [RFC8615]
+        self.dotfiles || !name.starts_with('.') || name == ".well-known"
+    }
+
+    /// Map the decoded request path `path` to a file below the root:
+    /// `400 Bad Request` if it tries to leave the root, `404 Not Found`
+    /// for hidden files and `403 Forbidden` for symbolic links that are
+    /// not allowed. The file itself need not exist.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        if path.contains('\0') {
+            return Err(StatusCode::BadRequest);
+        }
+        let mut file = self.path.clone();
+        // only the leading part that exists can hold a link
+        let mut existing = true;
+        for component in Path::new(path.trim_start_matches('/')).components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                _ => return Err(StatusCode::BadRequest),
+            };
+            if !self.is_visible(&name.to_string_lossy()) {
+                return Err(StatusCode::NotFound);
+            }
+            file.push(name);
+            if existing {
+                existing = match fs::symlink_metadata(&file) {
+                    Ok(meta) => {
+                        if meta.file_type().is_symlink() {
+                            self.check_link(&file)?;
+                        }
+                        true
+                    }
+                    Err(_) => false,
+                };
+            }
+        }
+        Ok(file)
+    }
+
+    /// Apply the symlink policy to the link at `file`, whose parent is
+    /// known to be below the root.
+    fn check_link(&self, file: &Path) -> Result<(), StatusCode> {
+        match self.symlinks {
+            Symlinks::Follow => Ok(()),
+            Symlinks::Deny => {
+                info!("{}: symbolic links are not allowed", file.display());
+                Err(StatusCode::Forbidden)
+            }
+            Symlinks::WithinRoot => {
+                let root = self.path.canonicalize().map_err(|_| StatusCode::NotFound)?;
+                match file.canonicalize() {
+                    Ok(target) if target.starts_with(&root) => Ok(()),
+                    // a dangling link is as good as a missing file
+                    Err(_) => Err(StatusCode::NotFound),
+                    Ok(target) => {
+                        info!("{}: links to {} outside the root", file.display(), target.display());
+                        Err(StatusCode::Forbidden)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use super::super::uri::URI;
+
+    static TREES: AtomicUsize = AtomicUsize::new(0);
+
+    /// A scratch directory holding `secret.txt` and, below `root`:
+    /// `a/b.txt`, `.env`, `.well-known/security.txt`, and the links
+    /// `inside` to `a/b.txt`, `outside` to `../secret.txt`, `up` to `..`
+    /// and `dangling` to nothing.
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new() -> Self {
+            let base = env::temp_dir().join(format!("root-{}-{}", process::id(), TREES.fetch_add(1, Ordering::SeqCst)));
+            let root = base.join("root");
+            fs::create_dir_all(root.join("a")).unwrap();
+            fs::create_dir_all(root.join(".well-known")).unwrap();
+            fs::write(base.join("secret.txt"), "secret").unwrap();
+            fs::write(root.join("a/b.txt"), "b").unwrap();
+            fs::write(root.join(".env"), "env").unwrap();
+            fs::write(root.join(".well-known/security.txt"), "contact").unwrap();
+            symlink("a/b.txt", root.join("inside")).unwrap();
+            symlink("../secret.txt", root.join("outside")).unwrap();
+            symlink("..", root.join("up")).unwrap();
+            symlink("missing", root.join("dangling")).unwrap();
+            Tree(base)
+        }
+
+        fn root(&self, symlinks: Symlinks, dotfiles: bool) -> Root {
+            Root::new(self.0.join("root"), symlinks, dotfiles)
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Resolve `target` the way a request does: parsed, with its dot
+    /// segments removed, and decoded.
+    fn resolve(root: &Root, target: &str) -> Result<PathBuf, StatusCode> {
+        let path = URI::from_str(target).ok()
+            .and_then(|uri| uri.decoded_path())
+            .ok_or(StatusCode::BadRequest)?;
+        root.resolve(&path)
+    }
+
+    #[test]
+    fn serves_files_below_the_root() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::default(), false);
+        assert_eq!(resolve(&root, "/a/b.txt"), Ok(root.path().join("a/b.txt")));
+        assert_eq!(resolve(&root, "/a/missing"), Ok(root.path().join("a/missing")));
+        // an encoded slash is only a slash once decoded
+        assert_eq!(resolve(&root, "/a%2fb.txt"), Ok(root.path().join("a/b.txt")));
+    }
+
+    #[test]
+    fn dot_segments_stay_below_the_root() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::default(), false);
+        for target in &["/../secret.txt", "/a/../../secret.txt", "/%2e%2e/secret.txt", "/a/%2E%2E/%2e%2e/secret.txt"] {
+            assert_eq!(resolve(&root, target), Ok(root.path().join("secret.txt")), "{}", target);
+        }
+    }
+
+    #[test]
+    fn rejects_parent_segments() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::Follow, true);
+        assert_eq!(root.resolve("/../secret.txt"), Err(StatusCode::BadRequest));
+        assert_eq!(root.resolve("/a/../../secret.txt"), Err(StatusCode::BadRequest));
+        // dot segments behind encoded slashes survive until decoding
+        assert_eq!(resolve(&root, "/a/..%2f..%2fsecret.txt"), Err(StatusCode::BadRequest));
+        assert_eq!(resolve(&root, "/a%2f%2e%2e%2f%2e%2e%2fsecret.txt"), Err(StatusCode::BadRequest));
+    }
+
+    #[test]
+    fn rejects_nul() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::default(), false);
+        assert_eq!(root.resolve("/a/b.txt\0.html"), Err(StatusCode::BadRequest));
+        assert_eq!(resolve(&root, "/a/b.txt%00.html"), Err(StatusCode::BadRequest));
+    }
+
+    #[test]
+    fn hides_dotfiles() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::default(), false);
+        assert_eq!(resolve(&root, "/.env"), Err(StatusCode::NotFound));
+        assert_eq!(resolve(&root, "/%2eenv"), Err(StatusCode::NotFound));
+        assert_eq!(resolve(&root, "/a/.git/config"), Err(StatusCode::NotFound));
+        assert!(!root.is_visible(".env"));
+
+        let root = tree.root(Symlinks::default(), true);
+        assert_eq!(resolve(&root, "/.env"), Ok(root.path().join(".env")));
+        assert!(root.is_visible(".env"));
+    }
+
+    #[test]
+    fn serves_well_known() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::default(), false);
+        assert_eq!(resolve(&root, "/.well-known/security.txt"), Ok(root.path().join(".well-known/security.txt")));
+        assert!(root.is_visible(".well-known"));
+    }
+
+    #[test]
+    fn symlinks_deny() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::Deny, false);
+        assert_eq!(resolve(&root, "/a/b.txt"), Ok(root.path().join("a/b.txt")));
+        for target in &["/inside", "/outside", "/up/secret.txt", "/dangling"] {
+            assert_eq!(resolve(&root, target), Err(StatusCode::Forbidden), "{}", target);
+        }
+    }
+
+    #[test]
+    fn symlinks_within_root() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::WithinRoot, false);
+        assert_eq!(resolve(&root, "/inside"), Ok(root.path().join("inside")));
+        assert_eq!(resolve(&root, "/outside"), Err(StatusCode::Forbidden));
+        assert_eq!(resolve(&root, "/up/secret.txt"), Err(StatusCode::Forbidden));
+        assert_eq!(resolve(&root, "/up/missing"), Err(StatusCode::Forbidden));
+        assert_eq!(resolve(&root, "/dangling"), Err(StatusCode::NotFound));
+    }
+
+    #[test]
+    fn symlinks_follow() {
+        let tree = Tree::new();
+        let root = tree.root(Symlinks::Follow, false);
+        assert_eq!(resolve(&root, "/inside"), Ok(root.path().join("inside")));
+        assert_eq!(resolve(&root, "/outside"), Ok(root.path().join("outside")));
+        assert_eq!(resolve(&root, "/up/secret.txt"), Ok(root.path().join("up/secret.txt")));
+    }
+}
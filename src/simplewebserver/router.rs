@@ -0,0 +1,169 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Request routing
+use super::config::{Config, Location, Matcher, Server};
+use super::request::Request;
+
+/// Select the server block answering `req` by the host it is addressed
+/// to. An exact name wins over wildcard names, and the longest matching
+/// wildcard over shorter ones; without a match the default server is used.
+pub fn find_server<'a>(config: &'a Config, req: &Request) -> &'a Server {
+    let default = config.servers.iter()
+        .find(|s| s.default)
+        .unwrap_or(&config.servers[0]);
+    let host = match req.host() {
+        Some(host) => host,
+        None => return default,
+    };
+
+    let mut wildcard: Option<(usize, &Server)> = None;
+    for server in &config.servers {
+        for name in &server.server_name {
+            if *name == host {
+                return server;
+            }
+            if name.starts_with("*.") && host.ends_with(&name[1..])
+                && wildcard.is_none_or(|(len, _)| name.len() > len) {
+                    wildcard = Some((name.len(), server));
+                }
+        }
+    }
+    wildcard.map_or(default, |(_, s)| s)
+}
+
+/// Select the location block of `server` matching `path`.
+///
+/// An exact match wins; otherwise the longest matching prefix is
+/// remembered and regular expressions are checked in order, the first
+/// match winning. The remembered prefix is used if no regular expression
+/// matches.
+pub fn find_location<'a>(server: &'a Server, path: &str) -> Option<&'a Location> {
+    let mut prefix: Option<(usize, &Location)> = None;
+    for location in &server.locations {
+        match &location.matcher {
+            Matcher::Exact(p) if p == path => return Some(location),
+            Matcher::Prefix(p) if path.starts_with(p.as_str())
+                && prefix.is_none_or(|(len, _)| p.len() > len) => {
+                    prefix = Some((p.len(), location));
+                }
+            _ => {}
+        }
+    }
+    server.locations.iter()
+        .find(|l| match l.matcher {
+            Matcher::Regex(_) => l.matcher.is_match(path),
+            _ => false,
+        })
+        .or_else(|| prefix.map(|(_, l)| l))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::common::HTTPVersion;
+    use super::super::header::Header;
+    use super::super::request::Method;
+    use super::super::respond;
+    use super::super::status::StatusCode;
+
+    static CONFIG: &str = r#"
+        [[listen]]
+        addr = "127.0.0.1:8080"
+
+        [[server]]
+        server_name = ["example.com"]
+
+        [[server]]
+        server_name = ["*.example.com"]
+
+        [[server]]
+        server_name = ["*.api.example.com"]
+
+        [[server]]
+        server_name = ["www.api.example.com", "example.org"]
+        default = true
+          [[server.location]]
+          prefix = "/"
+          handler = "redirect"
+          to = "/elsewhere"
+
+        [[server]]
+    "#;
+
+    fn request(version: HTTPVersion, uri: &str, host: Option<&str>) -> Request {
+        let mut header = Header::new();
+        if let Some(host) = host {
+            header.append("Host", host);
+        }
+        Request::from_parts(Method::GET, uri, version, header, None, None, None).unwrap()
+    }
+
+    /// The index of the server answering `uri` with `host`.
+    fn server(config: &Config, uri: &str, host: Option<&str>) -> usize {
+        let req = request(HTTPVersion::new(1, 1), uri, host);
+        let found = find_server(config, &req);
+        config.servers.iter().position(|s| std::ptr::eq(s, found)).unwrap()
+    }
+
+    #[test]
+    fn exact_names_win_over_wildcards() {
+        let config: Config = CONFIG.parse().unwrap();
+        assert_eq!(server(&config, "/", Some("example.com")), 0);
+        assert_eq!(server(&config, "/", Some("EXAMPLE.com.:8080")), 0);
+        assert_eq!(server(&config, "/", Some("example.org")), 3);
+        assert_eq!(server(&config, "/", Some("www.api.example.com")), 3);
+
+        assert_eq!(server(&config, "/", Some("www.example.com")), 1);
+        assert_eq!(server(&config, "/", Some("api.example.com")), 1);
+        // the longest wildcard
+        assert_eq!(server(&config, "/", Some("v2.api.example.com")), 2);
+        assert_eq!(server(&config, "/", Some("a.b.api.example.com")), 2);
+    }
+
+    #[test]
+    fn unknown_hosts_get_the_default_server() {
+        let config: Config = CONFIG.parse().unwrap();
+        assert_eq!(server(&config, "/", Some("badexample.com")), 3);
+        assert_eq!(server(&config, "/", Some("127.0.0.1")), 3);
+        assert_eq!(server(&config, "/", None), 3);
+
+        // the first one unless another is marked
+        let config: Config = CONFIG.replace("default = true", "").parse().unwrap();
+        assert_eq!(server(&config, "/", Some("example.net")), 0);
+    }
+
+    #[test]
+    fn absolute_uris_name_the_host() {
+        let config: Config = CONFIG.parse().unwrap();
+        assert_eq!(server(&config, "http://www.example.com/", Some("example.com")), 1);
+        assert_eq!(server(&config, "http://example.org:8080/", Some("example.com")), 3);
+    }
+
+    #[test]
+    fn http11_requires_host() {
+        let config: Config = CONFIG.parse().unwrap();
+        let status = |version, host| respond(&config, &request(version, "/", host)).0.status_code();
+        assert_eq!(status(HTTPVersion::new(1, 1), None), StatusCode::BadRequest);
+        assert_eq!(status(HTTPVersion::new(1, 1), Some("example.org")), StatusCode::MovedTemporarily);
+        assert_eq!(status(HTTPVersion::new(1, 0), None), StatusCode::MovedTemporarily);
+    }
+}
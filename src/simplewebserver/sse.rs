@@ -0,0 +1,405 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Server-Sent Events [[HTML Living Standard, Section 9.2](https://html.spec.whatwg.org/multipage/server-sent-events.html)]
+//!
+//! The response head is written with `Content-Type: text/event-stream`
+//! and the connection is handed to a [`Source`], which writes events for
+//! as long as it likes:
+//!
+//! ```notrust
+//! id: 42
+//! event: deploy
+//! data: {"service": "api",
+//! data:  "state": "done"}
+//!
+//! : heartbeat
+//! ```
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::request::{Method, Request};
+use super::response::{Connection, Response};
+use super::status::StatusCode;
+
+/// How long a write to a client may block before it is given up.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    /// Ask the client to wait this long before reconnecting.
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new<D: Into<String>>(data: D) -> Self {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    /// Line breaks cannot appear in a field, so `data` spanning several
+    /// lines, ended by CRLF, CR or LF as the client reads them, becomes
+    /// several `data` fields, which the client joins again.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id.replace(&['\r', '\n'][..], ""))?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event.replace(&['\r', '\n'][..], ""))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_secs() * 1000 + u64::from(retry.subsec_millis()))?;
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            writeln!(f, "data: {}", line)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// The event stream of one client. Clones write to the same connection;
+/// heartbeat comments are sent while any of them is alive.
+#[derive(Debug, Clone)]
+pub struct EventStream {
+    stream: Arc<Mutex<Connection>>,
+}
+
+impl EventStream {
+    fn new(stream: Connection, heartbeat: Duration) -> io::Result<Self> {
+        stream.socket().set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let stream = Arc::new(Mutex::new(stream));
+        let weak = Arc::downgrade(&stream);
+        thread::spawn(move || beat(weak, heartbeat));
+        Ok(EventStream { stream })
+    }
+
+    pub fn send(&self, event: &Event) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(event.to_string().as_bytes())?;
+        stream.flush()
+    }
+
+    /// Block until the client closes the connection. Clients send
+    /// nothing on an event stream, so anything read is discarded.
+    pub fn wait_closed(&self) {
+        let mut stream = self.stream.lock().unwrap().clone();
+        let mut buf = [0; 512];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        stream.close();
+    }
+}
+
+fn beat(stream: Weak<Mutex<Connection>>, heartbeat: Duration) {
+    loop {
+        thread::sleep(heartbeat);
+        let stream = match stream.upgrade() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut stream = stream.lock().unwrap();
+        if stream.write_all(b": heartbeat\n\n").and_then(|_| stream.flush()).is_err() {
+            stream.close();
+            return;
+        }
+    }
+}
+
+/// Produces the events of an SSE location.
+pub trait Source: fmt::Debug + Send + Sync {
+    /// Write events to one client until it goes away. `last_event_id` is
+    /// the `Last-Event-ID` a reconnecting client sends, to resume after
+    /// the last event it saw.
+    fn stream(&self, req: &Request, last_event_id: Option<&str>, events: EventStream);
+
+    /// Answer requests other than `GET`.
+    fn post(&self, _req: &Request) -> Response {
+        Response::error(StatusCode::NotImplemented)
+    }
+}
+
+/// Answer `GET` with an event stream fed by `source`, sending a heartbeat
+/// comment every `heartbeat` so idle connections are not dropped.
+pub fn respond(source: &Arc<dyn Source>, heartbeat: Duration, req: &Request) -> Response {
+    match req.method() {
+        Method::GET => {}
+        Method::POST => return source.post(req),
+        _ => return Response::error(StatusCode::NotImplemented),
+    }
+    let mut res = Response::new(StatusCode::OK);
+    res.set_header("Content-Type", "text/event-stream");
+    res.set_header("Cache-Control", "no-cache");
+    let source = source.clone();
+    let req = req.clone();
+    // nothing the client sends is read
+    res.set_handoff(move |stream, _| {
+        match EventStream::new(stream, heartbeat) {
+            Ok(events) => source.stream(&req, req.header("Last-Event-ID"), events),
+            Err(e) => warn!("event stream: {}", e),
+        }
+    });
+    res
+}
+
+/// Relays the events published with `POST` to every client. The body is
+/// the data and the `event` query parameter, if any, the event type.
+///
+/// Events are numbered, and the last `history` are kept so reconnecting
+/// clients receive the ones they missed.
+#[derive(Debug)]
+pub struct Hub {
+    history: AtomicUsize,
+    state: Mutex<HubState>,
+    /// Held while writing events, so that every client gets them in
+    /// order; the state is only locked to take a snapshot.
+    sending: Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct HubState {
+    next_id: u64,
+    events: VecDeque<(u64, Event)>,
+    clients: Vec<(u64, EventStream)>,
+    next_client: u64,
+}
+
+impl Hub {
+    pub fn new(history: usize) -> Self {
+        Hub {
+            history: AtomicUsize::new(history),
+            state: Mutex::new(HubState::default()),
+            sending: Mutex::new(()),
+        }
+    }
+
+    /// Change how many events are kept, forgetting the oldest as needed.
+    pub fn set_history(&self, history: usize) {
+        self.history.store(history, Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        while state.events.len() > history {
+            state.events.pop_front();
+        }
+    }
+
+    pub fn publish(&self, mut event: Event) {
+        let _sending = self.sending.lock().unwrap();
+        // write without holding the state, so a slow client does not hold
+        // up the others joining or leaving
+        let clients = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            event.id = Some(id.to_string());
+            state.events.push_back((id, event.clone()));
+            while state.events.len() > self.history.load(Ordering::SeqCst) {
+                state.events.pop_front();
+            }
+            state.clients.clone()
+        };
+        let gone: Vec<u64> = clients.iter()
+            .filter(|(_, events)| events.send(&event).is_err())
+            .map(|(client, _)| *client)
+            .collect();
+        // forget clients that can no longer be written to
+        if !gone.is_empty() {
+            self.state.lock().unwrap().clients.retain(|(client, _)| !gone.contains(client));
+        }
+    }
+}
+
+impl Source for Hub {
+    fn stream(&self, _req: &Request, last_event_id: Option<&str>, events: EventStream) {
+        let client = {
+            // no event is published between those missed and joining
+            let _sending = self.sending.lock().unwrap();
+            let (client, missed) = {
+                let mut state = self.state.lock().unwrap();
+                let missed: Vec<Event> = match last_event_id.and_then(|id| id.parse::<u64>().ok()) {
+                    Some(last) => state.events.iter().filter(|(id, _)| *id > last).map(|(_, e)| e.clone()).collect(),
+                    None => Vec::new(),
+                };
+                state.next_client += 1;
+                let client = state.next_client;
+                state.clients.push((client, events.clone()));
+                (client, missed)
+            };
+            if missed.iter().any(|event| events.send(event).is_err()) {
+                self.state.lock().unwrap().clients.retain(|(other, _)| *other != client);
+                return;
+            }
+            client
+        };
+        events.wait_closed();
+        self.state.lock().unwrap().clients.retain(|(other, _)| *other != client);
+    }
+
+    fn post(&self, req: &Request) -> Response {
+        let event = req.query_pairs().into_iter()
+            .find(|(k, _)| k == "event")
+            .map(|(_, v)| v)
+            .filter(|e| !e.is_empty());
+        self.publish(Event {
+            event,
+            ..Event::new(req.body().map(|b| String::from_utf8_lossy(b).into_owned()).unwrap_or_default())
+        });
+        Response::new(StatusCode::NoContent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+
+    use super::*;
+    use super::super::common::HTTPVersion;
+    use super::super::header::Header;
+
+    /// An event stream to a client on a loopback connection, and the
+    /// client's end.
+    fn connect() -> (EventStream, BufReader<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let events = EventStream::new(Connection::new(server), Duration::from_secs(60)).unwrap();
+        (events, BufReader::new(client))
+    }
+
+    /// The next event the client reads, as text.
+    fn next_event(client: &mut BufReader<TcpStream>) -> String {
+        let mut event = String::new();
+        loop {
+            let n = client.read_line(&mut event).unwrap();
+            if n == 0 || event.ends_with("\n\n") {
+                return event;
+            }
+        }
+    }
+
+    fn event(id: u64, data: &str) -> String {
+        format!("id: {}\ndata: {}\n\n", id, data)
+    }
+
+    /// Wait up to a second for `f` to hold.
+    fn eventually<F: Fn() -> bool>(f: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn clients(hub: &Hub) -> usize {
+        hub.state.lock().unwrap().clients.len()
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(Event::new("hello").to_string(), "data: hello\n\n");
+        assert_eq!(Event::new("").to_string(), "data: \n\n");
+        let event = Event {
+            id: Some("4\r\n2".to_string()),
+            event: Some("deploy\ndata: forged".to_string()),
+            data: "a\r\nb\rc\nd\r\r\n".to_string(),
+            retry: Some(Duration::from_millis(1500)),
+        };
+        assert_eq!(event.to_string(),
+                   "id: 42\nevent: deploydata: forged\nretry: 1500\n\
+                    data: a\ndata: b\ndata: c\ndata: d\ndata: \ndata: \n\n");
+    }
+
+    #[test]
+    fn missed_events_are_replayed() {
+        let hub = Arc::new(Hub::new(2));
+        for data in &["one", "two", "three"] {
+            hub.publish(Event::new(*data));
+        }
+        let req = Request::from_parts(Method::GET, "/", HTTPVersion::new(1, 1), Header::new(), None, None, None).unwrap();
+
+        // after the first, of which only the last two are kept
+        let (events, mut client) = connect();
+        let (source, r) = (hub.clone(), req.clone());
+        thread::spawn(move || source.stream(&r, Some("0"), events));
+        assert_eq!(next_event(&mut client), event(2, "two"));
+        assert_eq!(next_event(&mut client), event(3, "three"));
+        assert!(eventually(|| clients(&hub) == 1));
+        hub.publish(Event::new("four"));
+        assert_eq!(next_event(&mut client), event(4, "four"));
+
+        // after the last, or without an ID: only what comes next
+        let (events, resumed) = connect();
+        let (source, r) = (hub.clone(), req.clone());
+        thread::spawn(move || source.stream(&r, Some("4"), events));
+        let (events, fresh) = connect();
+        let source = hub.clone();
+        thread::spawn(move || source.stream(&req, None, events));
+        assert!(eventually(|| clients(&hub) == 3));
+        hub.publish(Event::new("five"));
+        for client in &mut [client, resumed, fresh] {
+            assert_eq!(next_event(client), event(5, "five"));
+        }
+    }
+
+    #[test]
+    fn disconnected_clients_are_dropped() {
+        let hub = Arc::new(Hub::new(0));
+        let req = Request::from_parts(Method::GET, "/", HTTPVersion::new(1, 1), Header::new(), None, None, None).unwrap();
+
+        // noticed by the stream waiting for the client to close
+        let (events, client) = connect();
+        let source = hub.clone();
+        thread::spawn(move || source.stream(&req, None, events));
+        assert!(eventually(|| clients(&hub) == 1));
+        drop(client);
+        assert!(eventually(|| clients(&hub) == 0));
+
+        // or by a write failing
+        let (events, client) = connect();
+        hub.state.lock().unwrap().clients.push((99, events));
+        drop(client);
+        assert!(eventually(|| {
+            hub.publish(Event::new("anyone?"));
+            clients(&hub) == 0
+        }));
+    }
+}
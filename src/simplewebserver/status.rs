@@ -21,13 +21,21 @@
 
 //! HTTP status codes
 use core::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
+use super::error::ParseError;
+
 /// # Status Code Definitions
 /// [[RFC1945, Section 9](https://tools.ietf.org/html/rfc1945#section-9)]
 /// Each Status-Code is described below, including a description of which
 /// method(s) it can follow and any meta information required in the
 /// response.
+///
+/// The codes of the [IANA HTTP Status Code Registry](https://www.iana.org/assignments/http-status-codes)
+/// have a variant each; any other three-digit code is kept as `Other`, so
+/// that a proxy can relay whatever its upstream answers
+/// [[RFC7231, Section 6](https://tools.ietf.org/html/rfc7231#section-6)].
 #[derive(Copy, Clone, Debug)]
 pub enum StatusCode {
     /// ## Informational
@@ -38,6 +46,17 @@ pub enum StatusCode {
     /// codes and they are not a valid response to a HTTP/1.0 request.
     /// However, they may be useful for experimental applications which are
     /// outside the scope of this specification.
+    ///
+    /// 100 Continue
+    /// [[RFC7231, Section 6.2.1](https://tools.ietf.org/html/rfc7231#section-6.2.1)]
+    Continue,
+    /// 101 Switching Protocols
+    /// [[RFC7231, Section 6.2.2](https://tools.ietf.org/html/rfc7231#section-6.2.2)]
+    SwitchingProtocols,
+    /// 102 Processing [[RFC2518](https://tools.ietf.org/html/rfc2518#section-10.1)]
+    Processing,
+    /// 103 Early Hints [[RFC8297](https://tools.ietf.org/html/rfc8297)]
+    EarlyHints,
 
     /// ## Successful
     /// [[RFC1945, Section 9.2](https://tools.ietf.org/html/rfc1945#section-9.2)]
@@ -45,13 +64,28 @@ pub enum StatusCode {
     /// successfully received, understood, and accepted.
     ///
     /// 200 OK
-    OK = 200,
+    OK,
     /// 201 Created
-    Created = 201,
+    Created,
     /// 202 Accepted
-    Accepted = 202,
+    Accepted,
+    /// 203 Non-Authoritative Information
+    /// [[RFC7231, Section 6.3.4](https://tools.ietf.org/html/rfc7231#section-6.3.4)]
+    NonAuthoritativeInformation,
     /// 204 No Content
-    NoContent = 204,
+    NoContent,
+    /// 205 Reset Content
+    /// [[RFC7231, Section 6.3.6](https://tools.ietf.org/html/rfc7231#section-6.3.6)]
+    ResetContent,
+    /// 206 Partial Content
+    /// [[RFC7233, Section 4.1](https://tools.ietf.org/html/rfc7233#section-4.1)]
+    PartialContent,
+    /// 207 Multi-Status [[RFC4918, Section 11.1](https://tools.ietf.org/html/rfc4918#section-11.1)]
+    MultiStatus,
+    /// 208 Already Reported [[RFC5842, Section 7.1](https://tools.ietf.org/html/rfc5842#section-7.1)]
+    AlreadyReported,
+    /// 226 IM Used [[RFC3229, Section 10.4.1](https://tools.ietf.org/html/rfc3229#section-10.4.1)]
+    IMUsed,
 
     /// ## Redirection
     /// [[RFC1945, Section 9.3](https://tools.ietf.org/html/rfc1945#section-9.3)]
@@ -64,13 +98,24 @@ pub enum StatusCode {
     /// indicate an infinite loop.
     ///
     /// 300 Multiple Choices
-    MultipleChoices = 300,
+    MultipleChoices,
     /// 301 Move Permanently
-    MovedPermanently = 301,
+    MovedPermanently,
     /// 302 Move Temporarily
-    MovedTemporarily = 302,
+    MovedTemporarily,
+    /// 303 See Other
+    /// [[RFC7231, Section 6.4.4](https://tools.ietf.org/html/rfc7231#section-6.4.4)]
+    SeeOther,
     /// 304 Not Modified
-    NotModified = 304,
+    NotModified,
+    /// 305 Use Proxy
+    /// [[RFC7231, Section 6.4.5](https://tools.ietf.org/html/rfc7231#section-6.4.5)]
+    UseProxy,
+    /// 307 Temporary Redirect
+    /// [[RFC7231, Section 6.4.7](https://tools.ietf.org/html/rfc7231#section-6.4.7)]
+    TemporaryRedirect,
+    /// 308 Permanent Redirect [[RFC7538, Section 3](https://tools.ietf.org/html/rfc7538#section-3)]
+    PermanentRedirect,
 
     /// ## Client Error 4xx
     /// [[RFC1945, Section 9.4](https://tools.ietf.org/html/rfc1945#section-9.4)]
@@ -83,13 +128,75 @@ pub enum StatusCode {
     /// condition. These status codes are applicable to any request method.
     ///
     /// 400 Bad Request
-    BadRequest = 400,
+    BadRequest,
     /// 401 Unauthorized
-    Unauthorized = 401,
+    Unauthorized,
+    /// 402 Payment Required
+    /// [[RFC7231, Section 6.5.2](https://tools.ietf.org/html/rfc7231#section-6.5.2)]
+    PaymentRequired,
     /// 403 Forbidden
-    Forbidden = 403,
+    Forbidden,
     /// 404 Not Found
-    NotFound = 404,
+    NotFound,
+    /// 405 Method Not Allowed
+    /// [[RFC7231, Section 6.5.5](https://tools.ietf.org/html/rfc7231#section-6.5.5)]
+    MethodNotAllowed,
+    /// 406 Not Acceptable
+    /// [[RFC7231, Section 6.5.6](https://tools.ietf.org/html/rfc7231#section-6.5.6)]
+    NotAcceptable,
+    /// 407 Proxy Authentication Required
+    /// [[RFC7235, Section 3.2](https://tools.ietf.org/html/rfc7235#section-3.2)]
+    ProxyAuthenticationRequired,
+    /// 408 Request Timeout
+    /// [[RFC7231, Section 6.5.7](https://tools.ietf.org/html/rfc7231#section-6.5.7)]
+    RequestTimeout,
+    /// 409 Conflict
+    /// [[RFC7231, Section 6.5.8](https://tools.ietf.org/html/rfc7231#section-6.5.8)]
+    Conflict,
+    /// 410 Gone
+    /// [[RFC7231, Section 6.5.9](https://tools.ietf.org/html/rfc7231#section-6.5.9)]
+    Gone,
+    /// 411 Length Required
+    /// [[RFC7231, Section 6.5.10](https://tools.ietf.org/html/rfc7231#section-6.5.10)]
+    LengthRequired,
+    /// 412 Precondition Failed
+    /// [[RFC7232, Section 4.2](https://tools.ietf.org/html/rfc7232#section-4.2)]
+    PreconditionFailed,
+    /// 413 Request Entity Too Large
+    RequestEntityTooLarge,
+    /// 414 URI Too Long
+    /// [[RFC7231, Section 6.5.12](https://tools.ietf.org/html/rfc7231#section-6.5.12)]
+    URITooLong,
+    /// 415 Unsupported Media Type
+    /// [[RFC7231, Section 6.5.13](https://tools.ietf.org/html/rfc7231#section-6.5.13)]
+    UnsupportedMediaType,
+    /// 416 Range Not Satisfiable
+    /// [[RFC7233, Section 4.4](https://tools.ietf.org/html/rfc7233#section-4.4)]
+    RangeNotSatisfiable,
+    /// 417 Expectation Failed
+    /// [[RFC7231, Section 6.5.14](https://tools.ietf.org/html/rfc7231#section-6.5.14)]
+    ExpectationFailed,
+    /// 421 Misdirected Request [[RFC7540, Section 9.1.2](https://tools.ietf.org/html/rfc7540#section-9.1.2)]
+    MisdirectedRequest,
+    /// 422 Unprocessable Entity [[RFC4918, Section 11.2](https://tools.ietf.org/html/rfc4918#section-11.2)]
+    UnprocessableEntity,
+    /// 423 Locked [[RFC4918, Section 11.3](https://tools.ietf.org/html/rfc4918#section-11.3)]
+    Locked,
+    /// 424 Failed Dependency [[RFC4918, Section 11.4](https://tools.ietf.org/html/rfc4918#section-11.4)]
+    FailedDependency,
+    /// 425 Too Early [[RFC8470, Section 5.2](https://tools.ietf.org/html/rfc8470#section-5.2)]
+    TooEarly,
+    /// 426 Upgrade Required
+    /// [[RFC7231, Section 6.5.15](https://tools.ietf.org/html/rfc7231#section-6.5.15)]
+    UpgradeRequired,
+    /// 428 Precondition Required [[RFC6585, Section 3](https://tools.ietf.org/html/rfc6585#section-3)]
+    PreconditionRequired,
+    /// 429 Too Many Requests [[RFC6585, Section 4](https://tools.ietf.org/html/rfc6585#section-4)]
+    TooManyRequests,
+    /// 431 Request Header Fields Too Large [[RFC6585, Section 5](https://tools.ietf.org/html/rfc6585#section-5)]
+    RequestHeaderFieldsTooLarge,
+    /// 451 Unavailable For Legal Reasons [[RFC7725, Section 3](https://tools.ietf.org/html/rfc7725#section-3)]
+    UnavailableForLegalReasons,
 
     /// ## Server Error 5xx
     /// [[RFC1945, Section 9.5](https://tools.ietf.org/html/rfc1945#section-9.5)]
@@ -104,51 +211,386 @@ pub enum StatusCode {
     /// are no required header fields.
     ///
     /// 500 Internal Server Error
-    InternalServerError = 500,
+    InternalServerError,
     /// 501 Not Implemented
-    NotImplemented = 501,
+    NotImplemented,
     /// 502 BadGateway
-    BadGateway = 502,
+    BadGateway,
     /// 503 Service Unavailable
-    ServiceUnavailable = 503,
+    ServiceUnavailable,
+    /// 504 Gateway Timeout
+    GatewayTimeout,
+    /// 505 HTTP Version Not Supported
+    /// [[RFC7231, Section 6.6.6](https://tools.ietf.org/html/rfc7231#section-6.6.6)]
+    HTTPVersionNotSupported,
+    /// 506 Variant Also Negotiates [[RFC2295, Section 8.1](https://tools.ietf.org/html/rfc2295#section-8.1)]
+    VariantAlsoNegotiates,
+    /// 507 Insufficient Storage [[RFC4918, Section 11.5](https://tools.ietf.org/html/rfc4918#section-11.5)]
+    InsufficientStorage,
+    /// 508 Loop Detected [[RFC5842, Section 7.2](https://tools.ietf.org/html/rfc5842#section-7.2)]
+    LoopDetected,
+    /// 510 Not Extended [[RFC2774, Section 7](https://tools.ietf.org/html/rfc2774#section-7)]
+    NotExtended,
+    /// 511 Network Authentication Required [[RFC6585, Section 6](https://tools.ietf.org/html/rfc6585#section-6)]
+    NetworkAuthenticationRequired,
+
+    /// An unregistered code from 100 to 599, understood as the x00 code of
+    /// its class
+    Other(u16),
 }
 
+/// Every registered code, for lookups by number or reason phrase.
+static REGISTERED: &[StatusCode] = &[
+    StatusCode::Continue,
+    StatusCode::SwitchingProtocols,
+    StatusCode::Processing,
+    StatusCode::EarlyHints,
+    StatusCode::OK,
+    StatusCode::Created,
+    StatusCode::Accepted,
+    StatusCode::NonAuthoritativeInformation,
+    StatusCode::NoContent,
+    StatusCode::ResetContent,
+    StatusCode::PartialContent,
+    StatusCode::MultiStatus,
+    StatusCode::AlreadyReported,
+    StatusCode::IMUsed,
+    StatusCode::MultipleChoices,
+    StatusCode::MovedPermanently,
+    StatusCode::MovedTemporarily,
+    StatusCode::SeeOther,
+    StatusCode::NotModified,
+    StatusCode::UseProxy,
+    StatusCode::TemporaryRedirect,
+    StatusCode::PermanentRedirect,
+    StatusCode::BadRequest,
+    StatusCode::Unauthorized,
+    StatusCode::PaymentRequired,
+    StatusCode::Forbidden,
+    StatusCode::NotFound,
+    StatusCode::MethodNotAllowed,
+    StatusCode::NotAcceptable,
+    StatusCode::ProxyAuthenticationRequired,
+    StatusCode::RequestTimeout,
+    StatusCode::Conflict,
+    StatusCode::Gone,
+    StatusCode::LengthRequired,
+    StatusCode::PreconditionFailed,
+    StatusCode::RequestEntityTooLarge,
+    StatusCode::URITooLong,
+    StatusCode::UnsupportedMediaType,
+    StatusCode::RangeNotSatisfiable,
+    StatusCode::ExpectationFailed,
+    StatusCode::MisdirectedRequest,
+    StatusCode::UnprocessableEntity,
+    StatusCode::Locked,
+    StatusCode::FailedDependency,
+    StatusCode::TooEarly,
+    StatusCode::UpgradeRequired,
+    StatusCode::PreconditionRequired,
+    StatusCode::TooManyRequests,
+    StatusCode::RequestHeaderFieldsTooLarge,
+    StatusCode::UnavailableForLegalReasons,
+    StatusCode::InternalServerError,
+    StatusCode::NotImplemented,
+    StatusCode::BadGateway,
+    StatusCode::ServiceUnavailable,
+    StatusCode::GatewayTimeout,
+    StatusCode::HTTPVersionNotSupported,
+    StatusCode::VariantAlsoNegotiates,
+    StatusCode::InsufficientStorage,
+    StatusCode::LoopDetected,
+    StatusCode::NotExtended,
+    StatusCode::NetworkAuthenticationRequired,
+];
+
 impl StatusCode {
     pub fn to_u16(&self) -> u16 {
-        *self as u16
+        match self {
+            StatusCode::Continue => 100,
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Processing => 102,
+            StatusCode::EarlyHints => 103,
+            StatusCode::OK => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NonAuthoritativeInformation => 203,
+            StatusCode::NoContent => 204,
+            StatusCode::ResetContent => 205,
+            StatusCode::PartialContent => 206,
+            StatusCode::MultiStatus => 207,
+            StatusCode::AlreadyReported => 208,
+            StatusCode::IMUsed => 226,
+            StatusCode::MultipleChoices => 300,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::MovedTemporarily => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::UseProxy => 305,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::PaymentRequired => 402,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::NotAcceptable => 406,
+            StatusCode::ProxyAuthenticationRequired => 407,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::Conflict => 409,
+            StatusCode::Gone => 410,
+            StatusCode::LengthRequired => 411,
+            StatusCode::PreconditionFailed => 412,
+            StatusCode::RequestEntityTooLarge => 413,
+            StatusCode::URITooLong => 414,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::ExpectationFailed => 417,
+            StatusCode::MisdirectedRequest => 421,
+            StatusCode::UnprocessableEntity => 422,
+            StatusCode::Locked => 423,
+            StatusCode::FailedDependency => 424,
+            StatusCode::TooEarly => 425,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::PreconditionRequired => 428,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::UnavailableForLegalReasons => 451,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+            StatusCode::HTTPVersionNotSupported => 505,
+            StatusCode::VariantAlsoNegotiates => 506,
+            StatusCode::InsufficientStorage => 507,
+            StatusCode::LoopDetected => 508,
+            StatusCode::NotExtended => 510,
+            StatusCode::NetworkAuthenticationRequired => 511,
+            StatusCode::Other(code) => *code,
+        }
+    }
+
+    /// The status code for `code`, `None` if it is not from 100 to 599.
+    pub fn from_u16(code: u16) -> Option<Self> {
+        if !(100..600).contains(&code) {
+            return None;
+        }
+        Some(REGISTERED.iter()
+            .find(|c| c.to_u16() == code)
+            .cloned()
+            .unwrap_or(StatusCode::Other(code)))
+    }
+
+    /// The registered status code whose reason phrase is `reason`,
+    /// ignoring case.
+    pub fn from_reason(reason: &str) -> Option<Self> {
+        REGISTERED.iter()
+            .find(|c| c.to_string().eq_ignore_ascii_case(reason.trim()))
+            .cloned()
+    }
+
+    pub fn is_registered(&self) -> bool {
+        !matches!(self, StatusCode::Other(_))
+    }
+
+    /// 1xx
+    pub fn is_informational(&self) -> bool {
+        self.to_u16() / 100 == 1
+    }
+
+    /// 2xx
+    pub fn is_success(&self) -> bool {
+        self.to_u16() / 100 == 2
+    }
+
+    /// 3xx
+    pub fn is_redirection(&self) -> bool {
+        self.to_u16() / 100 == 3
+    }
+
+    /// 4xx
+    pub fn is_client_error(&self) -> bool {
+        self.to_u16() / 100 == 4
+    }
+
+    /// 5xx
+    pub fn is_server_error(&self) -> bool {
+        self.to_u16() / 100 == 5
+    }
+}
+
+impl PartialEq for StatusCode {
+    fn eq(&self, other: &StatusCode) -> bool {
+        self.to_u16() == other.to_u16()
+    }
+}
+
+impl Eq for StatusCode {}
+
+impl Hash for StatusCode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.to_u16().hash(state);
     }
 }
 
 impl FromStr for StatusCode {
-    type Err = ();
+    type Err = ParseError;
 
-    fn from_str(_s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        unimplemented!()
+    /// Either the three digits of a Status-Code, e.g. `404`, or a
+    /// registered reason phrase, e.g. `Not Found`.
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        let code = if s.len() == 3 && s.bytes().all(|b| b.is_ascii_digit()) {
+            u16::from_str(s).ok().and_then(StatusCode::from_u16)
+        } else {
+            StatusCode::from_reason(s)
+        };
+        code.ok_or_else(|| ParseError::invalid("Status-Code", format!("`{}` is not a status code", s)))
     }
 }
 
 impl fmt::Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", match &self {
+            StatusCode::Continue => "Continue",
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Processing => "Processing",
+            StatusCode::EarlyHints => "Early Hints",
+
             StatusCode::OK => "OK",
             StatusCode::Created => "Created",
             StatusCode::Accepted => "Accepted",
+            StatusCode::NonAuthoritativeInformation => "Non-Authoritative Information",
             StatusCode::NoContent => "No Content",
+            StatusCode::ResetContent => "Reset Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MultiStatus => "Multi-Status",
+            StatusCode::AlreadyReported => "Already Reported",
+            StatusCode::IMUsed => "IM Used",
 
             StatusCode::MultipleChoices => "Multiple Choices",
             StatusCode::MovedPermanently => "Moved Permanently",
             StatusCode::MovedTemporarily => "Moved Temporarily",
+            StatusCode::SeeOther => "See Other",
             StatusCode::NotModified => "Not Modified",
+            StatusCode::UseProxy => "Use Proxy",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
 
             StatusCode::BadRequest => "Bad Request",
             StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::PaymentRequired => "Payment Required",
             StatusCode::Forbidden => "Forbidden",
             StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::NotAcceptable => "Not Acceptable",
+            StatusCode::ProxyAuthenticationRequired => "Proxy Authentication Required",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PreconditionFailed => "Precondition Failed",
+            StatusCode::RequestEntityTooLarge => "Request Entity Too Large",
+            StatusCode::URITooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::ExpectationFailed => "Expectation Failed",
+            StatusCode::MisdirectedRequest => "Misdirected Request",
+            StatusCode::UnprocessableEntity => "Unprocessable Entity",
+            StatusCode::Locked => "Locked",
+            StatusCode::FailedDependency => "Failed Dependency",
+            StatusCode::TooEarly => "Too Early",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::PreconditionRequired => "Precondition Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
 
             StatusCode::InternalServerError => "Internal Server Error",
             StatusCode::NotImplemented => "Not Implemented",
             StatusCode::BadGateway => "Bad Gateway",
             StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::HTTPVersionNotSupported => "HTTP Version Not Supported",
+            StatusCode::VariantAlsoNegotiates => "Variant Also Negotiates",
+            StatusCode::InsufficientStorage => "Insufficient Storage",
+            StatusCode::LoopDetected => "Loop Detected",
+            StatusCode::NotExtended => "Not Extended",
+            StatusCode::NetworkAuthenticationRequired => "Network Authentication Required",
+
+            StatusCode::Other(code) => match code / 100 {
+                1 => "Informational",
+                2 => "Success",
+                3 => "Redirection",
+                4 => "Client Error",
+                _ => "Server Error",
+            },
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_codes_round_trip() {
+        for code in REGISTERED {
+            assert!(code.is_registered());
+            assert_eq!(StatusCode::from_u16(code.to_u16()), Some(*code));
+            assert_eq!(code.to_u16().to_string().parse::<StatusCode>().unwrap(), *code);
+            assert_eq!(code.to_string().parse::<StatusCode>().unwrap(), *code);
+        }
+    }
+
+    #[test]
+    fn codes_are_parsed() {
+        assert_eq!("404".parse::<StatusCode>().unwrap(), StatusCode::NotFound);
+        assert_eq!("not found".parse::<StatusCode>().unwrap(), StatusCode::NotFound);
+        assert_eq!(" Bad Gateway ".parse::<StatusCode>().unwrap(), StatusCode::BadGateway);
+
+        let code = "299".parse::<StatusCode>().unwrap();
+        assert!(!code.is_registered());
+        assert_eq!(code.to_u16(), 299);
+        assert_eq!(code.to_string(), "Success");
+
+        for s in &["", "99", "600", "099", "1000", "+20", "2a0", "Not A Status", "Other"] {
+            assert!(s.parse::<StatusCode>().is_err(), "{}", s);
+        }
+        assert_eq!(StatusCode::from_u16(99), None);
+        assert_eq!(StatusCode::from_u16(600), None);
+        assert_eq!(StatusCode::from_u16(100), Some(StatusCode::Continue));
+        assert_eq!(StatusCode::from_u16(599), Some(StatusCode::Other(599)));
+    }
+
+    #[test]
+    fn classes() {
+        let class = |code: u16| {
+            let code = StatusCode::from_u16(code).unwrap();
+            [code.is_informational(), code.is_success(), code.is_redirection(),
+             code.is_client_error(), code.is_server_error()]
+                .iter()
+                .position(|is| *is)
+                .unwrap() + 1
+        };
+        for (code, expected) in &[(100, 1), (199, 1), (200, 2), (226, 2), (304, 3), (399, 3),
+                                  (400, 4), (451, 4), (499, 4), (500, 5), (511, 5), (599, 5)] {
+            assert_eq!(class(*code), *expected, "{}", code);
+        }
+        assert_eq!(StatusCode::Other(150).to_string(), "Informational");
+        assert_eq!(StatusCode::Other(350).to_string(), "Redirection");
+        assert_eq!(StatusCode::Other(450).to_string(), "Client Error");
+        assert_eq!(StatusCode::Other(550).to_string(), "Server Error");
+    }
+
+    #[test]
+    fn codes_compare_by_number() {
+        use std::collections::HashSet;
+
+        assert_eq!(StatusCode::Other(404), StatusCode::NotFound);
+        assert_ne!(StatusCode::Other(405), StatusCode::NotFound);
+        let set: HashSet<StatusCode> = [StatusCode::OK, StatusCode::Other(200)].iter().cloned().collect();
+        assert_eq!(set.len(), 1);
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! TLS listeners
+//!
+//! A listener with a certificate speaks TLS and offers HTTP/2 and
+//! HTTP/1.1 through ALPN
+//! [[RFC7301](https://tools.ietf.org/html/rfc7301)].
+use core::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::{NoClientAuth, ServerConfig, ServerSession, Session};
+use rustls::internal::pemfile;
+
+use super::h2::Transport;
+use super::request::ReadTimeout;
+
+/// The ALPN protocol identifier of HTTP/2 over TLS.
+pub const H2: &[u8] = b"h2";
+
+/// How long a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Tls {
+    cert: PathBuf,
+    config: Arc<ServerConfig>,
+}
+
+impl Tls {
+    /// Load a PEM certificate chain and its PKCS#8 or RSA private key,
+    /// offering HTTP/2 to clients if `http2`.
+    pub fn load(cert: &Path, key: &Path, http2: bool) -> Result<Self, String> {
+        let chain = File::open(cert)
+            .map_err(|e| e.to_string())
+            .and_then(|f| pemfile::certs(&mut BufReader::new(f))
+                .map_err(|_| "not a PEM certificate".to_string()))
+            .map_err(|e| format!("`{}`: {}", cert.display(), e))?;
+        if chain.is_empty() {
+            return Err(format!("`{}`: no certificate found", cert.display()));
+        }
+        let key = read_key(key)
+            .map_err(|e| format!("`{}`: {}", key.display(), e))?;
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(chain, key)
+            .map_err(|e| format!("`{}`: {}", cert.display(), e))?;
+        if http2 {
+            config.set_protocols(&[H2.to_vec(), b"http/1.1".to_vec()]);
+        } else {
+            config.set_protocols(&[b"http/1.1".to_vec()]);
+        }
+        Ok(Tls {
+            cert: cert.to_path_buf(),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Complete the handshake with the client on `sock`, then restore its
+    /// read timeout.
+    pub fn accept(&self, mut sock: TcpStream) -> io::Result<TlsStream> {
+        let mut session = ServerSession::new(&self.config);
+        let timeout = sock.read_timeout()?;
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while session.is_handshaking() {
+            session.complete_io(&mut sock)?;
+        }
+        sock.set_read_timeout(timeout)?;
+        Ok(TlsStream {
+            sock,
+            session: Mutex::new(session),
+        })
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tls({})", self.cert.display())
+    }
+}
+
+fn read_key(path: &Path) -> Result<rustls::PrivateKey, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(&mut file))
+        .map_err(|_| "not a PEM private key".to_string())?;
+    if keys.is_empty() {
+        file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(&mut file))
+            .map_err(|_| "not a PEM private key".to_string())?;
+    }
+    keys.pop().ok_or_else(|| "no private key found".to_string())
+}
+
+/// An established TLS connection.
+///
+/// Reading waits on the socket without holding the session, so that an
+/// HTTP/2 connection can go on sending responses while it waits for the
+/// next frame.
+pub struct TlsStream {
+    sock: TcpStream,
+    session: Mutex<ServerSession>,
+}
+
+impl TlsStream {
+    /// The protocol the client and we agreed on, if any.
+    pub fn alpn(&self) -> Option<Vec<u8>> {
+        self.session.lock().unwrap().get_alpn_protocol().map(|p| p.to_vec())
+    }
+}
+
+fn flush(session: &mut ServerSession, mut sock: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut sock)?;
+    }
+    Ok(())
+}
+
+impl Transport for TlsStream {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 16 * 1024];
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                match session.read(buf) {
+                    Ok(0) => {}
+                    Ok(n) => return Ok(n),
+                    // close_notify
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                    Err(e) => return Err(e),
+                }
+            }
+            let n = (&self.sock).read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session.lock().unwrap();
+            let mut records = &raw[..n];
+            while !records.is_empty() {
+                session.read_tls(&mut records)?;
+                if let Err(e) = session.process_new_packets() {
+                    let _ = flush(&mut session, &self.sock);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+            flush(&mut session, &self.sock)?;
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.write_all(data)?;
+        flush(&mut session, &self.sock)
+    }
+
+    /// Tell the client we are done and close the socket.
+    fn close(&self) {
+        let mut session = self.session.lock().unwrap();
+        session.send_close_notify();
+        let _ = flush(&mut session, &self.sock);
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+impl ReadTimeout for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf)
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,760 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Upstream pools
+//!
+//! A pool balances proxied requests over its peers. Peers that fail
+//! `max_fails` times in a row are ejected for `fail_timeout` and then
+//! tried again; with a health check configured, a background thread also
+//! probes every peer and takes it out of rotation after `fails`
+//! consecutive failed probes until it passes `passes` probes.
+//!
+//! Connections to a peer are kept alive after a response has been relayed
+//! and reused for later requests, up to `max_idle` per peer; idle
+//! connections are closed after `idle_timeout`, any connection after
+//! `max_lifetime`.
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::common::fnv1a;
+use super::proxy::Upstream;
+use super::request::Request;
+use super::status::StatusCode;
+
+/// Virtual nodes per peer on the consistent hash ring.
+const REPLICAS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    /// Consistent hashing of the client address or a header field.
+    Hash(HashKey),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKey {
+    ClientIp,
+    Header(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAlive {
+    /// Idle connections kept per peer, 0 disables keep-alive.
+    pub max_idle: usize,
+    pub idle_timeout: Duration,
+    pub max_lifetime: Duration,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            max_idle: 8,
+            idle_timeout: Duration::from_secs(60),
+            max_lifetime: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub fails: usize,
+    pub passes: usize,
+}
+
+/// A connection to a peer.
+#[derive(Debug)]
+pub struct Connection {
+    pub stream: BufReader<TcpStream>,
+    created: Instant,
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl BufRead for Connection {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.stream.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.stream.consume(amt)
+    }
+}
+
+#[derive(Debug)]
+struct Idle {
+    conn: Connection,
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub struct Peer {
+    pub upstream: Upstream,
+    idle: Mutex<Vec<Idle>>,
+    /// Requests sent over a reused connection.
+    hits: AtomicUsize,
+    /// Requests that needed a new connection.
+    misses: AtomicUsize,
+    /// Requests in flight, for `Strategy::LeastConnections`.
+    active: AtomicUsize,
+    /// Consecutive failed requests.
+    fails: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+    /// Cleared by the health check.
+    healthy: AtomicBool,
+}
+
+impl Peer {
+    fn new(upstream: Upstream) -> Self {
+        Peer {
+            upstream,
+            idle: Mutex::new(Vec::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            fails: AtomicUsize::new(0),
+            ejected_until: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn fails(&self) -> usize {
+        self.fails.load(Ordering::SeqCst)
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::SeqCst)
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                info!("{}: reinstated after ejection", self.upstream);
+                *ejected_until = None;
+                self.fails.store(0, Ordering::SeqCst);
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub peers: Vec<Peer>,
+    pub strategy: Strategy,
+    pub max_fails: usize,
+    pub fail_timeout: Duration,
+    pub keep_alive: KeepAlive,
+    pub health_check: Option<HealthCheck>,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>,
+}
+
+impl Pool {
+    pub fn new(name: String, upstreams: Vec<Upstream>, strategy: Strategy, max_fails: usize,
+               fail_timeout: Duration, keep_alive: KeepAlive, health_check: Option<HealthCheck>) -> Arc<Self> {
+        let mut ring = Vec::new();
+        for (i, upstream) in upstreams.iter().enumerate() {
+            for r in 0..REPLICAS {
+                ring.push((fnv1a(format!("{}#{}", upstream, r).as_bytes()), i));
+            }
+        }
+        ring.sort();
+
+        let pool = Arc::new(Pool {
+            name,
+            peers: upstreams.into_iter().map(Peer::new).collect(),
+            strategy,
+            max_fails,
+            fail_timeout,
+            keep_alive,
+            health_check,
+            next: AtomicUsize::new(0),
+            ring,
+        });
+        if pool.health_check.is_some() {
+            let weak = Arc::downgrade(&pool);
+            thread::spawn(move || check_health(weak));
+        }
+        pool
+    }
+
+    /// Pick an available peer for `req`, skipping the peers in `tried`.
+    pub fn lease(pool: &Arc<Pool>, req: &Request, tried: &[usize]) -> Option<Lease> {
+        let candidate = |i: &usize| !tried.contains(i) && pool.peers[*i].is_available();
+        let n = pool.peers.len();
+        let index = match &pool.strategy {
+            Strategy::RoundRobin => {
+                let start = pool.next.fetch_add(1, Ordering::SeqCst);
+                (0..n).map(|k| (start + k) % n).find(|i| candidate(i))
+            }
+            Strategy::LeastConnections => {
+                let start = pool.next.fetch_add(1, Ordering::SeqCst);
+                (0..n).map(|k| (start + k) % n)
+                    .filter(|i| candidate(i))
+                    .min_by_key(|i| pool.peers[*i].active.load(Ordering::SeqCst))
+            }
+            Strategy::Hash(key) => {
+                let value = match key {
+                    HashKey::ClientIp => req.peer_addr().map(|p| p.ip().to_string()),
+                    HashKey::Header(name) => req.header(name).map(|v| v.to_string()),
+                }.unwrap_or_default();
+                let h = fnv1a(value.as_bytes());
+                let start = match pool.ring.binary_search(&(h, 0)) {
+                    Ok(i) | Err(i) => i,
+                };
+                (0..pool.ring.len())
+                    .map(|k| pool.ring[(start + k) % pool.ring.len()].1)
+                    .find(|i| candidate(i))
+            }
+        }?;
+        pool.peers[index].active.fetch_add(1, Ordering::SeqCst);
+        Some(Lease {
+            pool: pool.clone(),
+            index,
+        })
+    }
+}
+
+/// A peer chosen for one request; counts as an active connection until
+/// dropped.
+pub struct Lease {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Lease {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn upstream(&self) -> &Upstream {
+        &self.pool.peers[self.index].upstream
+    }
+
+    fn peer(&self) -> &Peer {
+        &self.pool.peers[self.index]
+    }
+
+    /// Take an idle connection to the peer or open a new one. The flag
+    /// tells whether the connection was reused.
+    pub fn connect(&self, timeout: Duration) -> io::Result<(Connection, bool)> {
+        match self.checkout() {
+            Some(conn) => {
+                self.peer().hits.fetch_add(1, Ordering::SeqCst);
+                set_timeouts(&conn, timeout)?;
+                Ok((conn, true))
+            }
+            None => {
+                self.peer().misses.fetch_add(1, Ordering::SeqCst);
+                Ok((self.connect_new(timeout)?, false))
+            }
+        }
+    }
+
+    pub fn connect_new(&self, timeout: Duration) -> io::Result<Connection> {
+        let upstream = self.upstream();
+        let addr = (upstream.host.as_str(), upstream.port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let conn = Connection {
+            stream: BufReader::new(TcpStream::connect_timeout(&addr, timeout)?),
+            created: Instant::now(),
+        };
+        set_timeouts(&conn, timeout)?;
+        Ok(conn)
+    }
+
+    fn checkout(&self) -> Option<Connection> {
+        let keep_alive = &self.pool.keep_alive;
+        let mut idle = self.peer().idle.lock().unwrap();
+        while let Some(Idle { conn, since }) = idle.pop() {
+            if since.elapsed() < keep_alive.idle_timeout
+                && conn.created.elapsed() < keep_alive.max_lifetime
+                && !is_stale(&conn) {
+                return Some(conn);
+            }
+            debug!("{}: closing stale connection", self.upstream());
+        }
+        None
+    }
+
+    /// Return a connection whose response has been read completely.
+    pub fn release(&self, conn: Connection) {
+        let keep_alive = &self.pool.keep_alive;
+        if conn.created.elapsed() >= keep_alive.max_lifetime {
+            return;
+        }
+        let mut idle = self.peer().idle.lock().unwrap();
+        if idle.len() < keep_alive.max_idle {
+            idle.push(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    pub fn success(&self) {
+        self.pool.peers[self.index].fails.store(0, Ordering::SeqCst);
+    }
+
+    /// Count a failed request, ejecting the peer after `max_fails`.
+    pub fn failure(&self) {
+        let peer = &self.pool.peers[self.index];
+        let fails = peer.fails.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.pool.max_fails > 0 && fails >= self.pool.max_fails {
+            let mut ejected_until = peer.ejected_until.lock().unwrap();
+            if ejected_until.is_none() {
+                warn!("{}: ejected after {} failures", peer.upstream, fails);
+            }
+            *ejected_until = Some(Instant::now() + self.pool.fail_timeout);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.peers[self.index].active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn set_timeouts(conn: &Connection, timeout: Duration) -> io::Result<()> {
+    conn.stream.get_ref().set_read_timeout(Some(timeout))?;
+    conn.stream.get_ref().set_write_timeout(Some(timeout))
+}
+
+/// An idle connection is stale if the peer has closed it or sent
+/// anything unsolicited.
+fn is_stale(conn: &Connection) -> bool {
+    if !conn.stream.buffer().is_empty() {
+        return true;
+    }
+    let stream = conn.stream.get_ref();
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let stale = !matches!(stream.peek(&mut [0; 1]), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+    stale || stream.set_nonblocking(false).is_err()
+}
+
+/// Probe the peers of the pool until it is dropped, e.g. on reload.
+fn check_health(pool: Weak<Pool>) {
+    let mut passes = Vec::new();
+    let mut fails = Vec::new();
+    loop {
+        let interval = {
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => return,
+            };
+            let check = pool.health_check.as_ref().unwrap();
+            passes.resize(pool.peers.len(), 0);
+            fails.resize(pool.peers.len(), 0);
+            for (i, peer) in pool.peers.iter().enumerate() {
+                match probe(&peer.upstream, check) {
+                    Ok(()) => {
+                        fails[i] = 0;
+                        passes[i] += 1;
+                        if !peer.healthy.load(Ordering::SeqCst) && passes[i] >= check.passes {
+                            info!("{}: health check passed, back in upstream `{}`", peer.upstream, pool.name);
+                            peer.healthy.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    Err(e) => {
+                        passes[i] = 0;
+                        fails[i] += 1;
+                        if peer.healthy.load(Ordering::SeqCst) && fails[i] >= check.fails {
+                            warn!("{}: health check failed, removed from upstream `{}`: {}", peer.upstream, pool.name, e);
+                            peer.healthy.store(false, Ordering::SeqCst);
+                        }
+                    }
+                }
+            }
+            check.interval
+        };
+        thread::sleep(interval);
+    }
+}
+
+/// A peer passes if it answers `GET path` with a 2xx or 3xx status.
+fn probe(upstream: &Upstream, check: &HealthCheck) -> io::Result<()> {
+    let addr = (upstream.host.as_str(), upstream.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, check.timeout)?;
+    stream.set_read_timeout(Some(check.timeout))?;
+    stream.set_write_timeout(Some(check.timeout))?;
+    write!(stream, "GET {}{} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+           upstream.path, check.path, upstream.host, upstream.port)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match line.split_whitespace().nth(1).and_then(|c| c.parse::<u16>().ok()).and_then(StatusCode::from_u16) {
+        Some(status_code) if status_code.is_success() || status_code.is_redirection() => Ok(()),
+        Some(status_code) => Err(io::Error::other(format!("status {}", status_code.to_u16()))),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid status line")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use super::*;
+    use super::super::common::HTTPVersion;
+    use super::super::header::Header;
+    use super::super::request::Method;
+
+    /// A stand-in backend answering every request with `status`, counting
+    /// the connections it accepts.
+    struct Backend {
+        port: u16,
+        status: Arc<AtomicUsize>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl Backend {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let status = Arc::new(AtomicUsize::new(200));
+            let connections = Arc::new(AtomicUsize::new(0));
+            let backend = Backend {
+                port,
+                status: status.clone(),
+                connections: connections.clone(),
+            };
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let status = status.clone();
+                    thread::spawn(move || answer(stream, &status));
+                }
+            });
+            backend
+        }
+
+        fn upstream(&self) -> Upstream {
+            upstream(self.port)
+        }
+    }
+
+    fn answer(stream: TcpStream, status: &AtomicUsize) {
+        let mut reader = BufReader::new(&stream);
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) if line == "\r\n" => {
+                    let status = status.load(Ordering::SeqCst);
+                    if write!(&stream, "HTTP/1.1 {} Stand-in\r\nContent-Length: 0\r\n\r\n", status).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    fn upstream(port: u16) -> Upstream {
+        Upstream {
+            host: "127.0.0.1".to_string(),
+            port,
+            path: String::new(),
+        }
+    }
+
+    /// A port nothing listens on.
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn pool(upstreams: Vec<Upstream>, strategy: Strategy) -> Arc<Pool> {
+        Pool::new("test".to_string(), upstreams, strategy, 0, Duration::from_secs(0), KeepAlive::default(), None)
+    }
+
+    fn request(peer: &str, header: &[(&str, &str)]) -> Request {
+        let mut fields = Header::new();
+        for (k, v) in header {
+            fields.append(*k, *v);
+        }
+        Request::from_parts(Method::GET, "/", HTTPVersion::new(1, 1), fields, None,
+                            Some(peer.parse::<SocketAddr>().unwrap()), None).unwrap()
+    }
+
+    fn pick(pool: &Arc<Pool>, req: &Request) -> Option<usize> {
+        Pool::lease(pool, req, &[]).map(|lease| lease.index())
+    }
+
+    /// Wait up to a second for `f` to hold.
+    fn eventually<F: Fn() -> bool>(f: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(vec![upstream(1), upstream(2), upstream(3)], Strategy::RoundRobin);
+        let req = request("10.0.0.1:1000", &[]);
+        let picked: Vec<_> = (0..6).filter_map(|_| pick(&pool, &req)).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2]);
+        // a retry skips the peers already tried
+        let lease = Pool::lease(&pool, &req, &[0, 1]).unwrap();
+        assert_eq!(lease.index(), 2);
+        assert!(Pool::lease(&pool, &req, &[0, 1, 2]).is_none());
+    }
+
+    #[test]
+    fn least_connections() {
+        let pool = pool(vec![upstream(1), upstream(2), upstream(3)], Strategy::LeastConnections);
+        let req = request("10.0.0.1:1000", &[]);
+        let first = Pool::lease(&pool, &req, &[]).unwrap();
+        let second = Pool::lease(&pool, &req, &[]).unwrap();
+        let third = Pool::lease(&pool, &req, &[]).unwrap();
+        let mut indices = vec![first.index(), second.index(), third.index()];
+        indices.sort();
+        assert_eq!(indices, vec![0, 1, 2]);
+        // the peer whose request is done has the fewest in flight
+        let done = second.index();
+        drop(second);
+        assert_eq!(pick(&pool, &req), Some(done));
+        assert_eq!(pool.peers[first.index()].active(), 1);
+        drop(first);
+        drop(third);
+        assert!(pool.peers.iter().all(|peer| peer.active() == 0));
+    }
+
+    #[test]
+    fn hash() {
+        let upstreams = || (1..5).map(upstream).collect::<Vec<_>>();
+        let by_ip = pool(upstreams(), Strategy::Hash(HashKey::ClientIp));
+        let by_header = pool(upstreams(), Strategy::Hash(HashKey::Header("X-User".to_string())));
+
+        // the same key always lands on the same peer, whatever the port
+        let peer = pick(&by_ip, &request("10.0.0.1:1000", &[]));
+        for port in 1001..1010 {
+            assert_eq!(pick(&by_ip, &request(&format!("10.0.0.1:{}", port), &[])), peer);
+        }
+        let user = pick(&by_header, &request("10.0.0.1:1000", &[("X-User", "alice")]));
+        for i in 2..10 {
+            assert_eq!(pick(&by_header, &request(&format!("10.0.0.{}:1000", i), &[("X-User", "alice")])), user);
+        }
+
+        // and keys spread over the peers
+        let mut seen: Vec<_> = (0..64)
+            .filter_map(|i| pick(&by_header, &request("10.0.0.1:1000", &[("X-User", &format!("user{}", i))])))
+            .collect();
+        seen.sort();
+        seen.dedup();
+        assert!(seen.len() > 1, "{:?}", seen);
+
+        // a key whose peer is unavailable moves to another, and back
+        let req = request("10.0.0.1:1000", &[("X-User", "alice")]);
+        let user = user.unwrap();
+        by_header.peers[user].healthy.store(false, Ordering::SeqCst);
+        let moved = pick(&by_header, &req).unwrap();
+        assert_ne!(moved, user);
+        assert_eq!(pick(&by_header, &req), Some(moved));
+        by_header.peers[user].healthy.store(true, Ordering::SeqCst);
+        assert_eq!(pick(&by_header, &req), Some(user));
+    }
+
+    #[test]
+    fn passive_ejection() {
+        let pool = Pool::new("test".to_string(), vec![upstream(1), upstream(2)], Strategy::RoundRobin, 2,
+                             Duration::from_millis(100), KeepAlive::default(), None);
+        let req = request("10.0.0.1:1000", &[]);
+        let lease = Pool::lease(&pool, &req, &[0]).unwrap();
+        assert_eq!(lease.index(), 1);
+
+        // a success in between starts the count again
+        lease.failure();
+        lease.success();
+        lease.failure();
+        assert!(pool.peers[1].is_available());
+        lease.failure();
+        assert!(!pool.peers[1].is_available());
+        drop(lease);
+        assert!((0..4).all(|_| pick(&pool, &req) == Some(0)));
+
+        // reinstated once the fail timeout is over
+        thread::sleep(Duration::from_millis(150));
+        let mut picked: Vec<_> = (0..2).filter_map(|_| pick(&pool, &req)).collect();
+        picked.sort();
+        assert_eq!(picked, vec![0, 1]);
+        assert_eq!(pool.peers[1].fails(), 0);
+    }
+
+    #[test]
+    fn health_check() {
+        let backend = Backend::start();
+        let check = HealthCheck {
+            path: "/health".to_string(),
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_secs(1),
+            fails: 2,
+            passes: 2,
+        };
+        let pool = Pool::new("test".to_string(), vec![backend.upstream(), upstream(closed_port())], Strategy::RoundRobin,
+                             0, Duration::from_secs(0), KeepAlive::default(), Some(check));
+        let req = request("10.0.0.1:1000", &[]);
+
+        // a peer that does not answer is taken out of rotation
+        assert!(eventually(|| !pool.peers[1].is_healthy()));
+        assert!(pool.peers[0].is_healthy());
+        assert!((0..4).all(|_| pick(&pool, &req) == Some(0)));
+
+        // so is one answering with an error, until it recovers
+        backend.status.store(503, Ordering::SeqCst);
+        assert!(eventually(|| !pool.peers[0].is_healthy()));
+        assert!(pick(&pool, &req).is_none());
+        backend.status.store(302, Ordering::SeqCst);
+        assert!(eventually(|| pool.peers[0].is_healthy()));
+        assert_eq!(pick(&pool, &req), Some(0));
+    }
+
+    #[test]
+    fn keep_alive() {
+        let backend = Backend::start();
+        let pool = Pool::new("test".to_string(), vec![backend.upstream()], Strategy::RoundRobin, 0,
+                             Duration::from_secs(0), KeepAlive::default(), None);
+        let req = request("10.0.0.1:1000", &[]);
+        let timeout = Duration::from_secs(1);
+
+        let lease = Pool::lease(&pool, &req, &[]).unwrap();
+        let (conn, reused) = lease.connect(timeout).unwrap();
+        assert!(!reused);
+        lease.release(conn);
+        assert_eq!(pool.peers[0].idle(), 1);
+
+        // the idle connection is taken again, still usable
+        let (mut conn, reused) = lease.connect(timeout).unwrap();
+        assert!(reused);
+        assert_eq!(pool.peers[0].idle(), 0);
+        write!(conn.stream.get_ref(), "GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let mut status = String::new();
+        conn.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 200 Stand-in\r\n");
+        assert_eq!((pool.peers[0].hits(), pool.peers[0].misses()), (1, 1));
+        assert_eq!(backend.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn keep_alive_limits() {
+        let backend = Backend::start();
+        let keep_alive = KeepAlive {
+            max_idle: 1,
+            idle_timeout: Duration::from_millis(50),
+            ..KeepAlive::default()
+        };
+        let pool = Pool::new("test".to_string(), vec![backend.upstream()], Strategy::RoundRobin, 0,
+                             Duration::from_secs(0), keep_alive, None);
+        let req = request("10.0.0.1:1000", &[]);
+        let timeout = Duration::from_secs(1);
+        let lease = Pool::lease(&pool, &req, &[]).unwrap();
+
+        // no more than max_idle are kept
+        let (first, _) = lease.connect(timeout).unwrap();
+        let (second, _) = lease.connect(timeout).unwrap();
+        lease.release(first);
+        lease.release(second);
+        assert_eq!(pool.peers[0].idle(), 1);
+
+        // nor for longer than idle_timeout
+        thread::sleep(Duration::from_millis(80));
+        let (_, reused) = lease.connect(timeout).unwrap();
+        assert!(!reused);
+        assert_eq!(pool.peers[0].idle(), 0);
+    }
+
+    #[test]
+    fn stale_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = Pool::new("test".to_string(), vec![upstream(listener.local_addr().unwrap().port())],
+                             Strategy::RoundRobin, 0, Duration::from_secs(0), KeepAlive::default(), None);
+        let req = request("10.0.0.1:1000", &[]);
+        let timeout = Duration::from_secs(1);
+        let lease = Pool::lease(&pool, &req, &[]).unwrap();
+
+        // the peer closes the connection while it is idle
+        let (conn, _) = lease.connect(timeout).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        lease.release(conn);
+        drop(accepted);
+        thread::sleep(Duration::from_millis(50));
+        let (conn, reused) = lease.connect(timeout).unwrap();
+        assert!(!reused);
+
+        // or sends something unsolicited
+        let (mut accepted, _) = listener.accept().unwrap();
+        lease.release(conn);
+        accepted.write_all(b"HTTP/1.1 408 Request Timeout\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let (_, reused) = lease.connect(timeout).unwrap();
+        assert!(!reused);
+        assert_eq!(pool.peers[0].hits(), 0);
+    }
+}
@@ -0,0 +1,629 @@
+// Copyright 2018 Gitai<i@gitai.me> All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify,
+// merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall
+// be included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+// IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR
+// ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF
+// CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! The WebSocket Protocol [[RFC6455](https://tools.ietf.org/html/rfc6455)]
+//!
+//! A `GET` carrying `Upgrade: websocket` is answered with
+//! `101 Switching Protocols` and the connection is handed to a [`Service`]
+//! on a thread of its own:
+//!
+//! ```notrust
+//!      0                   1                   2                   3
+//!      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//!     +-+-+-+-+-------+-+-------------+-------------------------------+
+//!     |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+//!     |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
+//!     |N|V|V|V|       |S|             |   (if payload len==126/127)   |
+//!     | |1|2|3|       |K|             |                               |
+//!     +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
+//!     |     Extended payload length continued, if payload len == 127  |
+//!     + - - - - - - - - - - - - - - - +-------------------------------+
+//!     |                               |Masking-key, if MASK set to 1  |
+//!     +-------------------------------+-------------------------------+
+//!     | Masking-key (continued)       |          Payload Data         |
+//!     +-------------------------------- - - - - - - - - - - - - - - - +
+//! ```
+use std::fmt;
+use std::io;
+use std::io::{BufReader, Chain, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64;
+use sha1::Sha1;
+
+use super::common::HTTPVersion;
+use super::request::{Method, Request};
+use super::response::{Connection, Response};
+use super::status::StatusCode;
+
+static GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// ## Status Codes
+/// [[RFC6455, Section 7.4.1](https://tools.ietf.org/html/rfc6455#section-7.4.1)]
+pub const NORMAL: u16 = 1000;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_DATA: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// How long to wait for the peer to answer a close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// An unsolicited pong, e.g. a heartbeat.
+    Pong(Vec<u8>),
+    /// The peer closed the connection, with a status code and reason.
+    Close(Option<(u16, String)>),
+}
+
+/// Serves the clients of a WebSocket location.
+pub trait Service: fmt::Debug + Send + Sync {
+    /// Talk to one client until it goes away.
+    fn serve(&self, req: &Request, ws: WebSocket);
+}
+
+/// Send messages to a client, from any thread.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    stream: Arc<Mutex<Connection>>,
+}
+
+impl Sender {
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.frame(TEXT, text.as_bytes()),
+            Message::Binary(data) => self.frame(BINARY, data),
+            Message::Pong(data) => self.frame(PONG, data),
+            Message::Close(None) => self.frame(CLOSE, &[]),
+            Message::Close(Some((code, reason))) => {
+                let mut payload = vec![(code >> 8) as u8, *code as u8];
+                payload.extend_from_slice(reason.as_bytes());
+                self.frame(CLOSE, &payload)
+            }
+        }
+    }
+
+    /// Write a single unmasked frame, as servers do.
+    fn frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(n as u8),
+            n if n <= 0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&[(n >> 8) as u8, n as u8]);
+            }
+            n => {
+                frame.push(127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.lock().unwrap().write_all(&frame)
+    }
+}
+
+/// A WebSocket connection. Pings are answered as they arrive and
+/// fragmented messages are reassembled up to `max_message` bytes.
+pub struct WebSocket {
+    reader: BufReader<Chain<io::Cursor<Vec<u8>>, Connection>>,
+    sender: Sender,
+    max_message: usize,
+    /// A close frame has been sent.
+    closing: bool,
+    /// The closing handshake is complete.
+    closed: bool,
+}
+
+impl WebSocket {
+    /// A WebSocket over `stream`, reading the bytes already `received`
+    /// from it first.
+    pub fn new(stream: Connection, received: Vec<u8>, max_message: usize) -> Self {
+        let writer = stream.clone();
+        WebSocket {
+            reader: BufReader::new(io::Cursor::new(received).chain(stream)),
+            sender: Sender {
+                stream: Arc::new(Mutex::new(writer)),
+            },
+            max_message,
+            closing: false,
+            closed: false,
+        }
+    }
+
+    fn stream(&self) -> &Connection {
+        self.reader.get_ref().get_ref().1
+    }
+
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.sender.send(message)
+    }
+
+    /// Receive the next message. A protocol violation by the peer closes
+    /// the connection with the matching status code and is returned as an
+    /// `InvalidData` error.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed"));
+        }
+        match self.read_message() {
+            Ok(Message::Close(close)) => {
+                if !self.closing {
+                    // echo the status code back
+                    let code = close.as_ref().map_or(NORMAL, |(code, _)| *code);
+                    let _ = self.sender.send(&Message::Close(Some((code, String::new()))));
+                }
+                self.closed = true;
+                self.stream().close();
+                Ok(Message::Close(close))
+            }
+            Ok(message) => Ok(message),
+            Err(Failure::Io(e)) => {
+                self.closed = true;
+                Err(e)
+            }
+            Err(Failure::Protocol(code, reason)) => {
+                warn!("websocket: {}", reason);
+                let _ = self.close(code, reason);
+                Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+            }
+        }
+    }
+
+    /// Start the closing handshake and wait for the peer to finish it.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed || self.closing {
+            return Ok(());
+        }
+        self.closing = true;
+        self.sender.send(&Message::Close(Some((code, reason.to_string()))))?;
+        self.stream().socket().set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        loop {
+            match self.read_message() {
+                Ok(Message::Close(_)) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+        self.closed = true;
+        self.stream().close();
+        Ok(())
+    }
+
+    fn read_message(&mut self) -> Result<Message, Failure> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+            match opcode {
+                CLOSE => return parse_close(&payload),
+                PING => {
+                    self.sender.frame(PONG, &payload)?;
+                }
+                PONG => return Ok(Message::Pong(payload)),
+                TEXT | BINARY => {
+                    if message.is_some() {
+                        return Err(Failure::Protocol(PROTOCOL_ERROR, "new message before the last one ended"));
+                    }
+                    message = Some((opcode, payload));
+                }
+                CONTINUATION => match &mut message {
+                    Some((_, data)) => {
+                        if data.len() + payload.len() > self.max_message {
+                            return Err(Failure::Protocol(MESSAGE_TOO_BIG, "message too big"));
+                        }
+                        data.extend(payload);
+                    }
+                    None => return Err(Failure::Protocol(PROTOCOL_ERROR, "continuation of no message")),
+                },
+                _ => return Err(Failure::Protocol(PROTOCOL_ERROR, "unknown opcode")),
+            }
+            if fin && opcode != PING {
+                if let Some((opcode, data)) = message.take() {
+                    return if opcode == TEXT {
+                        String::from_utf8(data)
+                            .map(Message::Text)
+                            .map_err(|_| Failure::Protocol(INVALID_DATA, "text message is not UTF-8"))
+                    } else {
+                        Ok(Message::Binary(data))
+                    };
+                }
+            }
+        }
+    }
+
+    /// Read one frame [[RFC6455, Section 5.2](https://tools.ietf.org/html/rfc6455#section-5.2)],
+    /// returning FIN, the opcode and the unmasked payload.
+    fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>), Failure> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            // no extension was negotiated
+            return Err(Failure::Protocol(PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(Failure::Protocol(PROTOCOL_ERROR, "frame from the client is not masked"));
+        }
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut buf = [0; 2];
+                self.reader.read_exact(&mut buf)?;
+                u16::from_be_bytes(buf) as u64
+            }
+            127 => {
+                let mut buf = [0; 8];
+                self.reader.read_exact(&mut buf)?;
+                u64::from_be_bytes(buf)
+            }
+            n => n as u64,
+        };
+        if opcode & 0x8 != 0 && (!fin || len > 125) {
+            return Err(Failure::Protocol(PROTOCOL_ERROR, "control frames must be single frames of at most 125 bytes"));
+        }
+        if len > self.max_message as u64 {
+            return Err(Failure::Protocol(MESSAGE_TOO_BIG, "message too big"));
+        }
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask)?;
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+}
+
+enum Failure {
+    Io(io::Error),
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        Failure::Io(e)
+    }
+}
+
+/// [[RFC6455, Section 5.5.1](https://tools.ietf.org/html/rfc6455#section-5.5.1)]
+fn parse_close(payload: &[u8]) -> Result<Message, Failure> {
+    match payload.len() {
+        0 => Ok(Message::Close(None)),
+        1 => Err(Failure::Protocol(PROTOCOL_ERROR, "close frame with a truncated status code")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            // 1012 to 1014 were registered after RFC 6455
+            // [https://www.iana.org/assignments/websocket/websocket.xml#close-code-number]
+            if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                return Err(Failure::Protocol(PROTOCOL_ERROR, "invalid close status code"));
+            }
+            let reason = String::from_utf8(payload[2..].to_vec())
+                .map_err(|_| Failure::Protocol(INVALID_DATA, "close reason is not UTF-8"))?;
+            Ok(Message::Close(Some((code, reason))))
+        }
+    }
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Answer the opening handshake [[RFC6455, Section 4.2](https://tools.ietf.org/html/rfc6455#section-4.2)]
+/// and hand the connection to `service`.
+pub fn upgrade(service: &Arc<dyn Service>, max_message: usize, req: &Request) -> Response {
+    if *req.method() != Method::GET || req.version() < HTTPVersion::new(1, 1)
+        || !has_token(req.header("Upgrade"), "websocket")
+        || !has_token(req.header("Connection"), "Upgrade") {
+        return Response::error(StatusCode::BadRequest);
+    }
+    // [RFC6455, Section 4.4] and [RFC7231, Section 6.5.15]
+    if req.header("Sec-WebSocket-Version") != Some("13") {
+        let mut res = Response::error(StatusCode::UpgradeRequired);
+        res.set_header("Upgrade", "websocket");
+        res.set_header("Sec-WebSocket-Version", "13");
+        return res;
+    }
+    let key = match req.header("Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key).map(|k| k.len() == 16).unwrap_or(false) => key,
+        _ => return Response::error(StatusCode::BadRequest),
+    };
+    let accept = base64::encode(&Sha1::from(format!("{}{}", key, GUID)).digest().bytes());
+
+    let mut res = Response::new(StatusCode::SwitchingProtocols);
+    res.set_version(HTTPVersion::new(1, 1));
+    res.set_header("Upgrade", "websocket");
+    res.set_header("Connection", "Upgrade");
+    res.set_header("Sec-WebSocket-Accept", accept);
+    let service = service.clone();
+    let req = req.clone();
+    res.set_handoff(move |stream, received| service.serve(&req, WebSocket::new(stream, received, max_message)));
+    res
+}
+
+/// Sends every message back.
+#[derive(Debug)]
+pub struct Echo;
+
+impl Service for Echo {
+    fn serve(&self, _req: &Request, mut ws: WebSocket) {
+        loop {
+            match ws.recv() {
+                Ok(message @ Message::Text(_)) | Ok(message @ Message::Binary(_)) => {
+                    if ws.send(&message).is_err() {
+                        break;
+                    }
+                }
+                Ok(Message::Pong(_)) => {}
+                Ok(Message::Close(_)) | Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Relays every message to all clients connected to the location,
+/// e.g. for a dashboard fed by one publishing client.
+#[derive(Debug, Default)]
+pub struct Broadcast {
+    clients: Mutex<Vec<(usize, Sender)>>,
+    next: Mutex<usize>,
+}
+
+impl Service for Broadcast {
+    fn serve(&self, _req: &Request, mut ws: WebSocket) {
+        let id = {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            *next
+        };
+        self.clients.lock().unwrap().push((id, ws.sender()));
+        loop {
+            match ws.recv() {
+                Ok(message @ Message::Text(_)) | Ok(message @ Message::Binary(_)) => {
+                    // write without holding the lock, so a slow client does
+                    // not hold up the others joining, leaving or sending
+                    let clients = self.clients.lock().unwrap().clone();
+                    let gone: Vec<usize> = clients.iter()
+                        .filter(|(_, sender)| sender.send(&message).is_err())
+                        .map(|(other, _)| *other)
+                        .collect();
+                    // forget clients that can no longer be written to
+                    if !gone.is_empty() {
+                        self.clients.lock().unwrap().retain(|(other, _)| !gone.contains(other));
+                    }
+                }
+                Ok(Message::Pong(_)) => {}
+                Ok(Message::Close(_)) | Err(_) => break,
+            }
+        }
+        self.clients.lock().unwrap().retain(|(other, _)| *other != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use super::super::header::Header;
+
+    fn close(code: u16) -> Result<Message, Failure> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        parse_close(&payload)
+    }
+
+    #[test]
+    fn close_codes() {
+        for code in &[1000, 1001, 1003, 1007, 1011, 1012, 1013, 1014, 3000, 4999] {
+            match close(*code) {
+                Ok(Message::Close(Some((c, reason)))) => assert_eq!((c, reason.as_str()), (*code, "bye")),
+                _ => panic!("{} refused", code),
+            }
+        }
+        // reserved, never sent, or unassigned
+        for code in &[0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert!(close(*code).is_err(), "{} accepted", code);
+        }
+        assert!(parse_close(&[3]).is_err());
+        assert!(parse_close(&[3, 232, 0xff]).is_err());
+    }
+
+    fn request(method: Method, version: HTTPVersion, fields: &[(&str, &str)]) -> Request {
+        let mut header = Header::new();
+        for (name, value) in fields {
+            header.append(*name, *value);
+        }
+        Request::from_parts(method, "/ws", version, header, None, None, None).unwrap()
+    }
+
+    static HANDSHAKE: &[(&str, &str)] = &[
+        ("Host", "example.com"),
+        ("Upgrade", "websocket"),
+        ("Connection", "keep-alive, Upgrade"),
+        ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ("Sec-WebSocket-Version", "13"),
+    ];
+
+    #[test]
+    fn handshake() {
+        let service: Arc<dyn Service> = Arc::new(Echo);
+        let upgrade = |method, version, fields: &[(&str, &str)]| upgrade(&service, 1024, &request(method, version, fields));
+
+        // the example of [RFC6455, Section 1.3]
+        let mut res = upgrade(Method::GET, HTTPVersion::new(1, 1), HANDSHAKE);
+        assert_eq!(res.status_code(), StatusCode::SwitchingProtocols);
+        assert_eq!(res.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(res.header("Upgrade"), Some("websocket"));
+        assert_eq!(res.header("Connection"), Some("Upgrade"));
+        assert!(res.take_handoff().is_some());
+
+        let without = |name: &'static str| HANDSHAKE.iter().cloned().filter(|(k, _)| *k != name).collect::<Vec<_>>();
+        let with = |name: &'static str, value: &'static str| {
+            let mut fields = without(name);
+            fields.push((name, value));
+            fields
+        };
+        for fields in &[without("Upgrade"), without("Connection"), without("Sec-WebSocket-Key"),
+                        with("Upgrade", "h2c"), with("Sec-WebSocket-Key", "c2hvcnQ=")] {
+            assert_eq!(upgrade(Method::GET, HTTPVersion::new(1, 1), fields).status_code(), StatusCode::BadRequest, "{:?}", fields);
+        }
+        assert_eq!(upgrade(Method::POST, HTTPVersion::new(1, 1), HANDSHAKE).status_code(), StatusCode::BadRequest);
+        assert_eq!(upgrade(Method::GET, HTTPVersion::new(1, 0), HANDSHAKE).status_code(), StatusCode::BadRequest);
+
+        for fields in &[with("Sec-WebSocket-Version", "8"), without("Sec-WebSocket-Version")] {
+            let res = upgrade(Method::GET, HTTPVersion::new(1, 1), fields);
+            assert_eq!(res.status_code(), StatusCode::UpgradeRequired);
+            assert_eq!(res.header("Sec-WebSocket-Version"), Some("13"));
+            assert_eq!(res.header("Upgrade"), Some("websocket"));
+        }
+    }
+
+    /// A server end and the client's socket.
+    fn connect(max_message: usize) -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (WebSocket::new(Connection::new(server), Vec::new(), max_message), client)
+    }
+
+    /// A frame as a client sends it, masked unless `masked` is false.
+    fn frame(fin: bool, opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        let mask_bit = if masked { 0x80 } else { 0 };
+        match payload.len() {
+            n if n < 126 => frame.push(mask_bit | n as u8),
+            n => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+        }
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        if masked {
+            frame.extend_from_slice(&mask);
+        }
+        frame.extend(payload.iter().enumerate().map(|(i, b)| if masked { b ^ mask[i % 4] } else { *b }));
+        frame
+    }
+
+    /// Read a frame from the server: its first byte and payload.
+    fn server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0, "servers do not mask");
+        let mut payload = vec![0; (head[1] & 0x7f) as usize];
+        client.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    /// The status code the server closes with after `frames`, which must
+    /// break the protocol before a message is complete.
+    fn refused(max_message: usize, frames: &[Vec<u8>]) -> u16 {
+        let (mut ws, mut client) = connect(max_message);
+        client.write_all(&frames.concat()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        loop {
+            match server_frame(&mut client) {
+                (0x88, payload) => return u16::from_be_bytes([payload[0], payload[1]]),
+                (0x8A, _) => {}
+                (head, _) => panic!("unexpected frame {:#x}", head),
+            }
+        }
+    }
+
+    #[test]
+    fn client_frames_must_be_masked() {
+        assert_eq!(refused(1024, &[frame(true, TEXT, b"hello", false)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(true, PING, b"", false)]), PROTOCOL_ERROR);
+
+        let (mut ws, mut client) = connect(1024);
+        client.write_all(&frame(true, TEXT, b"hello", true)).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Text("hello".to_string()));
+        ws.send(&Message::Text("hi".to_string())).unwrap();
+        assert_eq!(server_frame(&mut client), (0x81, b"hi".to_vec()));
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let (mut ws, mut client) = connect(1024);
+        client.write_all(&[
+            frame(false, TEXT, b"Hel", true),
+            // control frames may come between fragments
+            frame(true, PING, b"?", true),
+            frame(false, CONTINUATION, b"", true),
+            frame(true, CONTINUATION, "lo €".as_bytes(), true),
+            frame(false, BINARY, &[1, 2], true),
+            frame(true, CONTINUATION, &[3], true),
+            frame(true, CLOSE, &[3, 232], true),
+        ].concat()).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Text("Hello €".to_string()));
+        assert_eq!(server_frame(&mut client), (0x8A, b"?".to_vec()));
+        assert_eq!(ws.recv().unwrap(), Message::Binary(vec![1, 2, 3]));
+        assert_eq!(ws.recv().unwrap(), Message::Close(Some((NORMAL, String::new()))));
+        assert_eq!(server_frame(&mut client), (0x88, vec![3, 232]));
+
+        assert_eq!(refused(1024, &[frame(true, CONTINUATION, b"lo", true)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(false, TEXT, b"Hel", true), frame(true, TEXT, b"lo", true)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(true, 0x3, b"", true)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(true, 0x40 | TEXT, b"", true)]), PROTOCOL_ERROR);
+        // a character split across fragments is fine, invalid UTF-8 is not
+        assert_eq!(refused(1024, &[frame(false, TEXT, &[0xe2, 0x82], true), frame(true, CONTINUATION, &[0x28], true)]),
+                   INVALID_DATA);
+    }
+
+    #[test]
+    fn control_frame_limits() {
+        let (mut ws, mut client) = connect(1024);
+        client.write_all(&[frame(true, PING, &[0; 125], true), frame(true, TEXT, b"", true)].concat()).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Text(String::new()));
+        assert_eq!(server_frame(&mut client), (0x8A, vec![0; 125]));
+
+        assert_eq!(refused(1024, &[frame(true, PING, &[0; 126], true)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(false, PING, b"", true)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(true, CLOSE, &[0; 126], true)]), PROTOCOL_ERROR);
+        assert_eq!(refused(1024, &[frame(true, CLOSE, &[3], true)]), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn messages_are_bounded() {
+        let (mut ws, mut client) = connect(8);
+        client.write_all(&[frame(false, BINARY, &[0; 4], true), frame(true, CONTINUATION, &[0; 4], true)].concat()).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Binary(vec![0; 8]));
+
+        assert_eq!(refused(8, &[frame(true, TEXT, &[b'a'; 9], true)]), MESSAGE_TOO_BIG);
+        assert_eq!(refused(8, &[frame(false, TEXT, &[b'a'; 5], true), frame(true, CONTINUATION, &[b'a'; 5], true)]),
+                   MESSAGE_TOO_BIG);
+        assert_eq!(refused(200, &[frame(true, BINARY, &[0; 201], true)]), MESSAGE_TOO_BIG);
+    }
+}